@@ -1,15 +1,25 @@
-#![feature(box_syntax)]
-#![feature(libc)]
 extern crate iocp;
-extern crate libc;
 extern crate num_cpus;
 extern crate threadpool;
 extern crate rand;
 
-use iocp::{IoCompletionPort, CompletionStatus};
-use std::{ptr, mem, thread};
+#[cfg(windows)]
+use iocp::{IoCompletionPort, CompletionStatus, DequeueResult, Overlapped};
+#[cfg(windows)]
+use std::thread;
+#[cfg(windows)]
+use std::time::Duration;
+#[cfg(windows)]
 use threadpool::ThreadPool;
 
+/// The state carried through the port with each packet.
+#[cfg(windows)]
+struct Request {
+	internal: u32,
+	internal_high: u32
+}
+
+#[cfg(windows)]
 fn main() {
 	let iocp = IoCompletionPort::new(0).unwrap();
 	
@@ -20,40 +30,38 @@ fn main() {
 		let iocp_clone = iocp.clone();
 		taskpool.execute(move || {
 			loop {
-				thread::sleep_ms(100 * i as u32);
-				let status = iocp_clone.get_queued(libc::INFINITE).unwrap();
+				thread::sleep(Duration::from_millis(100 * i as u64));
+				let status = match iocp_clone.get_queued(None).unwrap() {
+					DequeueResult::Completed(status) => status,
+					_ => continue
+				};
 				println!("Dequeued: {} from {} with {} {:p}", status.completion_key, i, status.byte_count, status.overlapped);
 				
-				// We re-box all this stuff so it gets freed
-				let overlapped: Box<libc::OVERLAPPED> = unsafe { mem::transmute(status.overlapped) };
-				let internal: Box<u32> = unsafe { mem::transmute(overlapped.Internal) };
-				let internal_high: Box<u32> = unsafe { mem::transmute(overlapped.InternalHigh) };
+				// Take back ownership of the allocation posted below so it gets freed
+				let overlapped: Overlapped<Request> = unsafe { Overlapped::from_raw(status.overlapped) };
+				let request = overlapped.data();
 				
-				println!("Overlapped: {} {} {} {} {:p}", internal, internal_high, overlapped.Offset, overlapped.OffsetHigh, overlapped.hEvent);
+				println!("Overlapped: {} {} {} {:p}", request.internal, request.internal_high, overlapped.offset(), overlapped.raw().hEvent);
 				
-				thread::sleep_ms(500);
+				thread::sleep(Duration::from_millis(500));
 			}
 		});
 	}
 	
 	loop {
-		let internal = box 3u32;
-		let internal_high = box 4u32;
-		// Transmute the boxes so they're on the heap but don't disappear
-		let overlapped = box libc::OVERLAPPED {
-			Internal: unsafe { mem::transmute(internal) },
-			InternalHigh: unsafe { mem::transmute(internal_high) },
-			Offset: 200,
-			OffsetHigh: 300,
-			hEvent: ptr::null_mut()
-		};
+		let overlapped = Overlapped::with_offset(Request { internal: 3, internal_high: 4 }, (300 << 32) | 200);
 		let status = CompletionStatus {
 			byte_count: 100,
 			completion_key: rand::random(),
-			overlapped: unsafe { mem::transmute(overlapped) }
+			overlapped: overlapped.into_raw()
 		};
 		println!("Queued: {}", status.completion_key);
 		iocp.post_queued(status).unwrap();
-		thread::sleep_ms(100);
+		thread::sleep(Duration::from_millis(100));
 	}
 }
+
+#[cfg(not(windows))]
+fn main() {
+	println!("This example requires Windows");
+}
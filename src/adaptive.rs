@@ -0,0 +1,96 @@
+//! Adaptive dequeue timeouts for ports that are idle most of the time.
+//!
+//! Polling a quiet port with a small timeout keeps waking the thread for nothing, which shows up
+//! as constant background CPU on laptops and virtual machines.
+
+use std::cmp;
+use std::time::Duration;
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult};
+
+/// Dequeues packets with a timeout that grows while the port stays idle.
+///
+/// Every wait that times out multiplies the timeout by the growth factor, up to the maximum.
+/// Dequeuing a packet resets the timeout to the minimum. Timeouts are rounded up to a multiple
+/// of the granularity so that wake-ups line up with the system timer tick and can be coalesced.
+pub struct AdaptiveDequeue {
+	min: u32,
+	max: u32,
+	growth: u32,
+	granularity: u32,
+	current: u32
+}
+
+impl AdaptiveDequeue {
+	/// Creates a new AdaptiveDequeue ramping from `min_timeout` to `max_timeout` milliseconds.
+	///
+	/// The timeout doubles on every idle wait and is rounded to a 16 millisecond granularity.
+	pub fn new(min_timeout: u32, max_timeout: u32) -> AdaptiveDequeue {
+		let max_timeout = cmp::max(min_timeout, max_timeout);
+		
+		AdaptiveDequeue {
+			min: min_timeout,
+			max: max_timeout,
+			growth: 2,
+			granularity: 16,
+			current: min_timeout
+		}
+	}
+	/// Sets the factor the timeout is multiplied by after each idle wait.
+	pub fn set_growth(&mut self, growth: u32) {
+		self.growth = cmp::max(growth, 1);
+	}
+	/// Sets the granularity in milliseconds the timeout is rounded up to.
+	///
+	/// A granularity of zero or one disables rounding.
+	pub fn set_granularity(&mut self, granularity: u32) {
+		self.granularity = granularity;
+	}
+	/// Returns the timeout in milliseconds that the next wait will use.
+	pub fn timeout(&self) -> u32 {
+		if self.granularity <= 1 || self.current == 0 {
+			return self.current;
+		}
+		
+		let rounded = (self.current as u64 + self.granularity as u64 - 1) / self.granularity as u64 * self.granularity as u64;
+		cmp::min(rounded, self.max as u64) as u32
+	}
+	/// Resets the timeout to the minimum.
+	pub fn reset(&mut self) {
+		self.current = self.min;
+	}
+	fn idle(&mut self) {
+		let grown = cmp::max(self.current as u64 * self.growth as u64, 1);
+		self.current = cmp::min(grown, self.max as u64) as u32;
+	}
+	fn wait(&self) -> Option<Duration> {
+		Some(Duration::from_millis(self.timeout() as u64))
+	}
+	/// Attempts to dequeue an I/O completion packet from the given port.
+	pub fn get_queued(&mut self, port: &IoCompletionPort) -> IocpResult<DequeueResult> {
+		match port.get_queued(self.wait()) {
+			Ok(DequeueResult::TimedOut) => {
+				self.idle();
+				Ok(DequeueResult::TimedOut)
+			},
+			result => {
+				self.reset();
+				result
+			}
+		}
+	}
+	/// Attempts to dequeue multiple I/O completion packets from the given port simultaneously.
+	///
+	/// Returns the number of CompletionStatus objects dequeued, which is zero if the wait timed out.
+	pub fn get_many_queued(&mut self, port: &IoCompletionPort, buf: &mut [CompletionStatus]) -> IocpResult<usize> {
+		let removed = try!(port.get_many_queued(buf, self.wait(), false));
+		
+		if removed == 0 {
+			self.idle();
+		} else {
+			self.reset();
+		}
+		
+		Ok(removed)
+	}
+}
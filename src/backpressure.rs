@@ -0,0 +1,190 @@
+//! Overlapped writes that account for data queued but not yet written.
+//!
+//! A write to a nearly full pipe or socket can stay pending indefinitely. Without accounting, a
+//! producer keeps submitting writes and the queued buffers grow without bound.
+
+use std::{cmp, mem, ptr};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError, set_notification_modes};
+
+use std::io::Error as IOError;
+
+/// A change in the amount of data queued on a BoundedWriter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pressure {
+	/// The queued bytes reached the high-water mark
+	High,
+	/// The queued bytes fell back to the low-water mark
+	Relieved
+}
+
+struct WriteOp {
+	overlapped: winapi::OVERLAPPED,
+	buffer: Vec<u8>
+}
+
+unsafe impl Send for WriteOp { }
+
+/// Submits overlapped writes to a handle while tracking how many bytes are still queued.
+///
+/// The handle is borrowed and must already be associated with the port given to `new`. Write
+/// completions arrive with the handle's completion key; pass them to `complete`. The callback is
+/// told when the queued bytes reach the high-water mark and again once they fall to the low-water
+/// mark, which is half the high-water mark unless set otherwise.
+///
+/// Dropping the writer cancels its pending writes and hands their buffers to the port, which
+/// discards the aborted packets.
+pub struct BoundedWriter<F> {
+	handle: winapi::HANDLE,
+	port: IoCompletionPort,
+	ops: HashMap<usize, Box<WriteOp>>,
+	queued: usize,
+	high_water: usize,
+	low_water: usize,
+	fail_fast: bool,
+	high: bool,
+	skip_on_success: bool,
+	callback: F
+}
+
+unsafe impl<F: Send> Send for BoundedWriter<F> { }
+
+impl<F> BoundedWriter<F> where F: FnMut(Pressure, usize) {
+	/// Creates a writer for the given handle, which is associated with the port.
+	///
+	/// The callback receives the new pressure and the number of queued bytes.
+	pub fn new(port: &IoCompletionPort, handle: winapi::HANDLE, high_water: usize, callback: F) -> BoundedWriter<F> {
+		BoundedWriter {
+			handle: handle,
+			port: port.clone(),
+			ops: HashMap::new(),
+			queued: 0,
+			high_water: high_water,
+			low_water: high_water / 2,
+			fail_fast: false,
+			high: false,
+			skip_on_success: false,
+			callback: callback
+		}
+	}
+	/// Sets the number of queued bytes at or below which the pressure is relieved.
+	pub fn set_low_water(&mut self, low_water: usize) {
+		self.low_water = cmp::min(low_water, self.high_water);
+	}
+	/// Makes writes that would take the queued bytes past the high-water mark fail with an error of
+	/// kind `WouldBlock` instead of being queued.
+	pub fn set_fail_fast(&mut self, fail_fast: bool) {
+		self.fail_fast = fail_fast;
+	}
+	/// Sets the notification modes of the handle.
+	///
+	/// Must also be called if the modes were set on the handle by other means. Once
+	/// `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, writes that complete synchronously are returned as
+	/// `Issued::Inline` and never count as queued.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		try!(unsafe { set_notification_modes(self.handle, modes) });
+		
+		if modes.contains(NotificationModes::SKIP_COMPLETION_PORT_ON_SUCCESS) {
+			self.skip_on_success = true;
+		}
+		
+		Ok(())
+	}
+	/// Returns the number of bytes submitted but not yet written.
+	pub fn queued_bytes(&self) -> usize {
+		self.queued
+	}
+	/// Returns the number of writes that are pending.
+	pub fn pending_writes(&self) -> usize {
+		self.ops.len()
+	}
+	/// Returns true while the queued bytes are above the low-water mark after reaching the high-water mark.
+	pub fn is_high(&self) -> bool {
+		self.high
+	}
+	/// Submits an overlapped write of the given data, which is copied.
+	///
+	/// Returns the OVERLAPPED pointer the completion will carry, or the number of bytes written by
+	/// a write that finished synchronously. Data longer than a DWORD can count is rejected with
+	/// ERROR_INVALID_PARAMETER.
+	pub fn write(&mut self, data: &[u8]) -> IocpResult<Issued<usize>> {
+		if data.len() > winapi::DWORD::max_value() as usize {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_INVALID_PARAMETER as i32))
+			);
+		}
+		
+		if self.fail_fast && self.queued + data.len() > self.high_water {
+			return Err(
+				IocpError::HostError(IOError::new(ErrorKind::WouldBlock, "write queue is above its high-water mark"))
+			);
+		}
+		
+		let mut op = Box::new(WriteOp {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: data.to_vec()
+		});
+		
+		let len = op.buffer.len() as winapi::DWORD;
+		let written = unsafe { kernel32::WriteFile(self.handle, op.buffer.as_ptr() as winapi::LPCVOID, len, ptr::null_mut(), &mut op.overlapped) };
+		
+		if written == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() != Some(winapi::ERROR_IO_PENDING as i32) {
+				return Err(
+					IocpError::HostError(error)
+				);
+			}
+		} else if self.skip_on_success {
+			// No packet will follow, so the data was never queued
+			let mut transferred = 0;
+			unsafe { kernel32::GetOverlappedResult(self.handle, &mut op.overlapped, &mut transferred, winapi::FALSE) };
+			
+			return Ok(Issued::Inline(transferred as usize));
+		}
+		
+		let overlapped = &mut op.overlapped as *mut winapi::OVERLAPPED;
+		self.ops.insert(overlapped as usize, op);
+		self.queued += data.len();
+		
+		if !self.high && self.queued >= self.high_water {
+			self.high = true;
+			(self.callback)(Pressure::High, self.queued);
+		}
+		
+		Ok(Issued::Pending(overlapped))
+	}
+	/// Accounts for a dequeued packet if it completes one of this writer's writes.
+	///
+	/// Returns `None` if the packet does not belong to this writer, and otherwise the number of
+	/// bytes written or the error the write failed with.
+	pub fn complete(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+		let op = match self.ops.remove(&(packet.overlapped() as usize)) {
+			Some(op) => op,
+			None => return None
+		};
+		
+		self.queued -= op.buffer.len();
+		
+		if self.high && self.queued <= self.low_water {
+			self.high = false;
+			(self.callback)(Pressure::Relieved, self.queued);
+		}
+		
+		packet.byte_count()
+	}
+}
+
+impl<F> Drop for BoundedWriter<F> {
+	fn drop(&mut self) {
+		// The handle is borrowed, so only the writer's own operations are cancelled
+		for (overlapped, op) in self.ops.drain() {
+			self.port.inner.retire_and_cancel(self.handle, overlapped as *mut winapi::OVERLAPPED, op);
+		}
+	}
+}
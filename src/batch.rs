@@ -0,0 +1,221 @@
+//! Dequeues packets in batches and dispatches them one at a time.
+//!
+//! GetQueuedCompletionStatusEx removes several packets in one call, so a busy port pays for one
+//! system call per batch instead of one per packet.
+
+use std::{cmp, mem};
+use std::time::Duration;
+
+use kernel32;
+use winapi;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use {IoCompletionPort, DequeueResult, IocpResult, IocpError, Packet, timeout_millis, wake_marker, stash_marker};
+
+use std::io::Error as IOError;
+
+/// Counters describing the batches dequeued by a BatchDispatcher.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BatchStats {
+	/// The number of waits that returned packets
+	pub batches: u64,
+	/// The total number of packets dequeued
+	pub packets: u64,
+	/// The number of waits that timed out
+	pub timeouts: u64,
+	/// The size of the largest batch
+	pub largest: usize,
+	/// The number of batches that filled the whole buffer
+	pub full: u64
+}
+
+impl BatchStats {
+	/// Returns the average number of packets per batch.
+	pub fn average(&self) -> f64 {
+		if self.batches == 0 { 0.0 } else { self.packets as f64 / self.batches as f64 }
+	}
+}
+
+/// Repeatedly dequeues up to `batch_size` packets and passes each to a handler.
+///
+/// The buffer for the entries is allocated once and reused for every batch.
+pub struct BatchDispatcher {
+	entries: Vec<winapi::OVERLAPPED_ENTRY>,
+	packets: Vec<DequeueResult>,
+	stats: BatchStats
+}
+
+unsafe impl Send for BatchDispatcher { }
+
+impl BatchDispatcher {
+	/// Creates a dispatcher removing up to `batch_size` packets per wait.
+	pub fn new(batch_size: usize) -> BatchDispatcher {
+		let batch_size = cmp::max(batch_size, 1);
+		
+		BatchDispatcher {
+			entries: vec![unsafe { mem::zeroed() }; batch_size],
+			packets: Vec::with_capacity(batch_size),
+			stats: BatchStats::default()
+		}
+	}
+	/// Returns the counters for the batches dequeued so far.
+	pub fn stats(&self) -> BatchStats {
+		self.stats
+	}
+	/// Resets the counters.
+	pub fn reset_stats(&mut self) {
+		self.stats = BatchStats::default();
+	}
+	/// Dispatches packets to the handler one at a time until it returns false.
+	///
+	/// Returns once the handler asks to stop or once a wait times out, and fails with
+	/// `IocpError::PortClosed` once the port is shut down. The packets of the batch the handler
+	/// has not been given yet when it asks to stop are kept by the port, and are returned by the
+	/// next call to `run` or any of the port's dequeueing methods.
+	pub fn run<F>(&mut self, port: &IoCompletionPort, timeout: Option<Duration>, mut handler: F) -> IocpResult<()>
+		where F: FnMut(DequeueResult) -> bool
+	{
+		self.run_slices(port, timeout, |packets| {
+			// Taken from the back, so that the packets not handled yet stay in the batch
+			packets.reverse();
+			while let Some(packet) = packets.pop() {
+				if !handler(packet) {
+					packets.reverse();
+					return false;
+				}
+			}
+			true
+		})
+	}
+	/// Dispatches each batch to the handler as a whole until it returns false.
+	///
+	/// Behaves like `run`. The handler may remove packets from the batch, and the ones it leaves
+	/// in the batch when it asks to stop are kept by the port.
+	pub fn run_slices<F>(&mut self, port: &IoCompletionPort, timeout: Option<Duration>, mut handler: F) -> IocpResult<()>
+		where F: FnMut(&mut Vec<DequeueResult>) -> bool
+	{
+		loop {
+			try!(port.inner.check_open());
+			
+			// Packets kept by IoCompletionPort::wait_for go first
+			self.packets.clear();
+			while self.packets.len() < self.entries.len() {
+				match port.inner.unstash(None) {
+					Some(packet) => self.packets.push(packet.into_result()),
+					None => break
+				}
+			}
+			
+			if !self.packets.is_empty() {
+				if !handler(&mut self.packets) {
+					self.keep_rest(port);
+					return Ok(());
+				}
+				continue;
+			}
+			
+			let removed = match try!(self.dequeue(port, timeout)) {
+				Some(removed) => removed,
+				None => {
+					self.stats.timeouts += 1;
+					return Ok(());
+				}
+			};
+			
+			self.stats.batches += 1;
+			self.stats.packets += removed as u64;
+			self.stats.largest = cmp::max(self.stats.largest, removed);
+			if removed == self.entries.len() {
+				self.stats.full += 1;
+			}
+			
+			let mut wakes = 0;
+			
+			for entry in self.entries[..removed].iter() {
+				if port.inner.reap(entry.lpOverlapped) {
+					continue;
+				}
+				
+				if entry.lpOverlapped == wake_marker() {
+					wakes += 1;
+					continue;
+				}
+				
+				if entry.lpOverlapped == stash_marker() {
+					if let Some(packet) = port.inner.unstash(None) {
+						self.packets.push(packet.into_result());
+					}
+					continue;
+				}
+				
+				self.packets.push(Packet::from_entry(entry).into_result());
+			}
+			
+			if self.packets.is_empty() {
+				if wakes > 0 {
+					port.inner.repost_wakes(wakes - 1);
+					return Err(IocpError::PortClosed);
+				}
+				continue;
+			}
+			
+			port.inner.repost_wakes(wakes);
+			
+			if !handler(&mut self.packets) {
+				self.keep_rest(port);
+				return Ok(());
+			}
+		}
+	}
+	/// Hands the packets left in the batch back to the port, ahead of the ones it already keeps.
+	fn keep_rest(&mut self, port: &IoCompletionPort) {
+		let rest: Vec<Packet> = self.packets.drain(..).filter_map(Packet::from_result).collect();
+		let count = rest.len();
+		
+		port.inner.restash(rest);
+		port.inner.notify_stashed(count);
+	}
+	/// Fills the entries with a batch, returning `None` if the wait timed out.
+	fn dequeue(&mut self, port: &IoCompletionPort, timeout: Option<Duration>) -> IocpResult<Option<usize>> {
+		let _waiting = try!(port.inner.enter());
+		
+		let mut removed = 0;
+		
+		let queued = unsafe {
+			kernel32::GetQueuedCompletionStatusEx(
+				port.inner.inner,
+				self.entries.as_mut_ptr(),
+				self.entries.len() as winapi::ULONG,
+				&mut removed,
+				timeout_millis(timeout),
+				winapi::FALSE
+			)
+		};
+		
+		if queued == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() == Some(winapi::WAIT_TIMEOUT as i32) {
+				return Ok(None);
+			}
+			return Err(
+				IocpError::HostError(error)
+			);
+		}
+		
+		Ok(Some(removed as usize))
+	}
+}
+
+/// Dispatches packets from the port to the handler in batches of up to `batch_size`.
+///
+/// Runs until the handler returns false or a wait times out, as `BatchDispatcher::run` does, and
+/// returns the counters for the batches that were dequeued.
+pub fn run_batched<F>(port: &IoCompletionPort, batch_size: usize, timeout: Option<Duration>, handler: F) -> IocpResult<BatchStats>
+	where F: FnMut(DequeueResult) -> bool
+{
+	let mut dispatcher = BatchDispatcher::new(batch_size);
+	try!(dispatcher.run(port, timeout, handler));
+	Ok(dispatcher.stats())
+}
@@ -0,0 +1,160 @@
+//! Blocking `Read`/`Write` adapters for handles opened for overlapped I/O.
+//!
+//! Operations are submitted with a private event whose low-order bit is set, which stops the
+//! system from queueing a completion packet to the port the handle is associated with.
+
+use std::{cmp, io, mem, ptr};
+use std::io::{Read, Write, Seek, SeekFrom};
+
+use kernel32;
+use winapi;
+
+use {IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+/// Performs synchronous reads and writes on a handle that was opened for overlapped I/O.
+///
+/// The handle is borrowed, not owned: it can stay associated with an IoCompletionPort and keep
+/// being used for asynchronous operations elsewhere. The calling thread blocks until each
+/// operation completes.
+///
+/// Operations that complete synchronously are returned inline without waiting on the event, so
+/// this also works on handles that have FILE_SKIP_COMPLETION_PORT_ON_SUCCESS or
+/// FILE_SKIP_SET_EVENT_ON_HANDLE set.
+pub struct BlockingIo {
+	handle: winapi::HANDLE,
+	event: winapi::HANDLE,
+	offset: u64
+}
+
+unsafe impl Send for BlockingIo { }
+
+impl BlockingIo {
+	/// Creates a new BlockingIo for the given handle, starting at offset zero.
+	pub fn new(handle: winapi::HANDLE) -> IocpResult<BlockingIo> {
+		let event = unsafe { kernel32::CreateEventW(ptr::null_mut(), winapi::TRUE, winapi::FALSE, ptr::null()) };
+		
+		if event.is_null() {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(BlockingIo {
+			handle: handle,
+			event: event,
+			offset: 0
+		})
+	}
+	/// Returns the handle this BlockingIo operates on.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.handle
+	}
+	/// Returns the file offset used for the next operation.
+	///
+	/// The offset is ignored by handles that are not seekable, such as pipes and sockets.
+	pub fn offset(&self) -> u64 {
+		self.offset
+	}
+	/// Sets the file offset used for the next operation.
+	pub fn set_offset(&mut self, offset: u64) {
+		self.offset = offset;
+	}
+	fn transfer(&mut self, buf: *mut u8, len: usize, write: bool) -> io::Result<usize> {
+		let len = cmp::min(len, winapi::DWORD::max_value() as usize) as winapi::DWORD;
+		
+		let mut overlapped: winapi::OVERLAPPED = unsafe { mem::zeroed() };
+		overlapped.Offset = self.offset as winapi::DWORD;
+		overlapped.OffsetHigh = (self.offset >> 32) as winapi::DWORD;
+		overlapped.hEvent = (self.event as usize | 1) as winapi::HANDLE;
+		
+		let submitted = unsafe {
+			if write {
+				kernel32::WriteFile(self.handle, buf as winapi::LPCVOID, len, ptr::null_mut(), &mut overlapped)
+			} else {
+				kernel32::ReadFile(self.handle, buf as winapi::LPVOID, len, ptr::null_mut(), &mut overlapped)
+			}
+		};
+		
+		let transferred = if submitted != 0 {
+			// A synchronous success has already filled in the OVERLAPPED, and the event may never be set
+			overlapped.InternalHigh as winapi::DWORD
+		} else {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() != Some(winapi::ERROR_IO_PENDING as i32) {
+				return end_of_stream(error, write);
+			}
+			
+			let mut transferred = 0;
+			let finished = unsafe { kernel32::GetOverlappedResult(self.handle, &mut overlapped, &mut transferred, winapi::TRUE) };
+			
+			if finished == 0 {
+				return end_of_stream(IOError::last_os_error(), write);
+			}
+			
+			transferred
+		};
+		
+		self.offset += transferred as u64;
+		
+		Ok(transferred as usize)
+	}
+}
+
+/// Reports end-of-file and a closed pipe as a zero-length read, as `Read` expects.
+fn end_of_stream(error: IOError, write: bool) -> io::Result<usize> {
+	match error.raw_os_error() {
+		Some(code) if !write && (code == winapi::ERROR_HANDLE_EOF as i32 || code == winapi::ERROR_BROKEN_PIPE as i32) => Ok(0),
+		_ => Err(error)
+	}
+}
+
+impl Read for BlockingIo {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = buf.len();
+		self.transfer(buf.as_mut_ptr(), len, false)
+	}
+}
+
+impl Write for BlockingIo {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.transfer(buf.as_ptr() as *mut u8, buf.len(), true)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Seek for BlockingIo {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let (base, delta) = match pos {
+			SeekFrom::Start(offset) => {
+				self.offset = offset;
+				return Ok(offset);
+			},
+			SeekFrom::Current(delta) => (self.offset, delta),
+			SeekFrom::End(delta) => {
+				let mut size: winapi::LARGE_INTEGER = 0;
+				if unsafe { kernel32::GetFileSizeEx(self.handle, &mut size) } == 0 {
+					return Err(IOError::last_os_error());
+				}
+				(size as u64, delta)
+			}
+		};
+		
+		let offset = base as i64 + delta;
+		if offset < 0 {
+			return Err(IOError::new(io::ErrorKind::InvalidInput, "invalid seek to a negative offset"));
+		}
+		
+		self.offset = offset as u64;
+		Ok(self.offset)
+	}
+}
+
+impl Drop for BlockingIo {
+	fn drop(&mut self) {
+		unsafe { let _ = kernel32::CloseHandle(self.event); }
+	}
+}
@@ -0,0 +1,86 @@
+//! Device control requests issued with overlapped I/O through a port.
+//!
+//! Drivers are talked to with DeviceIoControl. Opening the device for overlapped I/O lets the
+//! requests complete through the port like any other operation, so a slow driver does not tie
+//! up a thread.
+
+use std::fs::{File, OpenOptions};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::IntoRawHandle;
+use std::path::Path;
+
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError};
+use handle::AssociatedHandle;
+
+pub use handle::Completed;
+
+/// Builds a device control code, like the CTL_CODE macro.
+pub fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+	(device_type << 16) | (access << 14) | (function << 2) | method
+}
+
+/// A device opened for overlapped I/O and associated with a port.
+///
+/// Completions arrive with the device's completion key and the OVERLAPPED pointer returned when
+/// the request was started; pass them to `complete` to get the buffers back. The buffers are
+/// owned by the device until then, and requests still in flight when the device is dropped are
+/// cancelled.
+pub struct AsyncDevice {
+	inner: AssociatedHandle
+}
+
+impl AsyncDevice {
+	/// Opens a device, such as ```\\.\MyDriver```, for reading and writing.
+	pub fn open<P: AsRef<Path>>(port: &IoCompletionPort, path: P, completion_key: usize) -> IocpResult<AsyncDevice> {
+		AsyncDevice::open_with(port, path, OpenOptions::new().read(true).write(true), completion_key)
+	}
+	/// Opens a device with the given options, adding FILE_FLAG_OVERLAPPED to them.
+	pub fn open_with<P: AsRef<Path>>(port: &IoCompletionPort, path: P, options: &mut OpenOptions, completion_key: usize) -> IocpResult<AsyncDevice> {
+		let file = match options.custom_flags(winapi::FILE_FLAG_OVERLAPPED).open(path) {
+			Ok(file) => file,
+			Err(error) => return Err(IocpError::HostError(error))
+		};
+		
+		AsyncDevice::from_file(port, file, completion_key)
+	}
+	/// Takes over a device that was opened with FILE_FLAG_OVERLAPPED and associates it with the port.
+	pub fn from_file(port: &IoCompletionPort, file: File, completion_key: usize) -> IocpResult<AsyncDevice> {
+		let handle = file.into_raw_handle() as winapi::HANDLE;
+		
+		Ok(AsyncDevice {
+			inner: try!(AssociatedHandle::new(port, handle, completion_key))
+		})
+	}
+	/// Returns the handle of the device.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.inner.handle()
+	}
+	/// Returns the number of requests in flight.
+	pub fn in_flight(&self) -> usize {
+		self.inner.in_flight()
+	}
+	/// Sets the notification modes of the device.
+	///
+	/// Once `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, requests that complete synchronously are
+	/// returned as `Issued::Inline`.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		self.inner.set_notification_modes(modes)
+	}
+	/// Sends a request with the given control code to the driver.
+	///
+	/// Returns the OVERLAPPED pointer the completion will carry, or the finished request. Either
+	/// buffer can be empty. The output buffer comes back in `Completed::buffer`, with the number of
+	/// bytes the driver wrote to it as the result.
+	pub fn ioctl(&mut self, control_code: u32, input: Vec<u8>, output: Vec<u8>) -> IocpResult<Issued<Completed>> {
+		self.inner.device_io_control(control_code, input, output)
+	}
+	/// Takes back the buffers of the request a dequeued packet completes.
+	///
+	/// Returns `None` if the packet does not belong to this device. A request whose output did not
+	/// fit fails with ERROR_MORE_DATA, but the output buffer still holds the part that did.
+	pub fn complete(&mut self, packet: &DequeueResult) -> Option<Completed> {
+		self.inner.complete(packet)
+	}
+}
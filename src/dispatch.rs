@@ -0,0 +1,197 @@
+//! A reactor running handlers for the packets of a port on a pool of worker threads.
+//!
+//! Packets are routed by completion key. Workers dequeue them in batches of up to `MAX_BATCH`, so
+//! a busy port pays for one system call per batch rather than one per packet.
+
+use std::{cmp, thread};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+
+use winapi;
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult, MAX_BATCH};
+
+/// Handles the packets routed to it by a Dispatcher.
+///
+/// Handlers are called from several workers at once.
+pub trait Handler: Send + Sync {
+	/// Handles a dequeued packet, which tells whether its operation succeeded.
+	fn handle(&self, packet: DequeueResult);
+}
+
+impl<F> Handler for F where F: Fn(DequeueResult) + Send + Sync {
+	fn handle(&self, packet: DequeueResult) {
+		self(packet)
+	}
+}
+
+struct Routes {
+	handlers: HashMap<usize, Arc<dyn Handler>>,
+	fallback: Option<Arc<dyn Handler>>
+}
+
+impl Routes {
+	fn route(&self, completion_key: usize) -> Option<Arc<dyn Handler>> {
+		self.handlers.get(&completion_key).or(self.fallback.as_ref()).cloned()
+	}
+}
+
+static STOP: u8 = 0;
+
+/// The OVERLAPPED pointer carried by the packet that tells a worker to exit.
+fn stop_marker() -> *mut winapi::OVERLAPPED {
+	&STOP as *const u8 as *mut winapi::OVERLAPPED
+}
+
+/// Routes the packets of a port to handlers registered per completion key.
+///
+/// Handlers can be registered and removed while workers are running. Packets whose key has no
+/// handler go to the fallback handler, and are discarded if there is none.
+pub struct Dispatcher {
+	port: IoCompletionPort,
+	routes: Arc<RwLock<Routes>>,
+	batch_size: usize,
+	workers: Vec<JoinHandle<()>>
+}
+
+impl Dispatcher {
+	/// Creates a dispatcher for the given port, without any handlers.
+	pub fn new(port: &IoCompletionPort) -> Dispatcher {
+		Dispatcher {
+			port: port.clone(),
+			routes: Arc::new(RwLock::new(Routes {
+				handlers: HashMap::new(),
+				fallback: None
+			})),
+			batch_size: MAX_BATCH,
+			workers: Vec::new()
+		}
+	}
+	/// Returns the port packets are dequeued from.
+	pub fn port(&self) -> &IoCompletionPort {
+		&self.port
+	}
+	/// Sets the most packets a worker dequeues at once, between one and `MAX_BATCH`.
+	///
+	/// Only affects workers started afterwards.
+	pub fn set_batch_size(&mut self, batch_size: usize) {
+		self.batch_size = cmp::min(cmp::max(batch_size, 1), MAX_BATCH);
+	}
+	/// Registers the handler for packets with the given completion key.
+	///
+	/// Replaces any handler already registered for the key.
+	pub fn register<H>(&self, completion_key: usize, handler: H) where H: Handler + 'static {
+		self.routes.write().unwrap().handlers.insert(completion_key, Arc::new(handler));
+	}
+	/// Associates the given file handle with the port and registers the handler for its packets.
+	///
+	/// The handle's value is used as its completion key, which is returned.
+	pub fn register_handle<H>(&self, handle: winapi::HANDLE, handler: H) -> IocpResult<usize> where H: Handler + 'static {
+		let completion_key = handle as usize;
+		
+		try!(self.port.associate(handle, completion_key));
+		self.register(completion_key, handler);
+		
+		Ok(completion_key)
+	}
+	/// Removes the handler for the given completion key.
+	///
+	/// Returns false if no handler was registered for it.
+	pub fn deregister(&self, completion_key: usize) -> bool {
+		self.routes.write().unwrap().handlers.remove(&completion_key).is_some()
+	}
+	/// Sets the handler for packets whose completion key has no handler of its own.
+	pub fn set_fallback<H>(&self, handler: H) where H: Handler + 'static {
+		self.routes.write().unwrap().fallback = Some(Arc::new(handler));
+	}
+	/// Returns the number of workers started by `run` that have not been stopped.
+	pub fn workers(&self) -> usize {
+		self.workers.len()
+	}
+	/// Starts the given number of worker threads dispatching packets from the port.
+	///
+	/// Can be called again to add workers. A worker exits when `stop` is called or when waiting on
+	/// the port fails, such as after `IoCompletionPort::shutdown`.
+	pub fn run(&mut self, threads: usize) {
+		for _ in 0..threads {
+			let port = self.port.clone();
+			let routes = self.routes.clone();
+			let batch_size = self.batch_size;
+			
+			self.workers.push(thread::spawn(move || work(&port, &routes, batch_size)));
+		}
+	}
+	/// Stops every worker once it has dispatched the packets queued before this call, and waits
+	/// for them to exit.
+	///
+	/// The workers are waited for even if posting their stop packets fails, for example because
+	/// the port has been shut down, in which case they exit on their own; the error is returned
+	/// once they have.
+	pub fn stop(&mut self) -> IocpResult<()> {
+		let mut result = Ok(());
+		
+		for _ in 0..self.workers.len() {
+			result = self.port.inner.post_internal(CompletionStatus {
+				byte_count: 0,
+				completion_key: 0,
+				overlapped: stop_marker()
+			});
+			
+			if result.is_err() {
+				break;
+			}
+		}
+		
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+		
+		result
+	}
+}
+
+impl Drop for Dispatcher {
+	fn drop(&mut self) {
+		let _ = self.stop();
+	}
+}
+
+fn work(port: &IoCompletionPort, routes: &RwLock<Routes>, batch_size: usize) {
+	let mut packets = Vec::with_capacity(batch_size);
+	
+	loop {
+		if port.inner.dequeue_many(batch_size, None, false, |packet| packets.push(packet)).is_err() {
+			return;
+		}
+		
+		let mut stops = 0;
+		
+		for packet in packets.drain(..) {
+			if packet.status.overlapped == stop_marker() {
+				stops += 1;
+				continue;
+			}
+			
+			// The lock is released before the handler runs, so handlers can register others
+			let handler = routes.read().unwrap().route(packet.status.completion_key);
+			
+			if let Some(handler) = handler {
+				handler.handle(packet.into_result());
+			}
+		}
+		
+		// The rest of the batch has been dispatched, since it was already removed from the port.
+		// A batch can hold the markers meant for other workers, which are handed back to them.
+		if stops > 0 {
+			for _ in 1..stops {
+				let _ = port.inner.post_internal(CompletionStatus {
+					byte_count: 0,
+					completion_key: 0,
+					overlapped: stop_marker()
+				});
+			}
+			return;
+		}
+	}
+}
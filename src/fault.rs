@@ -0,0 +1,212 @@
+//! Fault injection for testing how applications handle misbehaving completions.
+//!
+//! FaultyPort wraps a port and, according to a seeded policy, turns some dequeues into timeouts,
+//! spurious wake-ups, short transfers and aborted operations. The same seed always injects the
+//! same sequence of faults.
+
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use winapi;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult};
+
+/// How often each kind of fault is injected, as a probability between 0 and 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultPolicy {
+	/// The seed of the pseudo-random sequence deciding which faults are injected
+	pub seed: u64,
+	/// The probability that a dequeue reports a timeout without removing a packet
+	pub timeout: f64,
+	/// The probability that a dequeue returns a packet nobody posted
+	pub spurious_wakeup: f64,
+	/// The probability that a packet's byte count is reduced
+	pub short_transfer: f64,
+	/// The probability that an I/O completion is reported as cancelled
+	pub aborted: f64,
+	/// The completion key carried by spurious packets
+	pub spurious_key: usize
+}
+
+impl FaultPolicy {
+	/// Creates a policy with the given seed that injects no faults.
+	pub fn new(seed: u64) -> FaultPolicy {
+		FaultPolicy {
+			seed: seed,
+			timeout: 0.0,
+			spurious_wakeup: 0.0,
+			short_transfer: 0.0,
+			aborted: 0.0,
+			spurious_key: 0
+		}
+	}
+}
+
+/// The number of faults of each kind injected so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FaultCounts {
+	/// Injected timeouts
+	pub timeouts: u64,
+	/// Injected spurious packets
+	pub spurious_wakeups: u64,
+	/// Packets whose byte count was reduced
+	pub short_transfers: u64,
+	/// Completions turned into ERROR_OPERATION_ABORTED failures
+	pub aborted: u64
+}
+
+struct FaultState {
+	rng: u64,
+	counts: FaultCounts
+}
+
+impl FaultState {
+	/// Returns the next number of the xorshift64* sequence.
+	fn next(&mut self) -> u64 {
+		self.rng ^= self.rng >> 12;
+		self.rng ^= self.rng << 25;
+		self.rng ^= self.rng >> 27;
+		self.rng.wrapping_mul(0x2545F4914F6CDD1D)
+	}
+	fn chance(&mut self, probability: f64) -> bool {
+		probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+	}
+}
+
+/// A port that injects faults into the packets it dequeues.
+///
+/// Faults are only injected by `get_queued`. Every other method behaves exactly like the wrapped
+/// port, which stays reachable through `port`.
+pub struct FaultyPort {
+	port: IoCompletionPort,
+	policy: FaultPolicy,
+	state: Mutex<FaultState>
+}
+
+unsafe impl Send for FaultyPort { }
+unsafe impl Sync for FaultyPort { }
+
+impl FaultyPort {
+	/// Wraps the given port.
+	pub fn new(port: IoCompletionPort, policy: FaultPolicy) -> FaultyPort {
+		FaultyPort {
+			port: port,
+			policy: policy,
+			state: Mutex::new(FaultState {
+				// xorshift gets stuck at zero
+				rng: if policy.seed == 0 { 0x9E3779B97F4A7C15 } else { policy.seed },
+				counts: FaultCounts::default()
+			})
+		}
+	}
+	/// Returns the wrapped port.
+	pub fn port(&self) -> &IoCompletionPort {
+		&self.port
+	}
+	/// Returns the policy faults are injected by.
+	pub fn policy(&self) -> FaultPolicy {
+		self.policy
+	}
+	/// Returns the number of faults injected so far.
+	pub fn counts(&self) -> FaultCounts {
+		self.state.lock().unwrap().counts
+	}
+	/// Associates the given file handle with the wrapped port.
+	pub fn associate(&self, handle: winapi::HANDLE, completion_key: usize) -> IocpResult<()> {
+		self.port.associate(handle, completion_key)
+	}
+	/// Posts an I/O completion packet to the wrapped port.
+	pub fn post_queued(&self, packet: CompletionStatus) -> IocpResult<()> {
+		self.port.post_queued(packet)
+	}
+	/// Attempts to dequeue an I/O completion packet, possibly injecting a fault.
+	///
+	/// An injected timeout returns immediately and leaves the queued packets in place. Packets
+	/// with a null OVERLAPPED pointer, which were posted rather than completed, are never shortened
+	/// or aborted.
+	pub fn get_queued(&self, timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		{
+			let mut state = self.state.lock().unwrap();
+			
+			if state.chance(self.policy.timeout) {
+				state.counts.timeouts += 1;
+				return Ok(DequeueResult::TimedOut);
+			}
+			
+			if state.chance(self.policy.spurious_wakeup) {
+				state.counts.spurious_wakeups += 1;
+				return Ok(DequeueResult::Completed(CompletionStatus {
+					byte_count: 0,
+					completion_key: self.policy.spurious_key,
+					overlapped: ptr::null_mut()
+				}));
+			}
+		}
+		
+		let mut status = match try!(self.port.get_queued(timeout)) {
+			DequeueResult::Completed(status) => status,
+			other => return Ok(other)
+		};
+		
+		if status.overlapped.is_null() {
+			return Ok(DequeueResult::Completed(status));
+		}
+		
+		let mut state = self.state.lock().unwrap();
+		
+		if state.chance(self.policy.aborted) {
+			state.counts.aborted += 1;
+			return Ok(DequeueResult::Cancelled(status));
+		}
+		
+		if status.byte_count > 1 && state.chance(self.policy.short_transfer) {
+			state.counts.short_transfers += 1;
+			status.byte_count = 1 + (state.next() % (status.byte_count as u64 - 1)) as usize;
+		}
+		
+		Ok(DequeueResult::Completed(status))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	
+	use {IoCompletionPort, CompletionStatus};
+	use super::{FaultyPort, FaultPolicy, FaultCounts};
+	
+	/// Posts a run of packets and records what each dequeue returned.
+	fn run(seed: u64) -> (Vec<Option<usize>>, FaultCounts) {
+		let mut policy = FaultPolicy::new(seed);
+		policy.timeout = 0.3;
+		policy.spurious_wakeup = 0.3;
+		policy.spurious_key = 99;
+		
+		let port = FaultyPort::new(IoCompletionPort::new(1).unwrap(), policy);
+		for key in 1..17 {
+			let mut packet = CompletionStatus::new();
+			packet.completion_key = key;
+			port.post_queued(packet).unwrap();
+		}
+		
+		let outcomes = (0..32).map(|_| {
+			port.get_queued(Some(Duration::from_millis(0))).unwrap().status().map(|status| status.completion_key)
+		}).collect();
+		
+		(outcomes, port.counts())
+	}
+
+	#[test]
+	fn same_seed_injects_the_same_faults() {
+		let (outcomes, counts) = run(42);
+		
+		assert_eq!(run(42), (outcomes.clone(), counts));
+		assert!(counts.timeouts > 0);
+		assert!(counts.spurious_wakeups > 0);
+		assert_eq!(outcomes.iter().filter(|key| **key == Some(99)).count() as u64, counts.spurious_wakeups);
+	}
+}
@@ -0,0 +1,251 @@
+//! Communication ports of file system minifilter drivers.
+//!
+//! A user-mode service connects to the port a minifilter creates and receives the driver's
+//! messages through overlapped FilterGetMessage calls, so they arrive on the same I/O completion
+//! port as the service's other I/O.
+
+use std::{cmp, mem, ptr, slice};
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Overlapped, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+#[repr(C)]
+struct FilterMessageHeader {
+	reply_length: winapi::ULONG,
+	message_id: u64
+}
+
+#[repr(C)]
+struct FilterReplyHeader {
+	status: winapi::LONG,
+	message_id: u64
+}
+
+#[link(name = "fltlib")]
+extern "system" {
+	fn FilterConnectCommunicationPort(
+		port_name: winapi::LPCWSTR,
+		options: winapi::DWORD,
+		context: winapi::LPCVOID,
+		size_of_context: winapi::WORD,
+		security_attributes: winapi::LPSECURITY_ATTRIBUTES,
+		port: *mut winapi::HANDLE
+	) -> winapi::HRESULT;
+	fn FilterGetMessage(
+		port: winapi::HANDLE,
+		message_buffer: *mut FilterMessageHeader,
+		message_buffer_size: winapi::DWORD,
+		overlapped: *mut winapi::OVERLAPPED
+	) -> winapi::HRESULT;
+	fn FilterReplyMessage(
+		port: winapi::HANDLE,
+		reply_buffer: *mut FilterReplyHeader,
+		reply_buffer_size: winapi::DWORD
+	) -> winapi::HRESULT;
+}
+
+/// Converts a failed HRESULT into an error, unwrapping Win32 error codes.
+fn hresult_error(result: winapi::HRESULT) -> IocpError {
+	let result = result as u32;
+	
+	let code = if result & 0xFFFF0000 == 0x80070000 { result & 0xFFFF } else { result };
+	IocpError::HostError(IOError::from_raw_os_error(code as i32))
+}
+
+fn is_pending(result: winapi::HRESULT) -> bool {
+	result as u32 == 0x80070000 | winapi::ERROR_IO_PENDING
+}
+
+/// A connection to a minifilter's communication port, opened for overlapped I/O.
+pub struct FilterPort {
+	handle: winapi::HANDLE,
+	port: IoCompletionPort
+}
+
+unsafe impl Send for FilterPort { }
+unsafe impl Sync for FilterPort { }
+
+impl FilterPort {
+	/// Connects to the communication port with the given name, such as ```\ScannerPort```, and
+	/// associates the connection with the port.
+	///
+	/// The context is passed to the driver's connect notification.
+	pub fn connect(port: &IoCompletionPort, name: &str, context: &[u8], completion_key: usize) -> IocpResult<FilterPort> {
+		if context.len() > winapi::WORD::max_value() as usize {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_INVALID_PARAMETER as i32))
+			);
+		}
+		
+		let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+		let mut handle = ptr::null_mut();
+		
+		let result = unsafe {
+			FilterConnectCommunicationPort(
+				name.as_ptr(),
+				0,
+				if context.is_empty() { ptr::null() } else { context.as_ptr() as winapi::LPCVOID },
+				context.len() as winapi::WORD,
+				ptr::null_mut(),
+				&mut handle
+			)
+		};
+		
+		if result < 0 {
+			return Err(hresult_error(result));
+		}
+		
+		let filter = FilterPort {
+			handle: handle,
+			port: port.clone()
+		};
+		
+		try!(port.associate(handle, completion_key));
+		
+		Ok(filter)
+	}
+	/// Returns the handle of the connection.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.handle
+	}
+	/// Replies to the message with the given identifier.
+	///
+	/// FilterReplyMessage has no overlapped form, but only copies the reply to the driver and
+	/// does not wait for it to be processed.
+	pub fn reply(&self, message_id: u64, status: i32, data: &[u8]) -> IocpResult<()> {
+		let header = mem::size_of::<FilterReplyHeader>();
+		let mut buffer = vec![0u64; (header + data.len() + 7) / 8];
+		
+		unsafe {
+			let reply = buffer.as_mut_ptr() as *mut FilterReplyHeader;
+			(*reply).status = status;
+			(*reply).message_id = message_id;
+			ptr::copy_nonoverlapping(data.as_ptr(), (reply as *mut u8).offset(header as isize), data.len());
+		}
+		
+		let result = unsafe { FilterReplyMessage(self.handle, buffer.as_mut_ptr() as *mut FilterReplyHeader, (header + data.len()) as winapi::DWORD) };
+		
+		if result < 0 {
+			return Err(hresult_error(result));
+		}
+		
+		Ok(())
+	}
+}
+
+impl Drop for FilterPort {
+	fn drop(&mut self) {
+		unsafe { let _ = kernel32::CloseHandle(self.handle); }
+	}
+}
+
+/// A message received from a minifilter.
+#[derive(Debug)]
+pub struct FilterMessage<'a> {
+	/// The identifier to pass to `FilterPort::reply`
+	pub message_id: u64,
+	/// The size of the reply the driver expects, zero if it expects none
+	pub reply_length: u32,
+	/// The body of the message
+	pub data: &'a [u8]
+}
+
+/// A buffer receiving one message at a time from a FilterPort.
+///
+/// The completion of `get` arrives with the connection's completion key and an OVERLAPPED pointer
+/// equal to `overlapped()`. Several slots can be pending on one connection to receive messages
+/// concurrently, and borrow it so that it outlives them. Dropping a pending slot cancels its
+/// wait and hands the buffer to the port, which discards the aborted packet.
+pub struct MessageSlot<'a> {
+	filter: &'a FilterPort,
+	buffer: Vec<u64>,
+	overlapped: Overlapped<()>,
+	pending: bool
+}
+
+unsafe impl<'a> Send for MessageSlot<'a> { }
+
+impl<'a> MessageSlot<'a> {
+	/// Creates a slot for messages of up to `size` bytes, excluding the message header.
+	pub fn new(filter: &'a FilterPort, size: usize) -> MessageSlot<'a> {
+		let len = mem::size_of::<FilterMessageHeader>() + size;
+		
+		MessageSlot {
+			filter: filter,
+			buffer: vec![0; (len + 7) / 8],
+			overlapped: Overlapped::new(()),
+			pending: false
+		}
+	}
+	/// Returns the OVERLAPPED pointer carried by the completion of `get`.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.overlapped.as_ptr()
+	}
+	/// Returns true while a message is being waited for.
+	pub fn is_pending(&self) -> bool {
+		self.pending
+	}
+	/// Starts waiting for the next message.
+	///
+	/// Does nothing if a message is already being waited for.
+	pub fn get(&mut self) -> IocpResult<()> {
+		if self.pending {
+			return Ok(());
+		}
+		
+		self.overlapped.reset();
+		
+		let size = cmp::min(self.buffer.len() * 8, winapi::DWORD::max_value() as usize) as winapi::DWORD;
+		let result = unsafe { FilterGetMessage(self.filter.handle, self.buffer.as_mut_ptr() as *mut FilterMessageHeader, size, self.overlapped()) };
+		
+		if result < 0 && !is_pending(result) {
+			return Err(hresult_error(result));
+		}
+		
+		self.pending = true;
+		
+		Ok(())
+	}
+	/// Returns the message received if the given packet completes the pending `get`.
+	///
+	/// Returns `None` if the packet does not belong to this slot. The slot can be re-armed with
+	/// `get` once the message is no longer needed.
+	pub fn message<'b>(&'b mut self, packet: &DequeueResult) -> Option<IocpResult<FilterMessage<'b>>> {
+		if !self.pending || packet.overlapped() != self.overlapped() {
+			return None;
+		}
+		
+		self.pending = false;
+		
+		let buffer = &self.buffer;
+		packet.byte_count().map(|result| result.map(|count| {
+			let header = mem::size_of::<FilterMessageHeader>();
+			let len = cmp::min(count, buffer.len() * 8).saturating_sub(header);
+			
+			unsafe {
+				let message = &*(buffer.as_ptr() as *const FilterMessageHeader);
+				FilterMessage {
+					message_id: message.message_id,
+					reply_length: message.reply_length,
+					data: slice::from_raw_parts((buffer.as_ptr() as *const u8).offset(header as isize), len)
+				}
+			}
+		}))
+	}
+}
+
+impl<'a> Drop for MessageSlot<'a> {
+	fn drop(&mut self) {
+		// Other slots can be pending on the same connection, so only this one is cancelled
+		if self.pending {
+			let retired = (mem::replace(&mut self.overlapped, Overlapped::new(())), mem::replace(&mut self.buffer, Vec::new()));
+			self.filter.port.inner.retire_and_cancel(self.filter.handle, retired.0.as_ptr(), Box::new(retired));
+		}
+	}
+}
@@ -0,0 +1,134 @@
+//! Files read and written with overlapped I/O through a port.
+//!
+//! Files are opened with FILE_FLAG_OVERLAPPED, so every read and write is positioned by its own
+//! offset and any number of them can be in flight at once.
+
+use std::fs::{File, OpenOptions};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::IntoRawHandle;
+use std::path::Path;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError};
+use handle::AssociatedHandle;
+#[cfg(feature = "pool")]
+use pool::{BufferPool, PooledOverlapped};
+
+use std::io::Error as IOError;
+
+pub use handle::Completed;
+
+/// A file opened for overlapped I/O and associated with a port.
+///
+/// Completions arrive with the file's completion key and the OVERLAPPED pointer returned when the
+/// operation was started; pass them to `complete` to get the buffer back. A read or write that
+/// finishes synchronously still queues its packet, so every started operation completes the same
+/// way, unless `SKIP_COMPLETION_PORT_ON_SUCCESS` has been set with `set_notification_modes`.
+/// Operations still in flight when the file is dropped are cancelled.
+pub struct AsyncFile {
+	inner: AssociatedHandle
+}
+
+impl AsyncFile {
+	/// Opens an existing file for reading.
+	pub fn open<P: AsRef<Path>>(port: &IoCompletionPort, path: P, completion_key: usize) -> IocpResult<AsyncFile> {
+		AsyncFile::open_with(port, path, OpenOptions::new().read(true), completion_key)
+	}
+	/// Creates a file for writing, truncating it if it exists.
+	pub fn create<P: AsRef<Path>>(port: &IoCompletionPort, path: P, completion_key: usize) -> IocpResult<AsyncFile> {
+		AsyncFile::open_with(port, path, OpenOptions::new().write(true).create(true).truncate(true), completion_key)
+	}
+	/// Opens a file with the given options, adding FILE_FLAG_OVERLAPPED to them.
+	pub fn open_with<P: AsRef<Path>>(port: &IoCompletionPort, path: P, options: &mut OpenOptions, completion_key: usize) -> IocpResult<AsyncFile> {
+		let file = match options.custom_flags(winapi::FILE_FLAG_OVERLAPPED).open(path) {
+			Ok(file) => file,
+			Err(error) => return Err(IocpError::HostError(error))
+		};
+		
+		AsyncFile::from_file(port, file, completion_key)
+	}
+	/// Takes over a file that was opened with FILE_FLAG_OVERLAPPED and associates it with the port.
+	pub fn from_file(port: &IoCompletionPort, file: File, completion_key: usize) -> IocpResult<AsyncFile> {
+		let handle = file.into_raw_handle() as winapi::HANDLE;
+		
+		Ok(AsyncFile {
+			inner: try!(AssociatedHandle::new(port, handle, completion_key))
+		})
+	}
+	/// Returns the handle of the file.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.inner.handle()
+	}
+	/// Returns the size of the file in bytes.
+	pub fn size(&self) -> IocpResult<u64> {
+		let mut size: winapi::LARGE_INTEGER = 0;
+		
+		if unsafe { kernel32::GetFileSizeEx(self.inner.handle(), &mut size) } == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(size as u64)
+	}
+	/// Returns the number of reads and writes in flight.
+	pub fn in_flight(&self) -> usize {
+		self.inner.in_flight()
+	}
+	/// Sets the notification modes of the file.
+	///
+	/// Once `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, reads and writes that complete synchronously
+	/// are returned as `Issued::Inline`.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		self.inner.set_notification_modes(modes)
+	}
+	/// Starts reading into the whole buffer from the given offset.
+	///
+	/// Returns the OVERLAPPED pointer the completion will carry, or the finished read. A read
+	/// starting at or past the end of the file may fail right away with ERROR_HANDLE_EOF instead of
+	/// completing through the port.
+	pub fn read_at(&mut self, buffer: Vec<u8>, offset: u64) -> IocpResult<Issued<Completed>> {
+		self.inner.read_at(buffer, offset)
+	}
+	/// Starts writing the whole buffer at the given offset.
+	///
+	/// Returns the OVERLAPPED pointer the completion will carry, or the finished write.
+	pub fn write_at(&mut self, buffer: Vec<u8>, offset: u64) -> IocpResult<Issued<Completed>> {
+		self.inner.write_at(buffer, offset)
+	}
+	/// Starts reading into a buffer checked out of the pool, from the given offset.
+	///
+	/// The packet is passed to the pool's `complete`. Dropping the file cancels the read, and the
+	/// buffer goes back to the pool once the port has discarded the aborted packet.
+	#[cfg(feature = "pool")]
+	pub fn read_pooled(&mut self, pool: &BufferPool, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.inner.read_pooled(pool, offset)
+	}
+	/// Starts writing the data in a buffer checked out of the pool at the given offset.
+	///
+	/// Completes and is cancelled like `read_pooled`.
+	#[cfg(feature = "pool")]
+	pub fn write_pooled(&mut self, pool: &BufferPool, overlapped: PooledOverlapped, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.inner.write_pooled(pool, overlapped, offset)
+	}
+	/// Takes back the buffer of the read or write a dequeued packet completes.
+	///
+	/// Returns `None` if the packet does not belong to this file. A read that reached the end of
+	/// the file reports zero bytes transferred.
+	pub fn complete(&mut self, packet: &DequeueResult) -> Option<Completed> {
+		self.inner.complete(packet).map(|mut completed| {
+			let end_of_file = match completed.result {
+				Err(IocpError::HostError(ref error)) => error.raw_os_error() == Some(winapi::ERROR_HANDLE_EOF as i32),
+				_ => false
+			};
+			
+			if end_of_file {
+				completed.result = Ok(0);
+			}
+			
+			completed
+		})
+	}
+}
@@ -0,0 +1,67 @@
+//! A process-wide port shared by everything that does not need a port of its own.
+//!
+//! The port is created the first time it is requested. Its concurrency comes from the
+//! IOCP_CONCURRENCY environment variable unless `init_global` was called first.
+
+use std::{env, ptr};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use {IoCompletionPort, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+/// The environment variable read for the concurrency of the global port.
+pub const CONCURRENCY_VAR: &'static str = "IOCP_CONCURRENCY";
+
+static GLOBAL: AtomicPtr<IoCompletionPort> = AtomicPtr::new(0 as *mut IoCompletionPort);
+
+/// Returns the global port, creating it if necessary.
+///
+/// The port allows as many concurrently running threads as IOCP_CONCURRENCY specifies, or as many
+/// as there are processors if it is unset or invalid.
+pub fn global() -> IocpResult<&'static IoCompletionPort> {
+	let existing = GLOBAL.load(Ordering::Acquire);
+	if !existing.is_null() {
+		return Ok(unsafe { &*existing });
+	}
+	
+	let concurrency = env::var(CONCURRENCY_VAR).ok().and_then(|value| value.trim().parse().ok()).unwrap_or(0);
+	
+	install(concurrency).map(|(port, _)| port)
+}
+
+/// Creates the global port with the given number of concurrent threads.
+///
+/// Fails with an error of kind `AlreadyExists` if the global port has already been created,
+/// either by an earlier call or by `global`.
+pub fn init_global(concurrent_threads: usize) -> IocpResult<&'static IoCompletionPort> {
+	if !GLOBAL.load(Ordering::Acquire).is_null() {
+		return Err(already_created());
+	}
+	
+	match try!(install(concurrent_threads)) {
+		(port, true) => Ok(port),
+		(_, false) => Err(already_created())
+	}
+}
+
+/// Creates a port and publishes it as the global port.
+///
+/// Also returns whether this call created the port, which is false if another thread published
+/// one first.
+fn install(concurrent_threads: usize) -> IocpResult<(&'static IoCompletionPort, bool)> {
+	let port = Box::into_raw(Box::new(try!(IoCompletionPort::new(concurrent_threads))));
+	
+	match GLOBAL.compare_exchange(ptr::null_mut(), port, Ordering::AcqRel, Ordering::Acquire) {
+		Ok(_) => Ok((unsafe { &*port }, true)),
+		Err(existing) => {
+			unsafe { drop(Box::from_raw(port)) };
+			Ok((unsafe { &*existing }, false))
+		}
+	}
+}
+
+fn already_created() -> IocpError {
+	IocpError::HostError(IOError::new(ErrorKind::AlreadyExists, "the global port has already been created"))
+}
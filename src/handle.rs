@@ -0,0 +1,242 @@
+//! Owned handles associated with a port that release their operations safely when dropped.
+//!
+//! Freeing an OVERLAPPED while its operation is still in flight, or before its packet has been
+//! dequeued, is the usual cause of memory corruption in completion port code. An AssociatedHandle
+//! owns the allocations of its operations and hands them to the port when it is dropped, which
+//! frees each one once its packet comes out of the queue.
+
+use std::{cmp, mem, ptr};
+use std::collections::HashMap;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError, set_notification_modes};
+#[cfg(feature = "pool")]
+use pool::{BufferPool, PooledOverlapped};
+
+use std::io::Error as IOError;
+
+#[repr(C)]
+struct Operation {
+	overlapped: winapi::OVERLAPPED,
+	buffer: Vec<u8>,
+	input: Vec<u8>
+}
+
+unsafe impl Send for Operation { }
+
+impl Operation {
+	fn new(buffer: Vec<u8>, input: Vec<u8>, offset: u64) -> Box<Operation> {
+		let mut operation = Box::new(Operation {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: buffer,
+			input: input
+		});
+		operation.overlapped.Offset = offset as winapi::DWORD;
+		operation.overlapped.OffsetHigh = (offset >> 32) as winapi::DWORD;
+		
+		operation
+	}
+	fn into_completed(self, result: IocpResult<usize>) -> Completed {
+		Completed {
+			buffer: self.buffer,
+			input: self.input,
+			result: result
+		}
+	}
+}
+
+/// An operation of an AssociatedHandle that has completed.
+#[derive(Debug)]
+pub struct Completed {
+	/// The buffer the operation was started with, which is the output buffer of a device control
+	pub buffer: Vec<u8>,
+	/// The input buffer of a device control, empty for other operations
+	pub input: Vec<u8>,
+	/// The number of bytes transferred, or the error the operation failed with
+	pub result: IocpResult<usize>
+}
+
+/// A handle owned by the crate and associated with a port.
+///
+/// Operations are started with a buffer, which is owned by the handle until the operation's packet
+/// is passed to `complete`. Dropping the handle cancels the operations still in flight and closes
+/// the handle. Their allocations are kept alive until the port dequeues their aborted packets,
+/// which it then discards instead of returning.
+pub struct AssociatedHandle {
+	handle: winapi::HANDLE,
+	port: IoCompletionPort,
+	operations: HashMap<usize, Box<Operation>>,
+	skip_on_success: bool,
+	#[cfg(feature = "pool")]
+	pools: Vec<BufferPool>
+}
+
+unsafe impl Send for AssociatedHandle { }
+
+impl AssociatedHandle {
+	/// Takes ownership of the given handle, which must have been opened for overlapped I/O, and
+	/// associates it with the port.
+	///
+	/// The handle is closed if the association fails.
+	pub fn new(port: &IoCompletionPort, handle: winapi::HANDLE, completion_key: usize) -> IocpResult<AssociatedHandle> {
+		let associated = AssociatedHandle {
+			handle: handle,
+			port: port.clone(),
+			operations: HashMap::new(),
+			skip_on_success: false,
+			#[cfg(feature = "pool")]
+			pools: Vec::new()
+		};
+		
+		try!(port.associate(handle, completion_key));
+		
+		Ok(associated)
+	}
+	/// Returns the owned handle.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.handle
+	}
+	/// Returns the number of operations in flight.
+	pub fn in_flight(&self) -> usize {
+		self.operations.len()
+	}
+	/// Sets the notification modes of the handle.
+	///
+	/// Once `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, operations that complete synchronously are
+	/// returned as `Issued::Inline` instead of being kept until their packet arrives.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		try!(unsafe { set_notification_modes(self.handle, modes) });
+		
+		if modes.contains(NotificationModes::SKIP_COMPLETION_PORT_ON_SUCCESS) {
+			self.skip_on_success = true;
+		}
+		
+		Ok(())
+	}
+	/// Starts an operation using the given buffer and file offset.
+	///
+	/// The closure issues the operation on the handle with the buffer and the OVERLAPPED, and
+	/// reports whether it was started. ERROR_IO_PENDING counts as started.
+	pub fn start<F>(&mut self, buffer: Vec<u8>, offset: u64, start: F) -> IocpResult<Issued<Completed>>
+		where F: FnOnce(winapi::HANDLE, &mut Vec<u8>, *mut winapi::OVERLAPPED) -> winapi::BOOL
+	{
+		self.issue(Operation::new(buffer, Vec::new(), offset), |handle, operation, overlapped| start(handle, &mut operation.buffer, overlapped))
+	}
+	fn issue<F>(&mut self, mut operation: Box<Operation>, start: F) -> IocpResult<Issued<Completed>>
+		where F: FnOnce(winapi::HANDLE, &mut Operation, *mut winapi::OVERLAPPED) -> winapi::BOOL
+	{
+		let overlapped = &mut operation.overlapped as *mut winapi::OVERLAPPED;
+		
+		if start(self.handle, &mut operation, overlapped) == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() != Some(winapi::ERROR_IO_PENDING as i32) {
+				return Err(
+					IocpError::HostError(error)
+				);
+			}
+		} else if self.skip_on_success {
+			// A synchronous success has already filled in the OVERLAPPED, and no packet will follow
+			let mut transferred = 0;
+			unsafe { kernel32::GetOverlappedResult(self.handle, overlapped, &mut transferred, winapi::FALSE) };
+			
+			return Ok(Issued::Inline(operation.into_completed(Ok(transferred as usize))));
+		}
+		
+		self.operations.insert(overlapped as usize, operation);
+		
+		Ok(Issued::Pending(overlapped))
+	}
+	/// Starts an overlapped read into the buffer at the given offset.
+	///
+	/// The whole buffer, up to its length, is read into.
+	pub fn read_at(&mut self, buffer: Vec<u8>, offset: u64) -> IocpResult<Issued<Completed>> {
+		self.start(buffer, offset, |handle, buffer, overlapped| unsafe {
+			let len = cmp::min(buffer.len(), winapi::DWORD::max_value() as usize) as winapi::DWORD;
+			kernel32::ReadFile(handle, buffer.as_mut_ptr() as winapi::LPVOID, len, ptr::null_mut(), overlapped)
+		})
+	}
+	/// Starts an overlapped write of the buffer at the given offset.
+	pub fn write_at(&mut self, buffer: Vec<u8>, offset: u64) -> IocpResult<Issued<Completed>> {
+		self.start(buffer, offset, |handle, buffer, overlapped| unsafe {
+			let len = cmp::min(buffer.len(), winapi::DWORD::max_value() as usize) as winapi::DWORD;
+			kernel32::WriteFile(handle, buffer.as_ptr() as winapi::LPCVOID, len, ptr::null_mut(), overlapped)
+		})
+	}
+	/// Starts an overlapped device control with the given control code.
+	///
+	/// The whole input buffer is passed to the driver, and the output buffer, up to its length, is
+	/// returned in `Completed::buffer` along with the number of bytes the driver wrote to it.
+	pub fn device_io_control(&mut self, control_code: u32, input: Vec<u8>, output: Vec<u8>) -> IocpResult<Issued<Completed>> {
+		self.issue(Operation::new(output, input, 0), |handle, operation, overlapped| unsafe {
+			let input_len = cmp::min(operation.input.len(), winapi::DWORD::max_value() as usize) as winapi::DWORD;
+			let output_len = cmp::min(operation.buffer.len(), winapi::DWORD::max_value() as usize) as winapi::DWORD;
+			
+			kernel32::DeviceIoControl(
+				handle,
+				control_code,
+				if input_len == 0 { ptr::null_mut() } else { operation.input.as_mut_ptr() as winapi::LPVOID },
+				input_len,
+				if output_len == 0 { ptr::null_mut() } else { operation.buffer.as_mut_ptr() as winapi::LPVOID },
+				output_len,
+				ptr::null_mut(),
+				overlapped
+			)
+		})
+	}
+	/// Starts an overlapped read into a buffer checked out of the pool, at the given offset.
+	///
+	/// The packet is passed to the pool's `complete` rather than this handle's. Dropping the
+	/// handle cancels the read, and the buffer goes back to the pool once the port has discarded
+	/// the aborted packet.
+	#[cfg(feature = "pool")]
+	pub fn read_pooled(&mut self, pool: &BufferPool, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.remember(pool);
+		pool.read(self.handle, offset)
+	}
+	/// Starts an overlapped write of the data in a buffer checked out of the pool, at the given offset.
+	///
+	/// Completes and is cancelled like `read_pooled`.
+	#[cfg(feature = "pool")]
+	pub fn write_pooled(&mut self, pool: &BufferPool, overlapped: PooledOverlapped, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.remember(pool);
+		pool.write(self.handle, overlapped, offset)
+	}
+	#[cfg(feature = "pool")]
+	fn remember(&mut self, pool: &BufferPool) {
+		if !self.pools.iter().any(|known| known.same_pool(pool)) {
+			self.pools.push(pool.clone());
+		}
+	}
+	/// Takes back the buffer of the operation a dequeued packet completes.
+	///
+	/// Returns `None` if the packet does not belong to one of this handle's operations.
+	pub fn complete(&mut self, packet: &DequeueResult) -> Option<Completed> {
+		let result = match packet.byte_count() {
+			Some(result) => result,
+			None => return None
+		};
+		
+		let operation = match self.operations.remove(&(packet.overlapped() as usize)) {
+			Some(operation) => operation,
+			None => return None
+		};
+		
+		Some(operation.into_completed(result))
+	}
+}
+
+impl Drop for AssociatedHandle {
+	fn drop(&mut self) {
+		for (overlapped, operation) in self.operations.drain() {
+			self.port.inner.retire_and_cancel(self.handle, overlapped as *mut winapi::OVERLAPPED, operation);
+		}
+		#[cfg(feature = "pool")]
+		for pool in self.pools.drain(..) {
+			pool.cancel(self.handle);
+		}
+		
+		unsafe { let _ = kernel32::CloseHandle(self.handle); }
+	}
+}
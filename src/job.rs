@@ -0,0 +1,179 @@
+//! Job object notifications delivered as completion packets.
+//!
+//! A job object associated with a port posts a packet whenever one of its processes starts or
+//! exits, or a limit is hit. The packet's byte count carries the message code and its OVERLAPPED
+//! pointer the process identifier the message is about.
+
+use std::mem;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, CompletionStatus, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+#[repr(C)]
+struct AssociateCompletionPort {
+	completion_key: winapi::PVOID,
+	completion_port: winapi::HANDLE
+}
+
+impl IoCompletionPort {
+	/// Associates the given job object with this IoCompletionPort.
+	///
+	/// The completion key is included in every notification packet the job posts. Decode those
+	/// packets with `JobNotification::from`. A job can only be associated with one port, and the
+	/// system does not guarantee that every notification is delivered.
+	///
+	/// # Safety
+	///
+	/// The handle must be a valid job object handle with the JOB_OBJECT_SET_ATTRIBUTES access
+	/// right. The packets the job posts carry process identifiers in place of OVERLAPPED pointers,
+	/// so code dequeueing from the port must tell them apart by their completion key and never
+	/// dereference them.
+	pub unsafe fn associate_job(&self, job_handle: winapi::HANDLE, completion_key: usize) -> IocpResult<()> {
+		let mut info = AssociateCompletionPort {
+			completion_key: completion_key as winapi::PVOID,
+			completion_port: self.inner.inner
+		};
+		
+		let associated = kernel32::SetInformationJobObject(
+			job_handle,
+			winapi::JobObjectAssociateCompletionPortInformation,
+			&mut info as *mut _ as winapi::LPVOID,
+			mem::size_of::<AssociateCompletionPort>() as winapi::DWORD
+		);
+		
+		if associated == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(())
+	}
+}
+
+/// A notification posted by a job object.
+///
+/// Messages about a single process carry the identifier of that process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobNotification {
+	/// The end-of-job time limit was reached and the job's processes were terminated
+	EndOfJobTime,
+	/// A process reached its end-of-process time limit and was terminated
+	EndOfProcessTime(u32),
+	/// Starting a process would have exceeded the active process limit
+	ActiveProcessLimit,
+	/// The last active process of the job has exited
+	ActiveProcessZero,
+	/// A process was added to the job
+	NewProcess(u32),
+	/// A process exited
+	ExitProcess(u32),
+	/// A process exited abnormally, for example because of an unhandled exception
+	AbnormalExitProcess(u32),
+	/// A process tried to commit more memory than the per-process limit allows
+	ProcessMemoryLimit(u32),
+	/// A process tried to commit memory beyond the job-wide limit
+	JobMemoryLimit(u32),
+	/// A notification limit of the job was exceeded
+	NotificationLimit,
+	/// The job's CPU cycle time limit was exceeded
+	JobCycleTimeLimit,
+	/// A message code this version of the library does not know about
+	Unknown {
+		/// The message code
+		message: u32,
+		/// The value carried by the OVERLAPPED pointer
+		value: usize
+	}
+}
+
+impl JobNotification {
+	/// Returns the identifier of the process the notification is about, if any.
+	pub fn process_id(&self) -> Option<u32> {
+		match *self {
+			JobNotification::EndOfProcessTime(pid) |
+			JobNotification::NewProcess(pid) |
+			JobNotification::ExitProcess(pid) |
+			JobNotification::AbnormalExitProcess(pid) |
+			JobNotification::ProcessMemoryLimit(pid) |
+			JobNotification::JobMemoryLimit(pid) => Some(pid),
+			_ => None
+		}
+	}
+}
+
+impl<'a> From<&'a CompletionStatus> for JobNotification {
+	fn from(status: &'a CompletionStatus) -> JobNotification {
+		let message = status.byte_count as winapi::DWORD;
+		let value = status.overlapped as usize;
+		let pid = value as u32;
+		
+		match message {
+			winapi::JOB_OBJECT_MSG_END_OF_JOB_TIME => JobNotification::EndOfJobTime,
+			winapi::JOB_OBJECT_MSG_END_OF_PROCESS_TIME => JobNotification::EndOfProcessTime(pid),
+			winapi::JOB_OBJECT_MSG_ACTIVE_PROCESS_LIMIT => JobNotification::ActiveProcessLimit,
+			winapi::JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO => JobNotification::ActiveProcessZero,
+			winapi::JOB_OBJECT_MSG_NEW_PROCESS => JobNotification::NewProcess(pid),
+			winapi::JOB_OBJECT_MSG_EXIT_PROCESS => JobNotification::ExitProcess(pid),
+			winapi::JOB_OBJECT_MSG_ABNORMAL_EXIT_PROCESS => JobNotification::AbnormalExitProcess(pid),
+			winapi::JOB_OBJECT_MSG_PROCESS_MEMORY_LIMIT => JobNotification::ProcessMemoryLimit(pid),
+			winapi::JOB_OBJECT_MSG_JOB_MEMORY_LIMIT => JobNotification::JobMemoryLimit(pid),
+			winapi::JOB_OBJECT_MSG_NOTIFICATION_LIMIT => JobNotification::NotificationLimit,
+			winapi::JOB_OBJECT_MSG_JOB_CYCLE_TIME_LIMIT => JobNotification::JobCycleTimeLimit,
+			_ => JobNotification::Unknown {
+				message: message,
+				value: value
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use winapi;
+	
+	use CompletionStatus;
+	use super::JobNotification;
+	
+	fn packet(message: winapi::DWORD, value: usize) -> CompletionStatus {
+		CompletionStatus {
+			byte_count: message as usize,
+			completion_key: 1,
+			overlapped: value as *mut winapi::OVERLAPPED
+		}
+	}
+
+	#[test]
+	fn process_messages_carry_the_pid() {
+		let exit = JobNotification::from(&packet(winapi::JOB_OBJECT_MSG_EXIT_PROCESS, 4242));
+		assert_eq!(exit, JobNotification::ExitProcess(4242));
+		assert_eq!(exit.process_id(), Some(4242));
+		
+		let abnormal = JobNotification::from(&packet(winapi::JOB_OBJECT_MSG_ABNORMAL_EXIT_PROCESS, 7));
+		assert_eq!(abnormal, JobNotification::AbnormalExitProcess(7));
+		
+		let new = JobNotification::from(&packet(winapi::JOB_OBJECT_MSG_NEW_PROCESS, 8));
+		assert_eq!(new, JobNotification::NewProcess(8));
+	}
+
+	#[test]
+	fn job_messages_have_no_pid() {
+		let zero = JobNotification::from(&packet(winapi::JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO, 0));
+		assert_eq!(zero, JobNotification::ActiveProcessZero);
+		assert_eq!(zero.process_id(), None);
+	}
+
+	#[test]
+	fn unknown_messages_keep_their_value() {
+		let unknown = JobNotification::from(&packet(99, 0x1234));
+		assert_eq!(unknown, JobNotification::Unknown {
+			message: 99,
+			value: 0x1234
+		});
+		assert_eq!(unknown.process_id(), None);
+	}
+}
@@ -2,20 +2,91 @@
 //!
 //! This crate is only available on Windows. See the example in ```examples/main.rs```.
 //!
-#![cfg(windows)]
-#![allow(unstable)]
-#![feature(heap_api)]
+//! With the ```stub``` feature the crate also builds on other platforms, where creating a port
+//! fails with an error of kind `Unsupported` and the feature modules are left out.
+//!
+#![cfg(any(windows, feature = "stub"))]
 
+#[cfg(windows)]
 extern crate kernel32;
+#[cfg(windows)]
 extern crate winapi;
+#[cfg(all(windows, feature = "net"))]
+extern crate ws2_32;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(windows, feature = "futures-io"))]
+extern crate futures_io;
+
+mod overlapped;
+#[cfg(not(windows))]
+mod stub;
+#[cfg(not(windows))]
+use stub as winapi;
+
+#[cfg(all(windows, feature = "adaptive"))]
+pub mod adaptive;
+#[cfg(all(windows, feature = "backpressure"))]
+pub mod backpressure;
+#[cfg(all(windows, feature = "batch"))]
+pub mod batch;
+#[cfg(all(windows, feature = "blocking"))]
+pub mod blocking;
+#[cfg(all(windows, feature = "device"))]
+pub mod device;
+#[cfg(all(windows, feature = "dispatch"))]
+pub mod dispatch;
+#[cfg(all(windows, feature = "fault"))]
+pub mod fault;
+#[cfg(all(windows, feature = "filter"))]
+pub mod filter;
+#[cfg(all(windows, feature = "fs"))]
+pub mod fs;
+#[cfg(all(windows, feature = "global"))]
+mod global;
+#[cfg(all(windows, feature = "handle"))]
+pub mod handle;
+#[cfg(all(windows, feature = "job"))]
+pub mod job;
+#[cfg(all(windows, feature = "net"))]
+pub mod net;
+#[cfg(all(windows, feature = "ping"))]
+pub mod ping;
+#[cfg(all(windows, feature = "pipe"))]
+pub mod pipe;
+#[cfg(all(windows, feature = "pool"))]
+pub mod pool;
+#[cfg(all(windows, feature = "process"))]
+pub mod process;
+#[cfg(all(windows, feature = "registry"))]
+pub mod registry;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(all(windows, feature = "shard"))]
+pub mod shard;
+#[cfg(all(windows, feature = "futures-io"))]
+pub mod stream;
+#[cfg(all(windows, feature = "wait"))]
+pub mod wait;
+#[cfg(all(windows, feature = "waker"))]
+pub mod waker;
+#[cfg(all(windows, feature = "watch"))]
+pub mod watch;
 
-use std::{os, ptr, mem};
+#[cfg_attr(not(windows), allow(unused_imports))]
+use std::{cmp, io, os, ptr, mem};
 use std::result::Result;
 use std::error::Error;
-use std::sync::Arc;
-use std::rt::heap;
-use std::slice;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::fmt;
+use std::ops::{BitOr, BitOrAssign};
+use std::time::Duration;
+#[cfg(windows)]
+use std::time::Instant;
 
 use std::io::Error as IOError;
 
@@ -24,6 +95,13 @@ use std::io::Error as IOError;
 pub use winapi::HANDLE;
 pub use winapi::OVERLAPPED;
 
+pub use overlapped::Overlapped;
+#[cfg(not(windows))]
+pub use stub::set_notification_modes;
+
+#[cfg(all(windows, feature = "global"))]
+pub use global::{CONCURRENCY_VAR, global, init_global};
+
 /// Represents an I/O completion port.
 pub struct IoCompletionPort {
 	inner: Arc<IocpImp>
@@ -46,9 +124,32 @@ impl IoCompletionPort {
 	/// If zero threads are specified, the system allows as many concurrently running
 	/// threads as there are processors in the system.
 	pub fn new(concurrent_threads: usize) -> IocpResult<IoCompletionPort> {
-		Ok(IoCompletionPort {
-			inner: Arc::new(try!(IocpImp::new(concurrent_threads)))
-		})
+		IocpBuilder::new().concurrent_threads(concurrent_threads).build()
+	}
+	/// Marks the IoCompletionPort as closed.
+	///
+	/// Packets posted afterwards, by any clone of the port, are handled according to the
+	/// ClosedPostPolicy the port was built with. The port's handle is only released once the last
+	/// clone has been dropped.
+	pub fn close(&self) {
+		self.inner.closed.store(true, Ordering::SeqCst);
+	}
+	/// Returns true once `close` has been called on any clone of the port.
+	pub fn is_closed(&self) -> bool {
+		self.inner.closed.load(Ordering::SeqCst)
+	}
+	/// Shuts the IoCompletionPort down, waking every thread waiting on it.
+	///
+	/// The port is closed as by `close`. Every dequeue started afterwards, by any clone of the port,
+	/// fails with `IocpError::PortClosed`, and so do the waits this wakes. A waiting thread that
+	/// is handed a packet queued before the wake-up returns it and fails on its next dequeue.
+	pub fn shutdown(&self) -> IocpResult<()> {
+		self.close();
+		self.inner.shutdown()
+	}
+	/// Returns true once `shutdown` has been called on any clone of the port.
+	pub fn is_shut_down(&self) -> bool {
+		self.inner.shut_down.load(Ordering::SeqCst)
 	}
 	/// Assoicates the given file handle with this IoCompletionPort.
 	///
@@ -57,14 +158,47 @@ impl IoCompletionPort {
 		self.inner.associate(handle, completion_key)
 	}
 	/// Attempts to dequeue an I/O completion packet from the IoCompletionPort.
-	pub fn get_queued(&self, timeout: u32) -> IocpResult<CompletionStatus> {
+	///
+	/// A timeout of `None` waits indefinitely. Timeouts are rounded up to whole milliseconds. An
+	/// error is only returned if the wait itself failed; the packet of a failed operation is
+	/// returned as `DequeueResult::FailedOperation`.
+	pub fn get_queued(&self, timeout: Option<Duration>) -> IocpResult<DequeueResult> {
 		self.inner.get_queued(timeout)
 	}
 	/// Attempts to dequeue multiple I/O completion packets from the IoCompletionPort simultaneously.
 	///
-	/// Returns the number of CompletionStatus objects dequeued.
-	pub fn get_many_queued(&self, buf: &mut [CompletionStatus], timeout: u32) -> IocpResult<usize> {
-		self.inner.get_many_queued(buf, timeout)
+	/// Returns the number of CompletionStatus objects dequeued, which is zero if the wait timed
+	/// out. Packets of failed operations are returned like any other; their status can be
+	/// retrieved from their OVERLAPPED.
+	///
+	/// At most `MAX_BATCH` packets are removed per call, using a buffer on the stack. If
+	/// `alertable` is true, the wait also returns, with zero packets, once the system has run an
+	/// APC queued to the calling thread.
+	pub fn get_many_queued(&self, buf: &mut [CompletionStatus], timeout: Option<Duration>, alertable: bool) -> IocpResult<usize> {
+		self.inner.get_many_queued(buf, timeout, alertable)
+	}
+	/// Waits for the packet of the operation using the given OVERLAPPED.
+	///
+	/// Packets of other operations dequeued in the meantime are kept and returned by later calls
+	/// to `get_queued` and `get_many_queued`, in the order they arrived. Once the wait is over,
+	/// threads already blocked on the port are woken up to take them. This only works if no other
+	/// thread dequeues the awaited packet first.
+	pub fn wait_for(&self, overlapped: *mut winapi::OVERLAPPED, timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		self.inner.wait_for(overlapped, timeout)
+	}
+	/// Cancels every pending operation on the given handle, whichever thread started it.
+	///
+	/// The packets of the cancelled operations are still queued, and are dequeued as
+	/// `DequeueResult::Cancelled`. Returns false if the handle had no pending operations.
+	pub fn cancel(&self, handle: winapi::HANDLE) -> IocpResult<bool> {
+		self.inner.cancel(handle, ptr::null_mut())
+	}
+	/// Cancels the pending operation on the given handle that uses the Overlapped.
+	///
+	/// Its packet is still queued, and is dequeued as `DequeueResult::Cancelled` unless the
+	/// operation completed before it could be cancelled. Returns false if no such operation is pending.
+	pub fn cancel_operation<T>(&self, handle: winapi::HANDLE, overlapped: &Overlapped<T>) -> IocpResult<bool> {
+		self.inner.cancel(handle, overlapped.as_ptr())
 	}
 	/// Posts an I/O completion packet to the IoCompletionPort.
 	///
@@ -73,6 +207,185 @@ impl IoCompletionPort {
 	pub fn post_queued(&self, packet: CompletionStatus) -> IocpResult<()> {
 		self.inner.post_queued(packet)
 	}
+	/// Returns the handle of the IoCompletionPort.
+	///
+	/// The handle stays owned by the port and is closed once the last clone has been dropped.
+	pub fn as_raw_handle(&self) -> winapi::HANDLE {
+		self.inner.inner
+	}
+	/// Returns a duplicate of the port's handle, which the caller must close.
+	///
+	/// The duplicate refers to the same port, so packets can be posted and dequeued through either
+	/// one. The system does not allow a port to be used by another process, so the duplicate is
+	/// only valid within the current one.
+	pub fn try_clone_handle(&self) -> IocpResult<winapi::HANDLE> {
+		self.inner.try_clone_handle()
+	}
+	/// Gives up ownership of the port's handle, which the caller must close.
+	///
+	/// If other clones of the port are still alive, they keep the handle and a duplicate is
+	/// returned instead. The allocations of retired operations are leaked, since their packets can
+	/// still be dequeued through the handle.
+	pub fn into_raw_handle(self) -> IocpResult<winapi::HANDLE> {
+		match Arc::try_unwrap(self.inner) {
+			Ok(mut inner) => Ok(inner.release_handle()),
+			Err(inner) => inner.try_clone_handle()
+		}
+	}
+	/// Takes ownership of the handle of an existing I/O completion port.
+	///
+	/// Packets posted after the port is closed fail, as with a port created by `IocpBuilder::new`.
+	/// The handle is closed once the last clone has been dropped.
+	///
+	/// # Safety
+	///
+	/// The handle must be a valid I/O completion port handle that is not owned by anything else.
+	pub unsafe fn from_raw_handle(handle: winapi::HANDLE) -> IoCompletionPort {
+		IoCompletionPort {
+			inner: Arc::new(IocpImp::with_handle(handle, ClosedPostPolicy::Error))
+		}
+	}
+}
+
+/// What happens to a packet posted to a port that has been closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClosedPostPolicy {
+	/// The packet is discarded and the post reports success
+	Drop,
+	/// The post fails with `IocpError::PortClosed`
+	Error,
+	/// The post panics
+	Panic
+}
+
+/// Configures and creates an IoCompletionPort.
+#[derive(Clone, Debug)]
+pub struct IocpBuilder {
+	concurrent_threads: usize,
+	closed_post_policy: ClosedPostPolicy
+}
+
+impl IocpBuilder {
+	/// Creates a builder for a port allowing as many concurrently running threads as there are
+	/// processors, whose posts fail once it is closed.
+	pub fn new() -> IocpBuilder {
+		IocpBuilder {
+			concurrent_threads: 0,
+			closed_post_policy: ClosedPostPolicy::Error
+		}
+	}
+	/// Sets the number of concurrently running threads the port allows, zero meaning one per processor.
+	pub fn concurrent_threads(mut self, concurrent_threads: usize) -> IocpBuilder {
+		self.concurrent_threads = concurrent_threads;
+		self
+	}
+	/// Sets what happens to packets posted after the port has been closed.
+	pub fn closed_post_policy(mut self, policy: ClosedPostPolicy) -> IocpBuilder {
+		self.closed_post_policy = policy;
+		self
+	}
+	/// Creates the port.
+	pub fn build(&self) -> IocpResult<IoCompletionPort> {
+		Ok(IoCompletionPort {
+			inner: Arc::new(try!(IocpImp::new(self.concurrent_threads, self.closed_post_policy)))
+		})
+	}
+}
+
+/// A set of flags changing how the completions of a handle's operations are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NotificationModes {
+	bits: u8
+}
+
+impl NotificationModes {
+	/// No packet is queued for an operation that completes synchronously with success
+	pub const SKIP_COMPLETION_PORT_ON_SUCCESS: NotificationModes = NotificationModes { bits: 0x1 };
+	/// The handle is not signaled when an operation on it completes
+	pub const SKIP_SET_EVENT_ON_HANDLE: NotificationModes = NotificationModes { bits: 0x2 };
+	
+	/// Returns the empty set, which is how handles behave by default.
+	pub fn empty() -> NotificationModes {
+		NotificationModes {
+			bits: 0
+		}
+	}
+	/// Returns the flags as passed to SetFileCompletionNotificationModes.
+	pub fn bits(&self) -> u8 {
+		self.bits
+	}
+	/// Returns true if every flag in `other` is set.
+	pub fn contains(&self, other: NotificationModes) -> bool {
+		self.bits & other.bits == other.bits
+	}
+	/// Returns true if no flag is set.
+	pub fn is_empty(&self) -> bool {
+		self.bits == 0
+	}
+}
+
+impl BitOr for NotificationModes {
+	type Output = NotificationModes;
+	
+	fn bitor(self, other: NotificationModes) -> NotificationModes {
+		NotificationModes {
+			bits: self.bits | other.bits
+		}
+	}
+}
+
+impl BitOrAssign for NotificationModes {
+	fn bitor_assign(&mut self, other: NotificationModes) {
+		self.bits |= other.bits;
+	}
+}
+
+/// Sets the notification modes of a handle opened for overlapped I/O.
+///
+/// Modes cannot be cleared once set. With `SKIP_COMPLETION_PORT_ON_SUCCESS`, an operation that
+/// completes synchronously does not queue a packet, so its result has to be taken straight away;
+/// the crate's wrappers report this as `Issued::Inline` once they have been told about the modes.
+///
+/// # Safety
+///
+/// The handle must be valid, and every piece of code starting operations on it must know about
+/// the modes, since an operation that completes synchronously would otherwise wait for a packet
+/// that never arrives and keep its buffer and OVERLAPPED locked up.
+#[cfg(windows)]
+pub unsafe fn set_notification_modes(handle: winapi::HANDLE, modes: NotificationModes) -> IocpResult<()> {
+	if kernel32::SetFileCompletionNotificationModes(handle, modes.bits) == 0 {
+		return Err(
+			IocpError::HostError(IOError::last_os_error())
+		);
+	}
+	
+	Ok(())
+}
+
+/// How an operation started by one of the crate's wrappers proceeded.
+pub enum Issued<T> {
+	/// The operation is in flight, and its packet will carry the OVERLAPPED pointer
+	Pending(*mut winapi::OVERLAPPED),
+	/// The operation completed synchronously on a handle that skips the port on success, so no
+	/// packet will be queued for it
+	Inline(T)
+}
+
+impl<T> Issued<T> {
+	/// Returns true if a packet will be queued for the operation.
+	pub fn is_pending(&self) -> bool {
+		match *self {
+			Issued::Pending(_) => true,
+			Issued::Inline(_) => false
+		}
+	}
+	/// Converts the result of an inline operation, keeping a pending one as it is.
+	pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Issued<U> {
+		match self {
+			Issued::Pending(overlapped) => Issued::Pending(overlapped),
+			Issued::Inline(value) => Issued::Inline(f(value))
+		}
+	}
 }
 
 /// Represents an I/O completion status packet
@@ -99,12 +412,301 @@ impl CompletionStatus {
 //impl Clone for CompletionStatus { }
 //impl Copy for CompletionStatus { }
 
+/// The outcome of waiting for an I/O completion packet.
+pub enum DequeueResult {
+	/// A packet was dequeued for an operation that succeeded, or was posted with `post_queued`
+	Completed(CompletionStatus),
+	/// No packet arrived before the timeout elapsed
+	TimedOut,
+	/// A packet was dequeued for an operation that was cancelled, which is not a failure
+	Cancelled(CompletionStatus),
+	/// A packet was dequeued for an operation that failed
+	FailedOperation {
+		/// The packet of the operation
+		status: CompletionStatus,
+		/// The error the operation failed with
+		error: IOError
+	}
+}
+
+impl DequeueResult {
+	/// Returns the dequeued packet, whether its operation succeeded or not.
+	///
+	/// Returns `None` if the wait timed out.
+	pub fn status(&self) -> Option<&CompletionStatus> {
+		match *self {
+			DequeueResult::Completed(ref status) => Some(status),
+			DequeueResult::Cancelled(ref status) => Some(status),
+			DequeueResult::FailedOperation { ref status, .. } => Some(status),
+			DequeueResult::TimedOut => None
+		}
+	}
+	/// Returns the OVERLAPPED pointer of the dequeued packet, or a null pointer if the wait timed out.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.status().map(|status| status.overlapped).unwrap_or(ptr::null_mut())
+	}
+	/// Returns the number of bytes transferred by the operation, or the error it failed with.
+	///
+	/// A cancelled operation reports ERROR_OPERATION_ABORTED. Returns `None` if the wait timed out.
+	pub fn byte_count(&self) -> Option<IocpResult<usize>> {
+		match *self {
+			DequeueResult::Completed(ref status) => Some(Ok(status.byte_count)),
+			DequeueResult::Cancelled(_) => Some(Err(IocpError::HostError(IOError::from_raw_os_error(ERROR_OPERATION_ABORTED)))),
+			DequeueResult::FailedOperation { ref error, .. } => Some(Err(IocpError::HostError(clone_error(error)))),
+			DequeueResult::TimedOut => None
+		}
+	}
+}
+
+/// The most packets `get_many_queued` removes in one call.
+pub const MAX_BATCH: usize = 64;
+
+/// The error of an operation that was cancelled.
+const ERROR_OPERATION_ABORTED: i32 = 995;
+
+/// Copies an error, which IOError does not do itself.
+fn clone_error(error: &IOError) -> IOError {
+	match error.raw_os_error() {
+		Some(code) => IOError::from_raw_os_error(code),
+		None => IOError::new(error.kind(), format!("{}", error))
+	}
+}
+
+#[cfg(windows)]
+#[link(name = "ntdll")]
+extern "system" {
+	fn RtlNtStatusToDosError(status: winapi::NTSTATUS) -> winapi::ULONG;
+}
+
+/// Converts a timeout to the milliseconds the system expects, rounding up so that a short timeout
+/// does not turn into a poll.
+#[cfg(windows)]
+fn timeout_millis(timeout: Option<Duration>) -> winapi::DWORD {
+	match timeout {
+		Some(timeout) => {
+			let millis = timeout.as_secs().saturating_mul(1000).saturating_add((timeout.subsec_nanos() as u64 + 999_999) / 1_000_000);
+			cmp::min(millis, winapi::INFINITE as u64 - 1) as winapi::DWORD
+		},
+		None => winapi::INFINITE
+	}
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
 struct IocpImp {
-	inner: winapi::HANDLE
+	inner: winapi::HANDLE,
+	closed: AtomicBool,
+	closed_post_policy: ClosedPostPolicy,
+	shut_down: AtomicBool,
+	waiters: AtomicUsize,
+	retired: Mutex<HashMap<usize, Box<dyn Send>>>,
+	retired_count: AtomicUsize,
+	stash: Mutex<VecDeque<Packet>>,
+	stashed: AtomicUsize
+}
+
+/// Counts a thread as waiting on the port for as long as it is alive.
+struct Waiting<'a> {
+	waiters: &'a AtomicUsize
+}
+
+impl<'a> Drop for Waiting<'a> {
+	fn drop(&mut self) {
+		self.waiters.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+static WAKE: u8 = 0;
+
+/// The OVERLAPPED pointer carried by the packets `shutdown` wakes waiting threads with.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn wake_marker() -> *mut winapi::OVERLAPPED {
+	&WAKE as *const u8 as *mut winapi::OVERLAPPED
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+static STASHED: u8 = 0;
+
+/// The OVERLAPPED pointer carried by the packets that tell a waiting thread to take a kept packet.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn stash_marker() -> *mut winapi::OVERLAPPED {
+	&STASHED as *const u8 as *mut winapi::OVERLAPPED
+}
+
+/// A dequeued packet along with the error of the operation it completes, if that failed.
+struct Packet {
+	status: CompletionStatus,
+	error: Option<IOError>
+}
+
+impl Packet {
+	#[cfg_attr(not(windows), allow(dead_code))]
+	fn into_result(self) -> DequeueResult {
+		match self.error {
+			Some(ref error) if error.raw_os_error() == Some(ERROR_OPERATION_ABORTED) => DequeueResult::Cancelled(self.status),
+			Some(error) => DequeueResult::FailedOperation {
+				status: self.status,
+				error: error
+			},
+			None => DequeueResult::Completed(self.status)
+		}
+	}
+	/// Takes the packet back out of a result, for putting it back on the port.
+	///
+	/// Returns `None` if the wait timed out.
+	#[cfg_attr(not(all(windows, feature = "batch")), allow(dead_code))]
+	fn from_result(result: DequeueResult) -> Option<Packet> {
+		match result {
+			DequeueResult::Completed(status) => Some(Packet {
+				status: status,
+				error: None
+			}),
+			DequeueResult::Cancelled(status) => Some(Packet {
+				status: status,
+				error: Some(IOError::from_raw_os_error(ERROR_OPERATION_ABORTED))
+			}),
+			DequeueResult::FailedOperation { status, error } => Some(Packet {
+				status: status,
+				error: Some(error)
+			}),
+			DequeueResult::TimedOut => None
+		}
+	}
+	/// Decodes an entry removed by GetQueuedCompletionStatusEx.
+	///
+	/// The entry's Internal field holds the NTSTATUS the operation completed with, which
+	/// GetQueuedCompletionStatus would have turned into an error of its own.
+	#[cfg(windows)]
+	fn from_entry(entry: &winapi::OVERLAPPED_ENTRY) -> Packet {
+		let status = entry.Internal as winapi::NTSTATUS;
+		
+		Packet {
+			status: CompletionStatus {
+				byte_count: entry.dwNumberOfBytesTransferred as usize,
+				completion_key: entry.lpCompletionKey as usize,
+				overlapped: entry.lpOverlapped
+			},
+			error: if status < 0 {
+				Some(IOError::from_raw_os_error(unsafe { RtlNtStatusToDosError(status) } as i32))
+			} else {
+				None
+			}
+		}
+	}
 }
 
 impl IocpImp {
-	pub fn new(concurrent_threads: usize) -> IocpResult<IocpImp> {
+	fn with_handle(handle: winapi::HANDLE, closed_post_policy: ClosedPostPolicy) -> IocpImp {
+		IocpImp {
+			inner: handle,
+			closed: AtomicBool::new(false),
+			closed_post_policy: closed_post_policy,
+			shut_down: AtomicBool::new(false),
+			waiters: AtomicUsize::new(0),
+			retired: Mutex::new(HashMap::new()),
+			retired_count: AtomicUsize::new(0),
+			stash: Mutex::new(VecDeque::new()),
+			stashed: AtomicUsize::new(0)
+		}
+	}
+	/// Gives up ownership of the handle so it is not closed when the port is dropped.
+	fn release_handle(&mut self) -> winapi::HANDLE {
+		mem::forget(mem::replace(self.retired.get_mut().unwrap(), HashMap::new()));
+		mem::replace(&mut self.inner, ptr::null_mut())
+	}
+	/// Fails with `PortClosed` once the port has been shut down.
+	fn check_open(&self) -> IocpResult<()> {
+		if self.shut_down.load(Ordering::SeqCst) {
+			return Err(IocpError::PortClosed);
+		}
+		
+		Ok(())
+	}
+	/// Counts the calling thread as waiting on the port until the returned guard is dropped.
+	///
+	/// `shutdown` posts one wake-up packet for every thread counted. The count is taken before
+	/// the port is checked, so a thread either sees the shutdown here or gets a packet.
+	#[cfg_attr(not(windows), allow(dead_code))]
+	fn enter<'a>(&'a self) -> IocpResult<Waiting<'a>> {
+		self.waiters.fetch_add(1, Ordering::SeqCst);
+		
+		let waiting = Waiting {
+			waiters: &self.waiters
+		};
+		
+		try!(self.check_open());
+		
+		Ok(waiting)
+	}
+	/// Keeps the allocation of a cancelled operation alive until its packet has been dequeued.
+	///
+	/// The packet is then swallowed by the port instead of being returned to the caller.
+	#[cfg_attr(any(not(windows), not(feature = "handle")), allow(dead_code))]
+	fn retire(&self, overlapped: *mut winapi::OVERLAPPED, allocation: Box<dyn Send>) {
+		self.retired.lock().unwrap().insert(overlapped as usize, allocation);
+		self.retired_count.fetch_add(1, Ordering::SeqCst);
+	}
+	/// Keeps a packet dequeued by `wait_for` to be returned by the next dequeue.
+	#[cfg_attr(not(windows), allow(dead_code))]
+	fn stash(&self, packet: Packet) {
+		self.stash.lock().unwrap().push_back(packet);
+		self.stashed.fetch_add(1, Ordering::SeqCst);
+	}
+	/// Puts packets that were removed but not handed out back in front of the kept ones, in order.
+	#[cfg_attr(not(all(windows, feature = "batch")), allow(dead_code))]
+	fn restash(&self, packets: Vec<Packet>) {
+		let count = packets.len();
+		let mut stash = self.stash.lock().unwrap();
+		
+		for packet in packets.into_iter().rev() {
+			stash.push_front(packet);
+		}
+		self.stashed.fetch_add(count, Ordering::SeqCst);
+	}
+	/// Takes the oldest packet kept by `wait_for`, or the first one matching the OVERLAPPED pointer if given.
+	#[cfg_attr(not(windows), allow(dead_code))]
+	fn unstash(&self, overlapped: Option<*mut winapi::OVERLAPPED>) -> Option<Packet> {
+		if self.stashed.load(Ordering::SeqCst) == 0 {
+			return None;
+		}
+		
+		let mut stash = self.stash.lock().unwrap();
+		
+		let index = match overlapped {
+			Some(overlapped) => stash.iter().position(|packet| packet.status.overlapped == overlapped),
+			None => if stash.is_empty() { None } else { Some(0) }
+		};
+		
+		index.and_then(|index| stash.remove(index)).map(|packet| {
+			self.stashed.fetch_sub(1, Ordering::SeqCst);
+			packet
+		})
+	}
+	/// Frees the allocation of a retired operation if the packet belongs to one.
+	///
+	/// Returns true if it did, meaning the packet must not be handed out.
+	#[cfg_attr(not(windows), allow(dead_code))]
+	fn reap(&self, overlapped: *mut winapi::OVERLAPPED) -> bool {
+		if overlapped.is_null() || self.retired_count.load(Ordering::SeqCst) == 0 {
+			return false;
+		}
+		
+		// Freed once the lock is released, since freeing it can retire other allocations
+		let allocation = self.retired.lock().unwrap().remove(&(overlapped as usize));
+		
+		match allocation {
+			Some(_) => {
+				self.retired_count.fetch_sub(1, Ordering::SeqCst);
+				true
+			},
+			None => false
+		}
+	}
+}
+
+#[cfg(windows)]
+impl IocpImp {
+	pub fn new(concurrent_threads: usize, closed_post_policy: ClosedPostPolicy) -> IocpResult<IocpImp> {
 		let handle = unsafe { kernel32::CreateIoCompletionPort(winapi::INVALID_HANDLE_VALUE, ptr::null_mut(), 0, concurrent_threads as winapi::DWORD) };
 		
 		if handle.is_null() {
@@ -113,9 +715,22 @@ impl IocpImp {
 			);
 		}
 		
-		Ok(IocpImp {
-			inner: handle
-		})
+		Ok(IocpImp::with_handle(handle, closed_post_policy))
+	}
+	pub fn try_clone_handle(&self) -> IocpResult<winapi::HANDLE> {
+		let mut handle = ptr::null_mut();
+		let duplicated = unsafe {
+			let process = kernel32::GetCurrentProcess();
+			kernel32::DuplicateHandle(process, self.inner, process, &mut handle, 0, winapi::FALSE, winapi::DUPLICATE_SAME_ACCESS)
+		};
+		
+		if duplicated == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(handle)
 	}
 	pub fn associate(&self, handle: winapi::HANDLE, completion_key: usize) -> IocpResult<()> {
 		let handle = unsafe { kernel32::CreateIoCompletionPort(handle, self.inner, completion_key as winapi::ULONG_PTR, 0) };
@@ -128,52 +743,246 @@ impl IocpImp {
 		
 		Ok(())
 	}
-	pub fn get_queued(&self, timeout: u32) -> IocpResult<CompletionStatus> {
-		let mut length: winapi::DWORD = 0;
-		let mut key: winapi::ULONG_PTR = 0;
-		let mut overlapped = ptr::null_mut();
-
-		let queued = unsafe { kernel32::GetQueuedCompletionStatus(self.inner, &mut length, &mut key, &mut overlapped, timeout) };
+	pub fn get_queued(&self, timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		try!(self.check_open());
 		
-		if queued == 0 {
-			return Err(
-				IocpError::GetQueuedError(IOError::last_os_error(), overlapped)
-			);
+		if let Some(packet) = self.unstash(None) {
+			return Ok(packet.into_result());
 		}
 		
-		Ok(CompletionStatus {
-			byte_count: length as usize,
-			completion_key: key as usize,
-			overlapped: overlapped
-		})
+		match try!(self.dequeue(timeout_millis(timeout))) {
+			Some(packet) => Ok(packet.into_result()),
+			None => Ok(DequeueResult::TimedOut)
+		}
 	}
-	pub fn get_many_queued(&self, buf: &mut [CompletionStatus], timeout: u32) -> IocpResult<usize> {
-		let allocation = unsafe { heap::allocate(buf.len() * mem::size_of::<winapi::OVERLAPPED_ENTRY>(), mem::align_of::<winapi::OVERLAPPED_ENTRY>()) };
-		
-		let ptr: *mut winapi::OVERLAPPED_ENTRY = unsafe { mem::transmute(allocation) };
-		let mut removed = 0;
+	/// Dequeues a packet, failing only if the wait itself did.
+	///
+	/// Returns `None` if the wait timed out.
+	fn dequeue(&self, timeout: winapi::DWORD) -> IocpResult<Option<Packet>> {
+		let _waiting = try!(self.enter());
 		
-		let queued = unsafe { kernel32::GetQueuedCompletionStatusEx(self.inner, ptr, buf.len() as winapi::DWORD, &mut removed, timeout, 0) };
+		loop {
+			let mut length: winapi::DWORD = 0;
+			let mut key: winapi::ULONG_PTR = 0;
+			let mut overlapped = ptr::null_mut();
+			
+			let queued = unsafe { kernel32::GetQueuedCompletionStatus(self.inner, &mut length, &mut key, &mut overlapped, timeout) };
+			
+			if self.reap(overlapped) {
+				continue;
+			}
+			
+			if overlapped == wake_marker() {
+				return Err(IocpError::PortClosed);
+			}
+			
+			// Another thread may have taken the kept packet first
+			if overlapped == stash_marker() {
+				match self.unstash(None) {
+					Some(packet) => return Ok(Some(packet)),
+					None => continue
+				}
+			}
+			
+			let error = if queued == 0 { Some(IOError::last_os_error()) } else { None };
+			
+			if overlapped.is_null() {
+				if let Some(error) = error {
+					if error.raw_os_error() == Some(winapi::WAIT_TIMEOUT as i32) {
+						return Ok(None);
+					}
+					return Err(
+						IocpError::GetQueuedError(error, overlapped)
+					);
+				}
+			}
+			
+			return Ok(Some(Packet {
+				status: CompletionStatus {
+					byte_count: length as usize,
+					completion_key: key as usize,
+					overlapped: overlapped
+				},
+				error: error
+			}));
+		}
+	}
+	pub fn wait_for(&self, overlapped: *mut winapi::OVERLAPPED, timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		try!(self.check_open());
 		
-		if queued == 0 {
-			return Err(
-				IocpError::HostError(IOError::last_os_error())
-			);
+		if let Some(packet) = self.unstash(Some(overlapped)) {
+			return Ok(packet.into_result());
 		}
 		
-		let entries = unsafe { slice::from_raw_parts(ptr, buf.len()) };
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		let mut stashed = 0;
 		
-		for (status, entry) in buf.iter_mut().zip(entries.iter()).take(removed as usize) {
-			*status = CompletionStatus {
-				byte_count: entry.dwNumberOfBytesTransferred as usize,
-				completion_key: entry.lpCompletionKey as usize,
-				overlapped: entry.lpOverlapped
+		let result = loop {
+			let remaining = deadline.map(|deadline| {
+				let now = Instant::now();
+				if now >= deadline { Duration::from_millis(0) } else { deadline - now }
+			});
+			
+			let packet = match self.dequeue(timeout_millis(remaining)) {
+				Ok(Some(packet)) => packet,
+				Ok(None) => break Ok(DequeueResult::TimedOut),
+				Err(error) => break Err(error)
 			};
+			
+			if packet.status.overlapped == overlapped {
+				break Ok(packet.into_result());
+			}
+			
+			self.stash(packet);
+			stashed += 1;
+		};
+		
+		// Only now, since this thread would otherwise be the one dequeueing the notifications
+		self.notify_stashed(stashed);
+		
+		result
+	}
+	pub fn get_many_queued(&self, buf: &mut [CompletionStatus], timeout: Option<Duration>, alertable: bool) -> IocpResult<usize> {
+		let mut kept = 0;
+		
+		try!(self.dequeue_many(buf.len(), timeout, alertable, |packet| {
+			buf[kept] = packet.status;
+			kept += 1;
+		}));
+		
+		Ok(kept)
+	}
+	/// Dequeues up to `len` packets, at most `MAX_BATCH` of them from the port, and passes each to `keep`.
+	///
+	/// Packets kept by `wait_for` go first. Returns without passing any packet if the wait timed
+	/// out or was interrupted by an APC.
+	fn dequeue_many<F>(&self, len: usize, timeout: Option<Duration>, alertable: bool, mut keep: F) -> IocpResult<()>
+		where F: FnMut(Packet)
+	{
+		try!(self.check_open());
+		
+		let mut unstashed = 0;
+		
+		while unstashed < len {
+			match self.unstash(None) {
+				Some(packet) => keep(packet),
+				None => break
+			}
+			unstashed += 1;
+		}
+		
+		if unstashed > 0 || len == 0 {
+			return Ok(());
 		}
 		
-		Ok(removed as usize)
+		let mut entries: [winapi::OVERLAPPED_ENTRY; MAX_BATCH] = unsafe { mem::zeroed() };
+		let len = cmp::min(len, MAX_BATCH);
+		
+		let _waiting = try!(self.enter());
+		
+		loop {
+			let mut removed = 0;
+			
+			let queued = unsafe {
+				kernel32::GetQueuedCompletionStatusEx(
+					self.inner,
+					entries.as_mut_ptr(),
+					len as winapi::ULONG,
+					&mut removed,
+					timeout_millis(timeout),
+					if alertable { winapi::TRUE } else { winapi::FALSE }
+				)
+			};
+			
+			if queued == 0 {
+				let error = IOError::last_os_error();
+				match error.raw_os_error() {
+					Some(code) if code == winapi::WAIT_TIMEOUT as i32 || code == winapi::WAIT_IO_COMPLETION as i32 => return Ok(()),
+					_ => ()
+				}
+				return Err(
+					IocpError::HostError(error)
+				);
+			}
+			
+			let mut kept = 0;
+			let mut wakes = 0;
+			
+			for entry in entries[..removed as usize].iter() {
+				if self.reap(entry.lpOverlapped) {
+					continue;
+				}
+				
+				if entry.lpOverlapped == wake_marker() {
+					wakes += 1;
+					continue;
+				}
+				
+				if entry.lpOverlapped == stash_marker() {
+					if let Some(packet) = self.unstash(None) {
+						keep(packet);
+						kept += 1;
+					}
+					continue;
+				}
+				
+				keep(Packet::from_entry(entry));
+				kept += 1;
+			}
+			
+			if kept > 0 {
+				self.repost_wakes(wakes);
+				return Ok(());
+			}
+			
+			if wakes > 0 {
+				self.repost_wakes(wakes - 1);
+				return Err(IocpError::PortClosed);
+			}
+		}
+	}
+	/// Posts a packet for each packet that was just kept, waking up a thread blocked on the port to take it.
+	///
+	/// A kept packet is otherwise only returned once a thread starts a new dequeue, however long
+	/// the threads already waiting have been blocked.
+	pub fn notify_stashed(&self, count: usize) {
+		for _ in 0..count {
+			let _ = self.post_internal(CompletionStatus {
+				byte_count: 0,
+				completion_key: 0,
+				overlapped: stash_marker()
+			});
+		}
+	}
+	/// Posts back wake-up packets that a batch removed but the calling thread did not honor.
+	///
+	/// `shutdown` posts one per waiting thread, and a single batch can remove several of them,
+	/// which would leave the threads they were meant for blocked.
+	pub fn repost_wakes(&self, count: usize) {
+		for _ in 0..count {
+			let _ = self.post_internal(CompletionStatus {
+				byte_count: 0,
+				completion_key: 0,
+				overlapped: wake_marker()
+			});
+		}
 	}
 	pub fn post_queued(&self, packet: CompletionStatus) -> IocpResult<()> {
+		if self.closed.load(Ordering::SeqCst) {
+			return match self.closed_post_policy {
+				ClosedPostPolicy::Drop => Ok(()),
+				ClosedPostPolicy::Error => Err(IocpError::PortClosed),
+				ClosedPostPolicy::Panic => panic!("packet posted to a closed port")
+			};
+		}
+		
+		self.post_internal(packet)
+	}
+	/// Posts a packet regardless of the ClosedPostPolicy.
+	///
+	/// Used for the packets the crate posts itself, which must neither be dropped nor panic, since
+	/// some are posted from thread pool callbacks that cannot unwind.
+	pub fn post_internal(&self, packet: CompletionStatus) -> IocpResult<()> {
 		let posted = unsafe {
 			kernel32::PostQueuedCompletionStatus(
 				self.inner,
@@ -193,9 +1002,58 @@ impl IocpImp {
 	}
 }
 
+#[cfg(windows)]
+impl IocpImp {
+	pub fn cancel(&self, handle: winapi::HANDLE, overlapped: *mut winapi::OVERLAPPED) -> IocpResult<bool> {
+		if unsafe { kernel32::CancelIoEx(handle, overlapped) } == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() == Some(winapi::ERROR_NOT_FOUND as i32) {
+				return Ok(false);
+			}
+			return Err(
+				IocpError::HostError(error)
+			);
+		}
+		
+		Ok(true)
+	}
+	/// Cancels the pending operation that uses the OVERLAPPED, keeping its allocation alive until
+	/// the aborted packet has been dequeued.
+	///
+	/// The allocation is retired before the operation is cancelled: the aborted packet can be
+	/// dequeued by another thread as soon as the cancel is issued, and a port that did not know
+	/// about the allocation yet would hand it out instead of swallowing it, leaving the allocation
+	/// retired for good.
+	#[cfg_attr(not(any(feature = "backpressure", feature = "filter", feature = "handle", feature = "net", feature = "pipe", feature = "pool", feature = "process", feature = "watch")), allow(dead_code))]
+	pub fn retire_and_cancel(&self, handle: winapi::HANDLE, overlapped: *mut winapi::OVERLAPPED, allocation: Box<dyn Send>) {
+		self.retire(overlapped, allocation);
+		
+		let _ = self.cancel(handle, overlapped);
+	}
+	pub fn shutdown(&self) -> IocpResult<()> {
+		self.shut_down.store(true, Ordering::SeqCst);
+		
+		// A thread that starts waiting from now on sees the flag, so only those already counted need waking
+		for _ in 0..self.waiters.load(Ordering::SeqCst) {
+			let posted = unsafe { kernel32::PostQueuedCompletionStatus(self.inner, 0, 0, wake_marker()) };
+			
+			if posted == 0 {
+				return Err(
+					IocpError::HostError(IOError::last_os_error())
+				);
+			}
+		}
+		
+		Ok(())
+	}
+}
+
+#[cfg(windows)]
 impl Drop for IocpImp {
 	fn drop(&mut self) {
-		unsafe { let _ = kernel32::CloseHandle(self.inner); }
+		if !self.inner.is_null() {
+			unsafe { let _ = kernel32::CloseHandle(self.inner); }
+		}
 	}
 }
 
@@ -205,7 +1063,8 @@ pub type IocpResult<T> = Result<T, IocpError>;
 #[derive(Debug)]
 pub enum IocpError {
 	GetQueuedError(IOError, *mut winapi::OVERLAPPED),
-	HostError(IOError)
+	HostError(IOError),
+	PortClosed
 }
 
 impl fmt::Display for IocpError {
@@ -213,17 +1072,100 @@ impl fmt::Display for IocpError {
 		match *self {
 			IocpError::GetQueuedError(ref string, _) => write!(f, "{}", string),
 			IocpError::HostError(ref string) => write!(f, "{}", string),
+			IocpError::PortClosed => write!(f, "the port has been shut down"),
 		}
 	}
 }
 
 unsafe impl Send for IocpError { }
 
+impl IocpError {
+	/// Creates a HostError from the calling thread's last error.
+	pub fn last_os_error() -> IocpError {
+		IocpError::HostError(IOError::last_os_error())
+	}
+	/// Creates a HostError from a GetLastError code.
+	pub fn from_raw_os_error(code: i32) -> IocpError {
+		IocpError::HostError(IOError::from_raw_os_error(code))
+	}
+	/// Returns the GetLastError code of the error, if it came from the system.
+	///
+	/// Associating a handle that is not valid, for example, fails with ERROR_INVALID_HANDLE.
+	pub fn raw_os_error(&self) -> Option<i32> {
+		match *self {
+			IocpError::GetQueuedError(ref error, _) => error.raw_os_error(),
+			IocpError::HostError(ref error) => error.raw_os_error(),
+			IocpError::PortClosed => None
+		}
+	}
+	/// Returns the kind of `io::Error` the error corresponds to.
+	pub fn kind(&self) -> io::ErrorKind {
+		match *self {
+			IocpError::GetQueuedError(ref error, _) => error.kind(),
+			IocpError::HostError(ref error) => error.kind(),
+			IocpError::PortClosed => io::ErrorKind::NotConnected
+		}
+	}
+}
+
+impl From<IOError> for IocpError {
+	fn from(error: IOError) -> IocpError {
+		IocpError::HostError(error)
+	}
+}
+
+impl From<IocpError> for IOError {
+	fn from(error: IocpError) -> IOError {
+		match error {
+			IocpError::GetQueuedError(error, _) => error,
+			IocpError::HostError(error) => error,
+			IocpError::PortClosed => IOError::new(io::ErrorKind::NotConnected, "the port has been shut down")
+		}
+	}
+}
+
 impl Error for IocpError {
     fn description(&self) -> &str {
 		match *self {
 			IocpError::GetQueuedError(_, _) => "Call to GetQueuedCompletionStatus failed",
-			IocpError::HostError(_) => "Call to function failed"
+			IocpError::HostError(_) => "Call to function failed",
+			IocpError::PortClosed => "The port has been shut down"
+		}
+	}
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+	use std::thread;
+	use std::time::Duration;
+	use std::sync::atomic::Ordering;
+	
+	use {IoCompletionPort, CompletionStatus, IocpError};
+	
+	#[test]
+	fn shutdown_wakes_every_batched_waiter() {
+		let port = IoCompletionPort::new(0).unwrap();
+		let threads = 4;
+		
+		let waiters: Vec<_> = (0..threads).map(|_| {
+			let port = port.clone();
+			thread::spawn(move || {
+				let mut buf: Vec<CompletionStatus> = (0..8).map(|_| CompletionStatus::new()).collect();
+				port.get_many_queued(&mut buf, None, false)
+			})
+		}).collect();
+		
+		while port.inner.waiters.load(Ordering::SeqCst) < threads {
+			thread::sleep(Duration::from_millis(1));
+		}
+		
+		port.shutdown().unwrap();
+		
+		for waiter in waiters {
+			match waiter.join().unwrap() {
+				Err(IocpError::PortClosed) => (),
+				result => panic!("unexpected result {:?}", result)
+			}
 		}
 	}
 }
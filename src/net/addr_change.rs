@@ -0,0 +1,107 @@
+//! Notifications of changes to the local address list.
+
+use std::{mem, ptr};
+
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, Overlapped, IocpResult};
+use super::{Socket, pending_or_error};
+
+/// Delivers a completion packet whenever the list of local addresses changes.
+///
+/// Each notification is a packet with the completion key given to `new` and an OVERLAPPED
+/// pointer equal to `overlapped()`. Pass every dequeued packet to `changed`, which re-arms the
+/// notification so that the next change is reported as well.
+///
+/// Dropping the watcher cancels the notification. Its allocation is handed to the port, which
+/// discards the aborted packet.
+pub struct AddressListWatcher {
+	socket: Socket,
+	port: IoCompletionPort,
+	overlapped: Overlapped<()>,
+	pending: bool
+}
+
+unsafe impl Send for AddressListWatcher { }
+
+impl AddressListWatcher {
+	/// Watches the IPv4 address list.
+	pub fn new(port: &IoCompletionPort, completion_key: usize) -> IocpResult<AddressListWatcher> {
+		AddressListWatcher::with_family(port, winapi::AF_INET, completion_key)
+	}
+	/// Watches the IPv6 address list.
+	pub fn new_v6(port: &IoCompletionPort, completion_key: usize) -> IocpResult<AddressListWatcher> {
+		AddressListWatcher::with_family(port, winapi::AF_INET6, completion_key)
+	}
+	fn with_family(port: &IoCompletionPort, family: winapi::c_int, completion_key: usize) -> IocpResult<AddressListWatcher> {
+		let socket = try!(Socket::new(family, winapi::SOCK_DGRAM, 0));
+		try!(port.associate(socket.as_handle(), completion_key));
+		
+		let mut watcher = AddressListWatcher {
+			socket: socket,
+			port: port.clone(),
+			overlapped: Overlapped::new(()),
+			pending: false
+		};
+		
+		try!(watcher.rearm());
+		
+		Ok(watcher)
+	}
+	/// Returns the OVERLAPPED pointer carried by notification packets.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.overlapped.as_ptr()
+	}
+	/// Checks whether the given packet is a notification from this watcher, and re-arms the
+	/// notification if it is.
+	///
+	/// Returns `None` if the packet does not belong to the watcher. If the notification failed or
+	/// cannot be re-armed, the error is returned and the watcher stops reporting changes.
+	pub fn changed(&mut self, packet: &DequeueResult) -> Option<IocpResult<()>> {
+		if !self.pending || packet.overlapped() != self.overlapped() {
+			return None;
+		}
+		
+		self.pending = false;
+		
+		match packet.byte_count() {
+			Some(Ok(_)) => Some(self.rearm()),
+			Some(Err(error)) => Some(Err(error)),
+			None => None
+		}
+	}
+	fn rearm(&mut self) -> IocpResult<()> {
+		self.overlapped.reset();
+		
+		let mut returned = 0;
+		let issued = unsafe {
+			ws2_32::WSAIoctl(
+				self.socket.raw,
+				winapi::SIO_ADDRESS_LIST_CHANGE,
+				ptr::null_mut(),
+				0,
+				ptr::null_mut(),
+				0,
+				&mut returned,
+				self.overlapped(),
+				None
+			)
+		};
+		
+		try!(pending_or_error(issued));
+		
+		self.pending = true;
+		
+		Ok(())
+	}
+}
+
+impl Drop for AddressListWatcher {
+	fn drop(&mut self) {
+		if self.pending {
+			let retired = mem::replace(&mut self.overlapped, Overlapped::new(()));
+			self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.as_ptr(), Box::new(retired));
+		}
+	}
+}
@@ -0,0 +1,141 @@
+//! Raw IPv4 sockets that receive every packet seen by an interface.
+
+use std::{cmp, mem, ptr};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, Overlapped, IocpResult, IocpError};
+use super::{Socket, last_error, pending_or_error};
+
+use std::io::Error as IOError;
+
+const SIO_RCVALL: winapi::DWORD = winapi::IOC_IN | winapi::IOC_VENDOR | 1;
+const RCVALL_ON: winapi::DWORD = 1;
+
+/// A raw IPv4 socket with SIO_RCVALL enabled, receiving every IP packet on one interface.
+///
+/// Call `recv` to post a receive; the completion arrives as a packet with the completion key
+/// given to `new` and an OVERLAPPED pointer equal to `overlapped()`. Pass that packet to
+/// `packet` to get the captured IP datagram, then call `recv` again.
+///
+/// Dropping the socket cancels a pending receive. Its OVERLAPPED and buffer are handed to the
+/// port, which discards the aborted packet.
+pub struct CaptureSocket {
+	socket: Socket,
+	port: IoCompletionPort,
+	overlapped: Overlapped<()>,
+	buffer: Vec<u8>,
+	pending: bool
+}
+
+unsafe impl Send for CaptureSocket { }
+
+impl CaptureSocket {
+	/// Creates a capture socket on the interface with the given local address and associates it
+	/// with the port.
+	///
+	/// Raw sockets require administrator privileges. Without them this fails with an error of
+	/// kind `PermissionDenied`.
+	pub fn new(port: &IoCompletionPort, interface: Ipv4Addr, completion_key: usize) -> IocpResult<CaptureSocket> {
+		let socket = match Socket::new(winapi::AF_INET, winapi::SOCK_RAW, 0) {
+			Ok(socket) => socket,
+			Err(IocpError::HostError(ref error)) if error.raw_os_error() == Some(winapi::WSAEACCES as i32) => {
+				return Err(
+					IocpError::HostError(IOError::new(ErrorKind::PermissionDenied, "capturing packets requires administrator privileges"))
+				);
+			},
+			Err(error) => return Err(error)
+		};
+		
+		try!(socket.bind(&SocketAddr::V4(SocketAddrV4::new(interface, 0))));
+		
+		let mut option = RCVALL_ON;
+		let mut returned = 0;
+		let enabled = unsafe {
+			ws2_32::WSAIoctl(
+				socket.raw,
+				SIO_RCVALL,
+				&mut option as *mut _ as winapi::LPVOID,
+				mem::size_of::<winapi::DWORD>() as winapi::DWORD,
+				ptr::null_mut(),
+				0,
+				&mut returned,
+				ptr::null_mut(),
+				None
+			)
+		};
+		
+		if enabled == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		try!(port.associate(socket.as_handle(), completion_key));
+		
+		Ok(CaptureSocket {
+			socket: socket,
+			port: port.clone(),
+			overlapped: Overlapped::new(()),
+			buffer: vec![0; 65535],
+			pending: false
+		})
+	}
+	/// Sets the size of the receive buffer, which limits the size of captured packets.
+	///
+	/// Has no effect while a receive is pending.
+	pub fn set_buffer_size(&mut self, size: usize) {
+		if !self.pending {
+			self.buffer.resize(cmp::max(size, 1), 0);
+		}
+	}
+	/// Returns the OVERLAPPED pointer carried by receive completions.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.overlapped.as_ptr()
+	}
+	/// Posts a receive for the next captured packet.
+	///
+	/// Does nothing if a receive is already pending.
+	pub fn recv(&mut self) -> IocpResult<()> {
+		if self.pending {
+			return Ok(());
+		}
+		
+		self.overlapped.reset();
+		
+		let mut buf = winapi::WSABUF {
+			len: self.buffer.len() as winapi::ULONG,
+			buf: self.buffer.as_mut_ptr() as *mut winapi::CHAR
+		};
+		let mut flags = 0;
+		let received = unsafe { ws2_32::WSARecv(self.socket.raw, &mut buf, 1, ptr::null_mut(), &mut flags, self.overlapped(), None) };
+		
+		try!(pending_or_error(received));
+		self.pending = true;
+		
+		Ok(())
+	}
+	/// Returns the captured IP datagram carried by the given packet if it completes the pending receive.
+	///
+	/// Returns `None` if the packet does not belong to the pending receive.
+	pub fn packet<'a>(&'a mut self, packet: &DequeueResult) -> Option<IocpResult<&'a [u8]>> {
+		if !self.pending || packet.overlapped() != self.overlapped() {
+			return None;
+		}
+		
+		self.pending = false;
+		
+		let buffer = &self.buffer;
+		packet.byte_count().map(|result| result.map(|count| &buffer[..cmp::min(count, buffer.len())]))
+	}
+}
+
+impl Drop for CaptureSocket {
+	fn drop(&mut self) {
+		if self.pending {
+			let retired = (mem::replace(&mut self.overlapped, Overlapped::new(())), mem::replace(&mut self.buffer, Vec::new()));
+			self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.0.as_ptr(), Box::new(retired));
+		}
+	}
+}
@@ -0,0 +1,143 @@
+//! Listening sockets that decide whether to accept a connection before the handshake completes.
+//!
+//! With SO_CONDITIONAL_ACCEPT set, the stack does not complete the TCP handshake on its own.
+//! Each pending connection is offered to a filter, and rejected peers are refused without ever
+//! being accepted.
+//!
+//! Only WSAAccept takes a condition function, so unlike AsyncTcpListener, which uses AcceptEx,
+//! these listeners accept synchronously and do not complete through a port.
+
+use std::{mem, panic};
+use std::net::SocketAddr;
+
+use winapi;
+
+use {IocpResult, IocpError};
+use super::{Socket, from_raw, last_error};
+
+const CF_ACCEPT: winapi::c_int = 0;
+const CF_REJECT: winapi::c_int = 1;
+
+type ConditionProc = unsafe extern "system" fn(
+	winapi::LPWSABUF,
+	winapi::LPWSABUF,
+	winapi::LPQOS,
+	winapi::LPQOS,
+	winapi::LPWSABUF,
+	winapi::LPWSABUF,
+	*mut winapi::GROUP,
+	winapi::DWORD_PTR
+) -> winapi::c_int;
+
+#[link(name = "ws2_32")]
+extern "system" {
+	// Declared here because the callback data is pointer sized, unlike in ws2_32-sys
+	fn WSAAccept(
+		s: winapi::SOCKET,
+		addr: *mut winapi::SOCKADDR,
+		addrlen: *mut winapi::c_int,
+		condition: Option<ConditionProc>,
+		callback_data: winapi::DWORD_PTR
+	) -> winapi::SOCKET;
+}
+
+/// A listening TCP socket with SO_CONDITIONAL_ACCEPT that filters connections by peer address.
+///
+/// Accepted sockets are overlapped and can be associated with a port. Accepting is synchronous:
+/// call `accept` from a dedicated thread, or once the listener is known to be readable.
+pub struct ConditionalListener<F> {
+	socket: Socket,
+	filter: F
+}
+
+unsafe impl<F: Send> Send for ConditionalListener<F> { }
+unsafe impl<F: Sync> Sync for ConditionalListener<F> { }
+
+impl<F> ConditionalListener<F> where F: Fn(&SocketAddr) -> bool {
+	/// Creates a listener on the given address.
+	///
+	/// The filter is called with the address of every connecting peer and returns true to accept
+	/// it. A filter that panics rejects the peer, since the panic cannot unwind through Winsock.
+	pub fn bind(addr: &SocketAddr, filter: F) -> IocpResult<ConditionalListener<F>> {
+		let family = match *addr {
+			SocketAddr::V4(_) => winapi::AF_INET,
+			SocketAddr::V6(_) => winapi::AF_INET6
+		};
+		let socket = try!(Socket::new(family, winapi::SOCK_STREAM, 0));
+		
+		try!(socket.set_option(winapi::SOL_SOCKET, winapi::SO_CONDITIONAL_ACCEPT, 1));
+		try!(socket.bind(addr));
+		try!(socket.listen());
+		
+		Ok(ConditionalListener {
+			socket: socket,
+			filter: filter
+		})
+	}
+	/// Returns the listening socket.
+	pub fn raw_socket(&self) -> winapi::SOCKET {
+		self.socket.raw
+	}
+	/// Returns the local address of the listener.
+	pub fn local_addr(&self) -> IocpResult<SocketAddr> {
+		self.socket.local_addr()
+	}
+	/// Waits for the next connection and offers it to the filter.
+	///
+	/// Returns the accepted socket and the peer's address, or `None` if the filter rejected the
+	/// peer. The caller owns the accepted socket and closes it with `closesocket`.
+	pub fn accept(&self) -> IocpResult<Option<(winapi::SOCKET, SocketAddr)>> {
+		let mut storage: winapi::SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+		let mut len = mem::size_of::<winapi::SOCKADDR_STORAGE>() as winapi::c_int;
+		
+		let accepted = unsafe {
+			WSAAccept(
+				self.socket.raw,
+				&mut storage as *mut _ as *mut winapi::SOCKADDR,
+				&mut len,
+				Some(condition::<F>),
+				&self.filter as *const F as winapi::DWORD_PTR
+			)
+		};
+		
+		if accepted == winapi::INVALID_SOCKET {
+			let error = last_error();
+			return match error {
+				IocpError::HostError(ref host) if host.raw_os_error() == Some(winapi::WSAECONNREFUSED as i32) => Ok(None),
+				_ => Err(error)
+			};
+		}
+		
+		match from_raw(&storage as *const _ as *const winapi::SOCKADDR, len) {
+			Some(addr) => Ok(Some((accepted, addr))),
+			None => {
+				drop(Socket { raw: accepted });
+				Ok(None)
+			}
+		}
+	}
+}
+
+unsafe extern "system" fn condition<F>(
+	caller_id: winapi::LPWSABUF,
+	_caller_data: winapi::LPWSABUF,
+	_sqos: winapi::LPQOS,
+	_gqos: winapi::LPQOS,
+	_callee_id: winapi::LPWSABUF,
+	_callee_data: winapi::LPWSABUF,
+	_group: *mut winapi::GROUP,
+	callback_data: winapi::DWORD_PTR
+) -> winapi::c_int where F: Fn(&SocketAddr) -> bool {
+	let filter = &*(callback_data as *const F);
+	
+	if caller_id.is_null() {
+		return CF_REJECT;
+	}
+	
+	let accepted = match from_raw((*caller_id).buf as *const winapi::SOCKADDR, (*caller_id).len as winapi::c_int) {
+		Some(ref addr) => panic::catch_unwind(panic::AssertUnwindSafe(|| filter(addr))).unwrap_or(false),
+		None => false
+	};
+	
+	if accepted { CF_ACCEPT } else { CF_REJECT }
+}
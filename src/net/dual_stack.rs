@@ -0,0 +1,168 @@
+//! Listening on IPv4 and IPv6 at the same time.
+
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+use winapi;
+
+use {IoCompletionPort, IocpResult, IocpError};
+use super::Socket;
+
+const IPPROTO_IPV6: winapi::c_int = 41;
+
+/// Listening sockets accepting both IPv4 and IPv6 connections on the same port.
+///
+/// This is a single IPv6 socket with IPV6_V6ONLY disabled where the system supports it, and a
+/// separate IPv4 and IPv6 socket otherwise. Addresses of IPv4 peers accepted on a dual-stack
+/// socket are IPv4-mapped IPv6 addresses; pass them through `normalize_addr`, or accept with
+/// `AsyncTcpListener::from_dual_stack`, which does so itself.
+pub struct DualStackListener {
+	sockets: Vec<Socket>
+}
+
+unsafe impl Send for DualStackListener { }
+unsafe impl Sync for DualStackListener { }
+
+/// Creates listening sockets for the given port on all IPv4 and IPv6 addresses.
+///
+/// If the port is zero, the system chooses one which is shared by both address families.
+pub fn listen_dual_stack(port: u16) -> IocpResult<DualStackListener> {
+	if let Ok(socket) = dual_stack_socket(port) {
+		return Ok(DualStackListener {
+			sockets: vec![socket]
+		});
+	}
+	
+	let v4 = try!(listening_socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))));
+	let port = try!(v4.local_addr()).port();
+	
+	let mut sockets = vec![v4];
+	
+	// IPv6 may not be installed at all, in which case IPv4 alone still serves every peer
+	match listening_socket(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port, 0, 0))) {
+		Ok(v6) => sockets.push(v6),
+		Err(ref error) if ipv6_unsupported(error) => { },
+		Err(error) => return Err(error)
+	}
+	
+	Ok(DualStackListener {
+		sockets: sockets
+	})
+}
+
+fn dual_stack_socket(port: u16) -> IocpResult<Socket> {
+	let socket = try!(Socket::new(winapi::AF_INET6, winapi::SOCK_STREAM, 0));
+	
+	try!(socket.set_option(IPPROTO_IPV6, winapi::IPV6_V6ONLY, 0));
+	try!(socket.bind(&SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port, 0, 0))));
+	try!(socket.listen());
+	
+	Ok(socket)
+}
+
+/// Returns true if the error means the system has no IPv6 support.
+fn ipv6_unsupported(error: &IocpError) -> bool {
+	match *error {
+		IocpError::HostError(ref error) => match error.raw_os_error() {
+			Some(code) => code == winapi::WSAEAFNOSUPPORT as i32 || code == winapi::WSAEPROTONOSUPPORT as i32,
+			None => false
+		},
+		_ => false
+	}
+}
+
+fn listening_socket(addr: SocketAddr) -> IocpResult<Socket> {
+	let family = match addr {
+		SocketAddr::V4(_) => winapi::AF_INET,
+		SocketAddr::V6(_) => winapi::AF_INET6
+	};
+	let socket = try!(Socket::new(family, winapi::SOCK_STREAM, 0));
+	
+	if family == winapi::AF_INET6 {
+		try!(socket.set_option(IPPROTO_IPV6, winapi::IPV6_V6ONLY, 1));
+	}
+	try!(socket.bind(&addr));
+	try!(socket.listen());
+	
+	Ok(socket)
+}
+
+impl DualStackListener {
+	/// Returns true if a single socket serves both address families.
+	pub fn is_dual_stack(&self) -> bool {
+		self.sockets.len() == 1
+	}
+	/// Returns the listening sockets.
+	pub fn raw_sockets(&self) -> Vec<winapi::SOCKET> {
+		self.sockets.iter().map(|socket| socket.raw).collect()
+	}
+	/// Returns the local addresses of the listening sockets.
+	pub fn local_addrs(&self) -> IocpResult<Vec<SocketAddr>> {
+		let mut addrs = Vec::with_capacity(self.sockets.len());
+		
+		for socket in self.sockets.iter() {
+			addrs.push(try!(socket.local_addr()));
+		}
+		
+		Ok(addrs)
+	}
+	/// Gives up the listening sockets, for `AsyncTcpListener::from_dual_stack`.
+	pub(super) fn into_sockets(self) -> Vec<Socket> {
+		self.sockets
+	}
+	/// Associates every listening socket with the port.
+	pub fn associate(&self, port: &IoCompletionPort, completion_key: usize) -> IocpResult<()> {
+		for socket in self.sockets.iter() {
+			try!(port.associate(socket.as_handle(), completion_key));
+		}
+		
+		Ok(())
+	}
+}
+
+/// Converts an IPv4-mapped IPv6 address into the IPv4 address it represents.
+///
+/// Other addresses are returned unchanged.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+	match addr {
+		SocketAddr::V6(ref v6) => {
+			let segments = v6.ip().segments();
+			if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+				let octets = v6.ip().octets();
+				let ip = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+				return SocketAddr::V4(SocketAddrV4::new(ip, v6.port()));
+			}
+		},
+		SocketAddr::V4(_) => { }
+	}
+	
+	addr
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+	
+	use super::normalize_addr;
+	
+	#[test]
+	fn mapped_addresses_become_ipv4() {
+		let mapped = SocketAddr::V6(SocketAddrV6::new(Ipv4Addr::new(192, 0, 2, 7).to_ipv6_mapped(), 8080, 0, 0));
+		assert_eq!(normalize_addr(mapped), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 7), 8080)));
+	}
+
+	#[test]
+	fn other_addresses_are_unchanged() {
+		let v4 = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 80));
+		assert_eq!(normalize_addr(v4), v4);
+		
+		let v6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 443, 0, 0));
+		assert_eq!(normalize_addr(v6), v6);
+		
+		// IPv4-compatible addresses, with zeroes in place of 0xffff, are not mapped ones
+		let compatible = SocketAddr::V6(SocketAddrV6::new(Ipv4Addr::new(192, 0, 2, 7).to_ipv6_compatible(), 80, 0, 0));
+		assert_eq!(normalize_addr(compatible), compatible);
+		
+		let loopback = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 80, 0, 0));
+		assert_eq!(normalize_addr(loopback), loopback);
+	}
+}
@@ -0,0 +1,239 @@
+//! Overlapped Winsock sockets driven by an I/O completion port.
+//!
+//! Sockets are created with WSA_FLAG_OVERLAPPED and Winsock is initialised the first time one is
+//! created.
+
+use std::{mem, ptr};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi;
+use ws2_32;
+
+use {IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+pub use self::addr_change::AddressListWatcher;
+pub use self::capture::CaptureSocket;
+pub use self::conditional::ConditionalListener;
+pub use self::dual_stack::{DualStackListener, listen_dual_stack, normalize_addr};
+pub use self::opts::SocketOpts;
+pub use self::readiness::ReadinessProbe;
+pub use self::shared::{SharedListener, inherit_listener};
+pub use self::tcp::{AsyncTcpListener, AsyncTcpStream};
+pub use self::tcp_info::{TcpInfo, TcpSendLimits, tcp_info};
+pub use self::udp::{AsyncUdpSocket, Datagrams};
+pub use self::watchdog::DisconnectWatchdog;
+
+mod addr_change;
+mod capture;
+mod conditional;
+mod dual_stack;
+mod opts;
+mod readiness;
+mod shared;
+mod tcp;
+mod tcp_info;
+mod udp;
+mod watchdog;
+
+const WSA_FLAG_OVERLAPPED: winapi::DWORD = 0x01;
+const SOMAXCONN: winapi::c_int = 0x7fffffff;
+
+/// Initialises Winsock 2.2 once for the whole process.
+///
+/// If WSAStartup failed, its error is returned by every call.
+fn init() -> IocpResult<()> {
+	static INIT: Once = Once::new();
+	static STARTUP: AtomicUsize = AtomicUsize::new(0);
+	
+	INIT.call_once(|| {
+		let mut data: winapi::WSADATA = unsafe { mem::zeroed() };
+		let error = unsafe { ws2_32::WSAStartup(0x202, &mut data) };
+		STARTUP.store(error as usize, Ordering::SeqCst);
+	});
+	
+	match STARTUP.load(Ordering::SeqCst) {
+		0 => Ok(()),
+		error => Err(IocpError::HostError(IOError::from_raw_os_error(error as i32)))
+	}
+}
+
+/// Returns the error of the last failed Winsock call on this thread.
+fn last_error() -> IocpError {
+	IocpError::HostError(IOError::from_raw_os_error(unsafe { ws2_32::WSAGetLastError() }))
+}
+
+/// Sets a socket option whose value is the given structure.
+fn set_option<T>(socket: winapi::SOCKET, level: winapi::c_int, name: winapi::c_int, value: &T) -> IocpResult<()> {
+	let set = unsafe {
+		ws2_32::setsockopt(socket, level, name, value as *const T as *const winapi::c_char, mem::size_of::<T>() as winapi::c_int)
+	};
+	
+	if set == winapi::SOCKET_ERROR {
+		return Err(last_error());
+	}
+	
+	Ok(())
+}
+
+/// Treats a pending overlapped operation as success.
+fn pending_or_error(result: winapi::c_int) -> IocpResult<()> {
+	if result != winapi::SOCKET_ERROR {
+		return Ok(());
+	}
+	
+	let code = unsafe { ws2_32::WSAGetLastError() };
+	if code == winapi::WSA_IO_PENDING as i32 {
+		return Ok(());
+	}
+	
+	Err(IocpError::HostError(IOError::from_raw_os_error(code)))
+}
+
+/// Converts a socket address into its Winsock representation.
+fn to_raw(addr: &SocketAddr) -> (winapi::SOCKADDR_STORAGE, winapi::c_int) {
+	let mut storage: winapi::SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+	
+	let len = match *addr {
+		SocketAddr::V4(ref addr) => {
+			let raw = unsafe { &mut *(&mut storage as *mut _ as *mut winapi::SOCKADDR_IN) };
+			raw.sin_family = winapi::AF_INET as winapi::ADDRESS_FAMILY;
+			raw.sin_port = addr.port().to_be();
+			raw.sin_addr.S_un = u32::from_ne_bytes(addr.ip().octets());
+			mem::size_of::<winapi::SOCKADDR_IN>()
+		},
+		SocketAddr::V6(ref addr) => {
+			let raw = unsafe { &mut *(&mut storage as *mut _ as *mut winapi::sockaddr_in6) };
+			raw.sin6_family = winapi::AF_INET6 as winapi::c_short;
+			raw.sin6_port = addr.port().to_be();
+			raw.sin6_flowinfo = addr.flowinfo();
+			raw.sin6_addr.s6_addr = addr.ip().octets();
+			raw.sin6_scope_id = addr.scope_id();
+			mem::size_of::<winapi::sockaddr_in6>()
+		}
+	};
+	
+	(storage, len as winapi::c_int)
+}
+
+/// Converts a Winsock socket address into a SocketAddr.
+///
+/// Returns `None` for address families other than IPv4 and IPv6.
+fn from_raw(raw: *const winapi::SOCKADDR, len: winapi::c_int) -> Option<SocketAddr> {
+	if raw.is_null() {
+		return None;
+	}
+	
+	let len = len as usize;
+	let family = unsafe { (*raw).sa_family } as winapi::c_int;
+	
+	if family == winapi::AF_INET && len >= mem::size_of::<winapi::SOCKADDR_IN>() {
+		let raw = unsafe { &*(raw as *const winapi::SOCKADDR_IN) };
+		let ip = Ipv4Addr::from(raw.sin_addr.S_un.to_ne_bytes());
+		Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(raw.sin_port))))
+	} else if family == winapi::AF_INET6 && len >= mem::size_of::<winapi::sockaddr_in6>() {
+		let raw = unsafe { &*(raw as *const winapi::sockaddr_in6) };
+		let ip = Ipv6Addr::from(raw.sin6_addr.s6_addr);
+		Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(raw.sin6_port), raw.sin6_flowinfo, raw.sin6_scope_id)))
+	} else {
+		None
+	}
+}
+
+/// An owned overlapped socket, closed when dropped.
+struct Socket {
+	raw: winapi::SOCKET
+}
+
+impl Socket {
+	fn new(family: winapi::c_int, socket_type: winapi::c_int, protocol: winapi::c_int) -> IocpResult<Socket> {
+		try!(init());
+		
+		let raw = unsafe { ws2_32::WSASocketW(family, socket_type, protocol, ptr::null_mut(), 0, WSA_FLAG_OVERLAPPED) };
+		
+		if raw == winapi::INVALID_SOCKET {
+			return Err(last_error());
+		}
+		
+		Ok(Socket {
+			raw: raw
+		})
+	}
+	fn bind(&self, addr: &SocketAddr) -> IocpResult<()> {
+		let (raw, len) = to_raw(addr);
+		
+		if unsafe { ws2_32::bind(self.raw, &raw as *const _ as *const winapi::SOCKADDR, len) } == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(())
+	}
+	fn listen(&self) -> IocpResult<()> {
+		if unsafe { ws2_32::listen(self.raw, SOMAXCONN) } == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(())
+	}
+	fn set_option(&self, level: winapi::c_int, name: winapi::c_int, value: winapi::c_int) -> IocpResult<()> {
+		let set = unsafe {
+			ws2_32::setsockopt(self.raw, level, name, &value as *const _ as *const winapi::c_char, mem::size_of::<winapi::c_int>() as winapi::c_int)
+		};
+		
+		if set == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(())
+	}
+	/// Loads a Winsock extension function, such as AcceptEx, for this socket's provider.
+	fn extension_function(&self, guid: winapi::GUID) -> IocpResult<usize> {
+		let mut guid = guid;
+		let mut function = 0usize;
+		let mut returned = 0;
+		
+		let loaded = unsafe {
+			ws2_32::WSAIoctl(
+				self.raw,
+				winapi::SIO_GET_EXTENSION_FUNCTION_POINTER,
+				&mut guid as *mut _ as winapi::LPVOID,
+				mem::size_of::<winapi::GUID>() as winapi::DWORD,
+				&mut function as *mut _ as winapi::LPVOID,
+				mem::size_of::<usize>() as winapi::DWORD,
+				&mut returned,
+				ptr::null_mut(),
+				None
+			)
+		};
+		
+		if loaded == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(function)
+	}
+	fn local_addr(&self) -> IocpResult<SocketAddr> {
+		let mut storage: winapi::SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+		let mut len = mem::size_of::<winapi::SOCKADDR_STORAGE>() as winapi::c_int;
+		
+		if unsafe { ws2_32::getsockname(self.raw, &mut storage as *mut _ as *mut winapi::SOCKADDR, &mut len) } == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		from_raw(&storage as *const _ as *const winapi::SOCKADDR, len).ok_or_else(|| {
+			IocpError::HostError(IOError::from_raw_os_error(winapi::WSAEAFNOSUPPORT as i32))
+		})
+	}
+	fn as_handle(&self) -> winapi::HANDLE {
+		self.raw as winapi::HANDLE
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		unsafe { let _ = ws2_32::closesocket(self.raw); }
+	}
+}
@@ -0,0 +1,160 @@
+//! Socket tuning gathered in one place.
+
+use std::{mem, ptr};
+
+use winapi;
+use ws2_32;
+
+use IocpResult;
+use super::{set_option, last_error};
+
+const IPPROTO_IP: winapi::c_int = 0;
+const IPPROTO_TCP: winapi::c_int = 6;
+const SO_REUSE_UNICASTPORT: winapi::c_int = 0x3007;
+const SIO_KEEPALIVE_VALS: winapi::DWORD = winapi::IOC_IN | winapi::IOC_VENDOR | 4;
+
+#[repr(C)]
+struct KeepaliveVals {
+	onoff: winapi::ULONG,
+	keepalivetime: winapi::ULONG,
+	keepaliveinterval: winapi::ULONG
+}
+
+/// A set of socket options to apply to sockets.
+///
+/// Options that are not set are left at the system default. Each setter consumes and returns the
+/// SocketOpts, so a whole configuration can be written as one chain of calls and shared by every
+/// place that creates sockets.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketOpts {
+	nodelay: Option<bool>,
+	recv_buffer_size: Option<u32>,
+	send_buffer_size: Option<u32>,
+	linger: Option<Option<u16>>,
+	reuse_addr: Option<bool>,
+	reuse_unicast_port: Option<bool>,
+	keepalive: Option<Option<u32>>,
+	tos: Option<u8>
+}
+
+impl SocketOpts {
+	/// Creates a SocketOpts that leaves every option at the system default.
+	pub fn new() -> SocketOpts {
+		SocketOpts::default()
+	}
+	/// Sets TCP_NODELAY, disabling Nagle's algorithm when true.
+	pub fn nodelay(mut self, nodelay: bool) -> SocketOpts {
+		self.nodelay = Some(nodelay);
+		self
+	}
+	/// Sets the size of the receive buffer (SO_RCVBUF) in bytes.
+	pub fn recv_buffer_size(mut self, size: u32) -> SocketOpts {
+		self.recv_buffer_size = Some(size);
+		self
+	}
+	/// Sets the size of the send buffer (SO_SNDBUF) in bytes.
+	pub fn send_buffer_size(mut self, size: u32) -> SocketOpts {
+		self.send_buffer_size = Some(size);
+		self
+	}
+	/// Sets SO_LINGER: `Some(seconds)` lingers on close for up to that long, `None` disables lingering.
+	pub fn linger(mut self, linger: Option<u16>) -> SocketOpts {
+		self.linger = Some(linger);
+		self
+	}
+	/// Sets SO_REUSEADDR.
+	///
+	/// This only has an effect on sockets that have not been bound yet.
+	pub fn reuse_addr(mut self, reuse_addr: bool) -> SocketOpts {
+		self.reuse_addr = Some(reuse_addr);
+		self
+	}
+	/// Sets SO_REUSE_UNICASTPORT, letting the system share local ports between sockets.
+	///
+	/// This only has an effect on sockets that have not been bound yet.
+	pub fn reuse_unicast_port(mut self, reuse_unicast_port: bool) -> SocketOpts {
+		self.reuse_unicast_port = Some(reuse_unicast_port);
+		self
+	}
+	/// Sets TCP keepalive: `Some(milliseconds)` sends probes after that much idle time, `None`
+	/// disables keepalive.
+	pub fn keepalive(mut self, keepalive: Option<u32>) -> SocketOpts {
+		self.keepalive = Some(keepalive);
+		self
+	}
+	/// Sets the IP type of service (IP_TOS).
+	pub fn tos(mut self, tos: u8) -> SocketOpts {
+		self.tos = Some(tos);
+		self
+	}
+	/// Applies every option that has been set to the given socket.
+	pub fn apply(&self, socket: winapi::SOCKET) -> IocpResult<()> {
+		if let Some(reuse_addr) = self.reuse_addr {
+			try!(set_option(socket, winapi::SOL_SOCKET, winapi::SO_REUSEADDR, &(reuse_addr as winapi::c_int)));
+		}
+		if let Some(reuse_unicast_port) = self.reuse_unicast_port {
+			try!(set_option(socket, winapi::SOL_SOCKET, SO_REUSE_UNICASTPORT, &(reuse_unicast_port as winapi::c_int)));
+		}
+		
+		self.apply_accepted(socket)
+	}
+	/// Applies the options that have been set to a socket accepted by AcceptEx.
+	///
+	/// The accepted socket is bound by the system, so the options only taking effect before
+	/// binding are left out.
+	pub(super) fn apply_accepted(&self, socket: winapi::SOCKET) -> IocpResult<()> {
+		if let Some(nodelay) = self.nodelay {
+			try!(set_option(socket, IPPROTO_TCP, winapi::TCP_NODELAY, &(nodelay as winapi::c_int)));
+		}
+		if let Some(size) = self.recv_buffer_size {
+			try!(set_option(socket, winapi::SOL_SOCKET, winapi::SO_RCVBUF, &(size as winapi::c_int)));
+		}
+		if let Some(size) = self.send_buffer_size {
+			try!(set_option(socket, winapi::SOL_SOCKET, winapi::SO_SNDBUF, &(size as winapi::c_int)));
+		}
+		if let Some(linger) = self.linger {
+			let value = winapi::linger {
+				l_onoff: linger.is_some() as winapi::u_short,
+				l_linger: linger.unwrap_or(0)
+			};
+			try!(set_option(socket, winapi::SOL_SOCKET, winapi::SO_LINGER, &value));
+		}
+		if let Some(keepalive) = self.keepalive {
+			try!(set_keepalive(socket, keepalive));
+		}
+		if let Some(tos) = self.tos {
+			try!(set_option(socket, IPPROTO_IP, winapi::IP_TOS, &(tos as winapi::c_int)));
+		}
+		
+		Ok(())
+	}
+}
+
+fn set_keepalive(socket: winapi::SOCKET, keepalive: Option<u32>) -> IocpResult<()> {
+	let mut vals = KeepaliveVals {
+		onoff: keepalive.is_some() as winapi::ULONG,
+		keepalivetime: keepalive.unwrap_or(0),
+		keepaliveinterval: 1000
+	};
+	let mut returned = 0;
+	
+	let set = unsafe {
+		ws2_32::WSAIoctl(
+			socket,
+			SIO_KEEPALIVE_VALS,
+			&mut vals as *mut _ as winapi::LPVOID,
+			mem::size_of::<KeepaliveVals>() as winapi::DWORD,
+			ptr::null_mut(),
+			0,
+			&mut returned,
+			ptr::null_mut(),
+			None
+		)
+	};
+	
+	if set == winapi::SOCKET_ERROR {
+		return Err(last_error());
+	}
+	
+	Ok(())
+}
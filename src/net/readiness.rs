@@ -0,0 +1,113 @@
+//! Zero-byte receives used as cheap "data is available" notifications.
+//!
+//! A pending receive pins its buffer until data arrives. For servers with many mostly-idle
+//! connections, posting a zero-length receive instead, and only reading once it completes,
+//! avoids holding a buffer per idle connection.
+
+use std::{mem, ptr};
+
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, Overlapped, IocpResult};
+use super::{last_error, pending_or_error};
+
+/// Posts zero-length receives on a socket to learn when data can be read.
+///
+/// The socket is borrowed and must stay open while a probe is pending. It must already be
+/// associated with the port given to `new`; the completion arrives with the socket's completion
+/// key and an OVERLAPPED pointer equal to `overlapped()`. Dropping the probe cancels it and hands
+/// its allocation to the port, which discards the aborted packet.
+pub struct ReadinessProbe {
+	socket: winapi::SOCKET,
+	port: IoCompletionPort,
+	overlapped: Overlapped<()>,
+	pending: bool
+}
+
+unsafe impl Send for ReadinessProbe { }
+
+impl ReadinessProbe {
+	/// Creates a probe for the given socket, which is associated with the port.
+	pub fn new(port: &IoCompletionPort, socket: winapi::SOCKET) -> ReadinessProbe {
+		ReadinessProbe {
+			socket: socket,
+			port: port.clone(),
+			overlapped: Overlapped::new(()),
+			pending: false
+		}
+	}
+	/// Creates a probe for the given socket and associates the socket with the port.
+	pub fn associate(port: &IoCompletionPort, socket: winapi::SOCKET, completion_key: usize) -> IocpResult<ReadinessProbe> {
+		try!(port.associate(socket as winapi::HANDLE, completion_key));
+		Ok(ReadinessProbe::new(port, socket))
+	}
+	/// Returns the OVERLAPPED pointer carried by the probe's completion.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.overlapped.as_ptr()
+	}
+	/// Returns true while a probe is pending.
+	pub fn is_pending(&self) -> bool {
+		self.pending
+	}
+	/// Posts a zero-length receive which completes once data is available or the peer closes.
+	///
+	/// Does nothing if a probe is already pending.
+	pub fn post(&mut self) -> IocpResult<()> {
+		if self.pending {
+			return Ok(());
+		}
+		
+		self.overlapped.reset();
+		
+		let mut buf = winapi::WSABUF {
+			len: 0,
+			buf: ptr::null_mut()
+		};
+		let mut flags = 0;
+		let posted = unsafe { ws2_32::WSARecv(self.socket, &mut buf, 1, ptr::null_mut(), &mut flags, self.overlapped(), None) };
+		
+		try!(pending_or_error(posted));
+		self.pending = true;
+		
+		Ok(())
+	}
+	/// Completes the pending probe if the given packet belongs to it.
+	///
+	/// Once the probe has succeeded the socket can be read. Returns `None` if the packet does not
+	/// belong to the pending probe. A probe fails with the receive's error, for example when the
+	/// connection was reset.
+	pub fn ready(&mut self, packet: &DequeueResult) -> Option<IocpResult<()>> {
+		if !self.pending || packet.overlapped() != self.overlapped() {
+			return None;
+		}
+		
+		self.pending = false;
+		
+		packet.byte_count().map(|result| result.map(|_| ()))
+	}
+	/// Reads the available data into the buffer without waiting for more.
+	///
+	/// Intended to be called once the probe has completed. Returns zero if the peer has closed the
+	/// connection.
+	pub fn recv_now(&self, buf: &mut [u8]) -> IocpResult<usize> {
+		let len = buf.len() as winapi::c_int;
+		let received = unsafe { ws2_32::recv(self.socket, buf.as_mut_ptr() as *mut winapi::c_char, len, 0) };
+		
+		if received == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(received as usize)
+	}
+}
+
+impl Drop for ReadinessProbe {
+	fn drop(&mut self) {
+		// The socket is borrowed, so only the probe is cancelled
+		if self.pending {
+			let retired = mem::replace(&mut self.overlapped, Overlapped::new(()));
+			self.port.inner.retire_and_cancel(self.socket as winapi::HANDLE, retired.as_ptr(), Box::new(retired));
+		}
+	}
+}
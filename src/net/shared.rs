@@ -0,0 +1,184 @@
+//! Sharing one listening socket between several processes.
+//!
+//! A completion port cannot be shared between processes, so scaling over processes means every
+//! process runs its own port. The parent creates the listener and hands a duplicate of it to each
+//! child with WSADuplicateSocket; every child then accepts on it through its own port, and the
+//! system spreads incoming connections over the processes waiting on it.
+
+use std::{mem, slice};
+use std::ffi::OsString;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use winapi;
+use ws2_32;
+
+use {IocpResult, IocpError};
+use super::{Socket, SocketOpts, WSA_FLAG_OVERLAPPED, init, last_error};
+
+use std::io::Error as IOError;
+
+const FROM_PROTOCOL_INFO: winapi::c_int = -1;
+
+/// A listening socket owned by a parent process and shared with the child processes it spawns.
+///
+/// Children receive the socket as the first line of their standard input and recover it with
+/// `inherit_listener`. `supervise` restarts children that have exited.
+pub struct SharedListener {
+	socket: Socket,
+	program: PathBuf,
+	args: Vec<OsString>,
+	children: Vec<Child>
+}
+
+unsafe impl Send for SharedListener { }
+
+impl SharedListener {
+	/// Creates a listener on the given address.
+	pub fn bind(addr: &SocketAddr) -> IocpResult<SharedListener> {
+		SharedListener::bind_with(addr, &SocketOpts::new())
+	}
+	/// Creates a listener on the given address, applying the options before binding.
+	pub fn bind_with(addr: &SocketAddr, opts: &SocketOpts) -> IocpResult<SharedListener> {
+		let family = match *addr {
+			SocketAddr::V4(_) => winapi::AF_INET,
+			SocketAddr::V6(_) => winapi::AF_INET6
+		};
+		let socket = try!(Socket::new(family, winapi::SOCK_STREAM, 0));
+		
+		try!(opts.apply(socket.raw));
+		try!(socket.bind(addr));
+		try!(socket.listen());
+		
+		Ok(SharedListener {
+			socket: socket,
+			program: PathBuf::new(),
+			args: Vec::new(),
+			children: Vec::new()
+		})
+	}
+	/// Returns the listening socket.
+	pub fn raw_socket(&self) -> winapi::SOCKET {
+		self.socket.raw
+	}
+	/// Returns the local address of the listener.
+	pub fn local_addr(&self) -> IocpResult<SocketAddr> {
+		self.socket.local_addr()
+	}
+	/// Spawns the given number of child processes running the program with the arguments.
+	///
+	/// The program and arguments are remembered and used by `supervise` to restart children.
+	pub fn spawn_children<P, I, S>(&mut self, count: usize, program: P, args: I) -> IocpResult<()>
+		where P: AsRef<Path>, I: IntoIterator<Item = S>, S: Into<OsString>
+	{
+		self.program = program.as_ref().to_path_buf();
+		self.args = args.into_iter().map(|arg| arg.into()).collect();
+		
+		for _ in 0..count {
+			let child = try!(self.spawn_child());
+			self.children.push(child);
+		}
+		
+		Ok(())
+	}
+	fn spawn_child(&self) -> IocpResult<Child> {
+		let mut child = try!(Command::new(&self.program).args(&self.args).stdin(Stdio::piped()).spawn().map_err(IocpError::HostError));
+		
+		let mut info: winapi::WSAPROTOCOL_INFOW = unsafe { mem::zeroed() };
+		if unsafe { ws2_32::WSADuplicateSocketW(self.socket.raw, child.id(), &mut info) } == winapi::SOCKET_ERROR {
+			let error = last_error();
+			let _ = child.kill();
+			return Err(error);
+		}
+		
+		let bytes = unsafe { slice::from_raw_parts(&info as *const _ as *const u8, mem::size_of::<winapi::WSAPROTOCOL_INFOW>()) };
+		let mut line = String::with_capacity(bytes.len() * 2 + 1);
+		for byte in bytes.iter() {
+			line.push_str(&format!("{:02x}", byte));
+		}
+		line.push('\n');
+		
+		let written = match child.stdin {
+			Some(ref mut stdin) => stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush()),
+			None => Err(IOError::new(io::ErrorKind::BrokenPipe, "child has no standard input"))
+		};
+		
+		if let Err(error) = written {
+			let _ = child.kill();
+			return Err(IocpError::HostError(error));
+		}
+		
+		Ok(child)
+	}
+	/// Returns the process IDs of the running children.
+	pub fn child_ids(&self) -> Vec<u32> {
+		self.children.iter().map(|child| child.id()).collect()
+	}
+	/// Restarts every child that has exited.
+	///
+	/// Returns the number of children restarted.
+	pub fn supervise(&mut self) -> IocpResult<usize> {
+		let mut restarted = 0;
+		
+		for index in 0..self.children.len() {
+			let exited = match self.children[index].try_wait() {
+				Ok(status) => status.is_some(),
+				Err(error) => return Err(IocpError::HostError(error))
+			};
+			
+			if exited {
+				self.children[index] = try!(self.spawn_child());
+				restarted += 1;
+			}
+		}
+		
+		Ok(restarted)
+	}
+	/// Kills every child and waits for them to exit.
+	pub fn kill_children(&mut self) {
+		for mut child in self.children.drain(..) {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+/// Recovers the listening socket shared by the parent `SharedListener`.
+///
+/// Must be called in the child process before anything else reads its standard input. The
+/// returned socket is overlapped and owned by the caller, who closes it with `closesocket`.
+pub fn inherit_listener() -> IocpResult<winapi::SOCKET> {
+	let mut line = String::new();
+	let stdin = io::stdin();
+	try!(stdin.lock().read_line(&mut line).map_err(IocpError::HostError));
+	
+	let line = line.trim();
+	let size = mem::size_of::<winapi::WSAPROTOCOL_INFOW>();
+	
+	// Slicing the line two characters at a time needs every character to be a single byte
+	if !line.is_ascii() || line.len() != size * 2 {
+		return Err(IocpError::HostError(IOError::new(io::ErrorKind::InvalidData, "standard input does not hold a shared listener")));
+	}
+	
+	let mut info: winapi::WSAPROTOCOL_INFOW = unsafe { mem::zeroed() };
+	let bytes = unsafe { slice::from_raw_parts_mut(&mut info as *mut _ as *mut u8, size) };
+	
+	for (index, byte) in bytes.iter_mut().enumerate() {
+		*byte = match u8::from_str_radix(&line[index * 2..index * 2 + 2], 16) {
+			Ok(byte) => byte,
+			Err(_) => return Err(IocpError::HostError(IOError::new(io::ErrorKind::InvalidData, "standard input does not hold a shared listener")))
+		};
+	}
+	
+	try!(init());
+	
+	let socket = unsafe { ws2_32::WSASocketW(FROM_PROTOCOL_INFO, FROM_PROTOCOL_INFO, FROM_PROTOCOL_INFO, &mut info, 0, WSA_FLAG_OVERLAPPED) };
+	
+	if socket == winapi::INVALID_SOCKET {
+		return Err(last_error());
+	}
+	
+	Ok(socket)
+}
@@ -0,0 +1,633 @@
+//! Overlapped TCP listeners and streams using the Winsock extension functions.
+//!
+//! AcceptEx, ConnectEx and GetAcceptExSockaddrs are loaded through WSAIoctl the first time a
+//! socket needs them and cached for the rest of the process.
+
+use std::{cmp, mem, ptr};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr, SocketAddrV6, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError, set_notification_modes};
+#[cfg(feature = "pool")]
+use pool::{BufferPool, PooledOverlapped};
+use super::{Socket, SocketOpts, DualStackListener, TcpInfo, normalize_addr, tcp_info, to_raw, from_raw, last_error, set_option, pending_or_error};
+
+use std::io::Error as IOError;
+
+const SO_UPDATE_ACCEPT_CONTEXT: winapi::c_int = 0x700B;
+const SO_UPDATE_CONNECT_CONTEXT: winapi::c_int = 0x7010;
+const SD_SEND: winapi::c_int = 1;
+
+/// The space AcceptEx needs for each address: the largest sockaddr plus 16 bytes.
+const ACCEPT_ADDR_LEN: usize = 128 + 16;
+
+const WSAID_ACCEPTEX: winapi::GUID = winapi::GUID {
+	Data1: 0xb5367df1,
+	Data2: 0xcbac,
+	Data3: 0x11cf,
+	Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92]
+};
+
+const WSAID_GETACCEPTEXSOCKADDRS: winapi::GUID = winapi::GUID {
+	Data1: 0xb5367df2,
+	Data2: 0xcbac,
+	Data3: 0x11cf,
+	Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92]
+};
+
+const WSAID_CONNECTEX: winapi::GUID = winapi::GUID {
+	Data1: 0x25a207b9,
+	Data2: 0xddf3,
+	Data3: 0x4660,
+	Data4: [0x8e, 0xe9, 0x76, 0xe5, 0x8c, 0x74, 0x06, 0x3e]
+};
+
+type AcceptEx = unsafe extern "system" fn(
+	winapi::SOCKET,
+	winapi::SOCKET,
+	winapi::PVOID,
+	winapi::DWORD,
+	winapi::DWORD,
+	winapi::DWORD,
+	winapi::LPDWORD,
+	winapi::LPOVERLAPPED
+) -> winapi::BOOL;
+
+type GetAcceptExSockaddrs = unsafe extern "system" fn(
+	winapi::PVOID,
+	winapi::DWORD,
+	winapi::DWORD,
+	winapi::DWORD,
+	*mut winapi::LPSOCKADDR,
+	winapi::LPINT,
+	*mut winapi::LPSOCKADDR,
+	winapi::LPINT
+);
+
+type ConnectEx = unsafe extern "system" fn(
+	winapi::SOCKET,
+	*const winapi::SOCKADDR,
+	winapi::c_int,
+	winapi::PVOID,
+	winapi::DWORD,
+	winapi::LPDWORD,
+	winapi::LPOVERLAPPED
+) -> winapi::BOOL;
+
+static ACCEPT_EX: AtomicUsize = AtomicUsize::new(0);
+static GET_ACCEPT_EX_SOCKADDRS: AtomicUsize = AtomicUsize::new(0);
+static CONNECT_EX: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the cached extension function, loading it through the given socket the first time.
+fn load(cache: &AtomicUsize, socket: &Socket, guid: winapi::GUID) -> IocpResult<usize> {
+	let function = cache.load(Ordering::SeqCst);
+	if function != 0 {
+		return Ok(function);
+	}
+	
+	let function = try!(socket.extension_function(guid));
+	cache.store(function, Ordering::SeqCst);
+	
+	Ok(function)
+}
+
+/// Reports the outcome of AcceptEx or ConnectEx, treating a pending operation as success.
+fn started(result: winapi::BOOL) -> IocpResult<()> {
+	pending_or_error(if result == winapi::FALSE { winapi::SOCKET_ERROR } else { 0 })
+}
+
+fn family_of(addr: &SocketAddr) -> winapi::c_int {
+	match *addr {
+		SocketAddr::V4(_) => winapi::AF_INET,
+		SocketAddr::V6(_) => winapi::AF_INET6
+	}
+}
+
+fn unspecified(family: winapi::c_int) -> SocketAddr {
+	if family == winapi::AF_INET {
+		SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
+	} else {
+		SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0))
+	}
+}
+
+struct AcceptOp {
+	overlapped: winapi::OVERLAPPED,
+	addresses: [u8; ACCEPT_ADDR_LEN * 2]
+}
+
+unsafe impl Send for AcceptOp { }
+
+impl AcceptOp {
+	fn new() -> Box<AcceptOp> {
+		Box::new(AcceptOp {
+			overlapped: unsafe { mem::zeroed() },
+			addresses: [0; ACCEPT_ADDR_LEN * 2]
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+/// A listening TCP socket accepting connections with AcceptEx.
+///
+/// One accept is in flight at a time. Its completion arrives with the listener's completion key
+/// and an OVERLAPPED pointer equal to `accept_overlapped()`; pass it to `accepted`.
+///
+/// Dropping the listener cancels a pending accept. Its allocation is handed to the port, which
+/// discards the aborted packet.
+pub struct AsyncTcpListener {
+	socket: Socket,
+	port: IoCompletionPort,
+	family: winapi::c_int,
+	opts: SocketOpts,
+	accept_ex: AcceptEx,
+	get_sockaddrs: GetAcceptExSockaddrs,
+	op: Box<AcceptOp>,
+	pending: Option<Socket>
+}
+
+unsafe impl Send for AsyncTcpListener { }
+
+impl AsyncTcpListener {
+	/// Creates a socket listening on the given address and associates it with the port.
+	pub fn bind(port: &IoCompletionPort, addr: &SocketAddr, completion_key: usize) -> IocpResult<AsyncTcpListener> {
+		AsyncTcpListener::bind_with(port, addr, &SocketOpts::new(), completion_key)
+	}
+	/// Creates a listening socket, applying the options before binding and to every accepted stream.
+	pub fn bind_with(port: &IoCompletionPort, addr: &SocketAddr, opts: &SocketOpts, completion_key: usize) -> IocpResult<AsyncTcpListener> {
+		let family = family_of(addr);
+		let socket = try!(Socket::new(family, winapi::SOCK_STREAM, 0));
+		
+		try!(opts.apply(socket.raw));
+		try!(socket.bind(addr));
+		try!(socket.listen());
+		
+		AsyncTcpListener::from_listening(port, socket, family, opts, completion_key)
+	}
+	/// Takes over the sockets of a dual-stack listener, returning a listener for each of them.
+	///
+	/// Every listener is associated with the port using the given completion key, so their
+	/// accepts are told apart by `accept_overlapped()`.
+	pub fn from_dual_stack(port: &IoCompletionPort, listener: DualStackListener, completion_key: usize) -> IocpResult<Vec<AsyncTcpListener>> {
+		AsyncTcpListener::from_dual_stack_with(port, listener, &SocketOpts::new(), completion_key)
+	}
+	/// Takes over the sockets of a dual-stack listener, applying the options to every accepted stream.
+	///
+	/// The listening sockets are already bound, so options that only matter before binding have no effect.
+	pub fn from_dual_stack_with(port: &IoCompletionPort, listener: DualStackListener, opts: &SocketOpts, completion_key: usize) -> IocpResult<Vec<AsyncTcpListener>> {
+		let sockets = listener.into_sockets();
+		let mut listeners = Vec::with_capacity(sockets.len());
+		
+		for socket in sockets {
+			let family = family_of(&try!(socket.local_addr()));
+			listeners.push(try!(AsyncTcpListener::from_listening(port, socket, family, opts, completion_key)));
+		}
+		
+		Ok(listeners)
+	}
+	fn from_listening(port: &IoCompletionPort, socket: Socket, family: winapi::c_int, opts: &SocketOpts, completion_key: usize) -> IocpResult<AsyncTcpListener> {
+		let accept_ex = try!(load(&ACCEPT_EX, &socket, WSAID_ACCEPTEX));
+		let get_sockaddrs = try!(load(&GET_ACCEPT_EX_SOCKADDRS, &socket, WSAID_GETACCEPTEXSOCKADDRS));
+		
+		try!(port.associate(socket.as_handle(), completion_key));
+		
+		Ok(AsyncTcpListener {
+			socket: socket,
+			port: port.clone(),
+			family: family,
+			opts: opts.clone(),
+			accept_ex: unsafe { mem::transmute::<usize, AcceptEx>(accept_ex) },
+			get_sockaddrs: unsafe { mem::transmute::<usize, GetAcceptExSockaddrs>(get_sockaddrs) },
+			op: AcceptOp::new(),
+			pending: None
+		})
+	}
+	/// Returns the listening socket.
+	pub fn raw_socket(&self) -> winapi::SOCKET {
+		self.socket.raw
+	}
+	/// Returns the local address the listener is bound to.
+	pub fn local_addr(&self) -> IocpResult<SocketAddr> {
+		self.socket.local_addr()
+	}
+	/// Returns the OVERLAPPED pointer carried by accept completions.
+	pub fn accept_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.op.as_ptr()
+	}
+	/// Returns true while an accept is pending.
+	pub fn is_accepting(&self) -> bool {
+		self.pending.is_some()
+	}
+	/// Posts an accept for the next connection.
+	///
+	/// Does nothing if an accept is already pending.
+	pub fn accept(&mut self) -> IocpResult<()> {
+		if self.pending.is_some() {
+			return Ok(());
+		}
+		
+		let accepted = try!(Socket::new(self.family, winapi::SOCK_STREAM, 0));
+		
+		self.op.overlapped = unsafe { mem::zeroed() };
+		
+		let mut received = 0;
+		let overlapped = self.accept_overlapped();
+		let result = unsafe {
+			(self.accept_ex)(
+				self.socket.raw,
+				accepted.raw,
+				self.op.addresses.as_mut_ptr() as winapi::PVOID,
+				0,
+				ACCEPT_ADDR_LEN as winapi::DWORD,
+				ACCEPT_ADDR_LEN as winapi::DWORD,
+				&mut received,
+				overlapped
+			)
+		};
+		
+		try!(started(result));
+		self.pending = Some(accepted);
+		
+		Ok(())
+	}
+	/// Completes the pending accept if the given packet belongs to it.
+	///
+	/// The accepted stream is associated with the port using the given completion key, and gets
+	/// the options the listener was bound with. The peer
+	/// address of an IPv4 connection accepted on a dual-stack socket is returned as an IPv4
+	/// address. Returns `None` if the packet does not belong to the pending accept.
+	pub fn accepted(&mut self, port: &IoCompletionPort, packet: &DequeueResult, completion_key: usize) -> Option<IocpResult<(AsyncTcpStream, SocketAddr)>> {
+		if self.pending.is_none() || packet.overlapped() != self.accept_overlapped() {
+			return None;
+		}
+		
+		let accepted = self.pending.take().unwrap();
+		
+		if let Some(Err(error)) = packet.byte_count() {
+			return Some(Err(error));
+		}
+		
+		Some(self.finish_accept(port, accepted, completion_key))
+	}
+	fn finish_accept(&mut self, port: &IoCompletionPort, accepted: Socket, completion_key: usize) -> IocpResult<(AsyncTcpStream, SocketAddr)> {
+		try!(set_option(accepted.raw, winapi::SOL_SOCKET, SO_UPDATE_ACCEPT_CONTEXT, &self.socket.raw));
+		try!(self.opts.apply_accepted(accepted.raw));
+		
+		let mut local = ptr::null_mut();
+		let mut local_len = 0;
+		let mut remote = ptr::null_mut();
+		let mut remote_len = 0;
+		
+		unsafe {
+			(self.get_sockaddrs)(
+				self.op.addresses.as_mut_ptr() as winapi::PVOID,
+				0,
+				ACCEPT_ADDR_LEN as winapi::DWORD,
+				ACCEPT_ADDR_LEN as winapi::DWORD,
+				&mut local,
+				&mut local_len,
+				&mut remote,
+				&mut remote_len
+			);
+		}
+		
+		let peer = match from_raw(remote, remote_len) {
+			Some(peer) => normalize_addr(peer),
+			None => unspecified(self.family)
+		};
+		
+		let stream = try!(AsyncTcpStream::from_socket(port, accepted, completion_key, false));
+		
+		Ok((stream, peer))
+	}
+}
+
+impl Drop for AsyncTcpListener {
+	fn drop(&mut self) {
+		if self.pending.is_some() {
+			let retired = mem::replace(&mut self.op, AcceptOp::new());
+			self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.as_ptr(), retired);
+		}
+	}
+}
+
+struct StreamOp {
+	overlapped: winapi::OVERLAPPED,
+	buffer: Vec<u8>
+}
+
+unsafe impl Send for StreamOp { }
+
+impl StreamOp {
+	fn new(capacity: usize) -> Box<StreamOp> {
+		Box::new(StreamOp {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: Vec::with_capacity(capacity)
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+/// A connected TCP socket with one send and one receive in flight at a time.
+///
+/// Completions arrive with the stream's completion key and an OVERLAPPED pointer equal to
+/// `send_overlapped()` or `recv_overlapped()`. A stream created by `connect` first completes the
+/// connection, using the send OVERLAPPED.
+///
+/// Dropping the stream cancels the operations in flight. Their allocations are handed to the
+/// port, which discards the aborted packets.
+pub struct AsyncTcpStream {
+	socket: Socket,
+	port: IoCompletionPort,
+	send: Box<StreamOp>,
+	recv: Box<StreamOp>,
+	connecting: bool,
+	sending: bool,
+	receiving: bool,
+	skip_on_success: bool,
+	#[cfg(feature = "pool")]
+	pools: Vec<BufferPool>
+}
+
+unsafe impl Send for AsyncTcpStream { }
+
+impl AsyncTcpStream {
+	/// Starts connecting a new socket to the given address and associates it with the port.
+	///
+	/// Pass packets to `connected` until it reports the outcome of the connection.
+	pub fn connect(port: &IoCompletionPort, addr: &SocketAddr, completion_key: usize) -> IocpResult<AsyncTcpStream> {
+		AsyncTcpStream::connect_with(port, addr, &SocketOpts::new(), completion_key)
+	}
+	/// Starts connecting a new socket, applying the options before binding it.
+	pub fn connect_with(port: &IoCompletionPort, addr: &SocketAddr, opts: &SocketOpts, completion_key: usize) -> IocpResult<AsyncTcpStream> {
+		let family = family_of(addr);
+		let socket = try!(Socket::new(family, winapi::SOCK_STREAM, 0));
+		
+		try!(opts.apply(socket.raw));
+		
+		// ConnectEx requires a bound socket
+		try!(socket.bind(&unspecified(family)));
+		
+		let connect_ex = unsafe { mem::transmute::<usize, ConnectEx>(try!(load(&CONNECT_EX, &socket, WSAID_CONNECTEX))) };
+		
+		let mut stream = try!(AsyncTcpStream::from_socket(port, socket, completion_key, true));
+		
+		let (raw, len) = to_raw(addr);
+		let mut sent = 0;
+		let overlapped = stream.send_overlapped();
+		let result = unsafe { connect_ex(stream.socket.raw, &raw as *const _ as *const winapi::SOCKADDR, len, ptr::null_mut(), 0, &mut sent, overlapped) };
+		
+		if let Err(error) = started(result) {
+			// No packet will arrive for a connect that failed outright
+			stream.connecting = false;
+			stream.sending = false;
+			return Err(error);
+		}
+		
+		Ok(stream)
+	}
+	fn from_socket(port: &IoCompletionPort, socket: Socket, completion_key: usize, connecting: bool) -> IocpResult<AsyncTcpStream> {
+		try!(port.associate(socket.as_handle(), completion_key));
+		
+		Ok(AsyncTcpStream {
+			socket: socket,
+			port: port.clone(),
+			send: StreamOp::new(0),
+			recv: {
+				let mut recv = StreamOp::new(0);
+				recv.buffer.resize(8192, 0);
+				recv
+			},
+			connecting: connecting,
+			sending: connecting,
+			receiving: false,
+			skip_on_success: false,
+			#[cfg(feature = "pool")]
+			pools: Vec::new()
+		})
+	}
+	/// Returns the connected socket.
+	pub fn raw_socket(&self) -> winapi::SOCKET {
+		self.socket.raw
+	}
+	/// Returns the local address of the socket.
+	pub fn local_addr(&self) -> IocpResult<SocketAddr> {
+		self.socket.local_addr()
+	}
+	/// Returns the address of the peer.
+	pub fn peer_addr(&self) -> IocpResult<SocketAddr> {
+		let mut storage: winapi::SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+		let mut len = mem::size_of::<winapi::SOCKADDR_STORAGE>() as winapi::c_int;
+		
+		if unsafe { ws2_32::getpeername(self.socket.raw, &mut storage as *mut _ as *mut winapi::SOCKADDR, &mut len) } == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		from_raw(&storage as *const _ as *const winapi::SOCKADDR, len).ok_or_else(|| {
+			IocpError::HostError(IOError::from_raw_os_error(winapi::WSAEAFNOSUPPORT as i32))
+		})
+	}
+	/// Fetches the statistics of the connection, such as its round trip time and retransmissions.
+	pub fn tcp_info(&self) -> IocpResult<TcpInfo> {
+		tcp_info(self.socket.raw)
+	}
+	/// Returns the OVERLAPPED pointer carried by send and connect completions.
+	pub fn send_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.send.as_ptr()
+	}
+	/// Returns the OVERLAPPED pointer carried by receive completions.
+	pub fn recv_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.recv.as_ptr()
+	}
+	/// Returns true while the connection started by `connect` has not completed.
+	pub fn is_connecting(&self) -> bool {
+		self.connecting
+	}
+	/// Completes the connection started by `connect` if the given packet belongs to it.
+	///
+	/// Returns `None` if the packet does not belong to the pending connection.
+	pub fn connected(&mut self, packet: &DequeueResult) -> Option<IocpResult<()>> {
+		if !self.connecting || packet.overlapped() != self.send_overlapped() {
+			return None;
+		}
+		
+		self.connecting = false;
+		self.sending = false;
+		
+		if let Some(Err(error)) = packet.byte_count() {
+			return Some(Err(error));
+		}
+		
+		Some(set_option(self.socket.raw, winapi::SOL_SOCKET, SO_UPDATE_CONNECT_CONTEXT, &0 as &winapi::c_int))
+	}
+	/// Sets the notification modes of the socket.
+	///
+	/// Once `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, sends and receives that complete
+	/// synchronously are returned as `Issued::Inline`.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		try!(unsafe { set_notification_modes(self.socket.as_handle(), modes) });
+		
+		if modes.contains(NotificationModes::SKIP_COMPLETION_PORT_ON_SUCCESS) {
+			self.skip_on_success = true;
+		}
+		
+		Ok(())
+	}
+	/// Sets the size of the buffer receives are read into.
+	///
+	/// Has no effect while a receive is pending.
+	pub fn set_recv_buffer_size(&mut self, size: usize) {
+		if !self.receiving {
+			self.recv.buffer.resize(cmp::max(size, 1), 0);
+		}
+	}
+	/// Posts a send of the given data.
+	///
+	/// The data is copied, so the slice does not have to outlive the operation. Fails if a send
+	/// or the connection is still pending.
+	pub fn send(&mut self, data: &[u8]) -> IocpResult<Issued<usize>> {
+		if self.sending {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::WSAEINPROGRESS as i32))
+			);
+		}
+		
+		self.send.overlapped = unsafe { mem::zeroed() };
+		self.send.buffer.clear();
+		self.send.buffer.extend_from_slice(data);
+		
+		let mut buf = winapi::WSABUF {
+			len: cmp::min(self.send.buffer.len(), winapi::ULONG::max_value() as usize) as winapi::ULONG,
+			buf: self.send.buffer.as_mut_ptr() as *mut winapi::CHAR
+		};
+		let mut sent = 0;
+		let overlapped = self.send_overlapped();
+		let result = unsafe { ws2_32::WSASend(self.socket.raw, &mut buf, 1, &mut sent, 0, overlapped, None) };
+		
+		try!(pending_or_error(result));
+		
+		if result == 0 && self.skip_on_success {
+			return Ok(Issued::Inline(sent as usize));
+		}
+		
+		self.sending = true;
+		
+		Ok(Issued::Pending(overlapped))
+	}
+	/// Completes the pending send if the given packet belongs to it.
+	///
+	/// Returns the number of bytes sent, or `None` if the packet does not belong to the pending send.
+	pub fn sent(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+		if self.connecting || !self.sending || packet.overlapped() != self.send_overlapped() {
+			return None;
+		}
+		
+		self.sending = false;
+		
+		packet.byte_count()
+	}
+	/// Posts a receive into the stream's buffer.
+	///
+	/// Does nothing if a receive is already pending. An inline receive returns the data, where an
+	/// empty slice means the peer closed the connection.
+	pub fn recv(&mut self) -> IocpResult<Issued<&[u8]>> {
+		if self.receiving {
+			return Ok(Issued::Pending(self.recv_overlapped()));
+		}
+		
+		self.recv.overlapped = unsafe { mem::zeroed() };
+		
+		let mut buf = winapi::WSABUF {
+			len: cmp::min(self.recv.buffer.len(), winapi::ULONG::max_value() as usize) as winapi::ULONG,
+			buf: self.recv.buffer.as_mut_ptr() as *mut winapi::CHAR
+		};
+		let mut received = 0;
+		let mut flags = 0;
+		let overlapped = self.recv_overlapped();
+		let result = unsafe { ws2_32::WSARecv(self.socket.raw, &mut buf, 1, &mut received, &mut flags, overlapped, None) };
+		
+		try!(pending_or_error(result));
+		
+		if result == 0 && self.skip_on_success {
+			let count = cmp::min(received as usize, self.recv.buffer.len());
+			return Ok(Issued::Inline(&self.recv.buffer[..count]));
+		}
+		
+		self.receiving = true;
+		
+		Ok(Issued::Pending(overlapped))
+	}
+	/// Returns the data carried by the given packet if it completes the pending receive.
+	///
+	/// An empty slice means the peer closed the connection. Returns `None` if the packet does not
+	/// belong to the pending receive.
+	pub fn received<'a>(&'a mut self, packet: &DequeueResult) -> Option<IocpResult<&'a [u8]>> {
+		if !self.receiving || packet.overlapped() != self.recv_overlapped() {
+			return None;
+		}
+		
+		self.receiving = false;
+		
+		let buffer = &self.recv.buffer;
+		packet.byte_count().map(|result| result.map(|count| &buffer[..cmp::min(count, buffer.len())]))
+	}
+	/// Starts a receive into a buffer checked out of the pool.
+	///
+	/// Any number of pooled receives and sends can be in flight alongside the stream's own. Their
+	/// packets are passed to the pool's `complete`. Dropping the stream cancels them, and their
+	/// buffers go back to the pool once the port has discarded the aborted packets.
+	#[cfg(feature = "pool")]
+	pub fn recv_pooled(&mut self, pool: &BufferPool) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.remember(pool);
+		pool.recv(self.socket.raw)
+	}
+	/// Starts a send of the data in a buffer checked out of the pool.
+	///
+	/// Completes and is cancelled like `recv_pooled`.
+	#[cfg(feature = "pool")]
+	pub fn send_pooled(&mut self, pool: &BufferPool, overlapped: PooledOverlapped) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.remember(pool);
+		pool.send(self.socket.raw, overlapped)
+	}
+	#[cfg(feature = "pool")]
+	fn remember(&mut self, pool: &BufferPool) {
+		if !self.pools.iter().any(|known| known.same_pool(pool)) {
+			self.pools.push(pool.clone());
+		}
+	}
+	/// Shuts down the sending half of the connection, telling the peer no more data follows.
+	pub fn shutdown_send(&self) -> IocpResult<()> {
+		if unsafe { ws2_32::shutdown(self.socket.raw, SD_SEND) } == winapi::SOCKET_ERROR {
+			return Err(last_error());
+		}
+		
+		Ok(())
+	}
+}
+
+impl Drop for AsyncTcpStream {
+	fn drop(&mut self) {
+		let pending = [
+			(self.sending, &mut self.send),
+			(self.receiving, &mut self.recv)
+		];
+		
+		for (in_flight, operation) in pending {
+			if in_flight {
+				let retired = mem::replace(operation, StreamOp::new(0));
+				self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.as_ptr(), retired);
+			}
+		}
+		#[cfg(feature = "pool")]
+		for pool in self.pools.drain(..) {
+			pool.cancel(self.socket.as_handle());
+		}
+	}
+}
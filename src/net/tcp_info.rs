@@ -0,0 +1,198 @@
+//! Per-connection TCP statistics through SIO_TCP_INFO.
+
+use std::{mem, ptr};
+
+use winapi;
+use ws2_32;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use IocpResult;
+use super::last_error;
+
+const SIO_TCP_INFO: winapi::DWORD = winapi::IOC_INOUT | winapi::IOC_VENDOR | 39;
+
+#[repr(C)]
+struct TcpInfoV0 {
+	state: winapi::c_int,
+	mss: winapi::ULONG,
+	connection_time_ms: u64,
+	timestamps_enabled: winapi::BOOLEAN,
+	rtt_us: winapi::ULONG,
+	min_rtt_us: winapi::ULONG,
+	bytes_in_flight: winapi::ULONG,
+	cwnd: winapi::ULONG,
+	snd_wnd: winapi::ULONG,
+	rcv_wnd: winapi::ULONG,
+	rcv_buf: winapi::ULONG,
+	bytes_out: u64,
+	bytes_in: u64,
+	bytes_reordered: winapi::ULONG,
+	bytes_retrans: winapi::ULONG,
+	fast_retrans: winapi::ULONG,
+	dup_acks_in: winapi::ULONG,
+	timeout_episodes: winapi::ULONG,
+	syn_retrans: winapi::UCHAR
+}
+
+#[repr(C)]
+struct TcpInfoV1 {
+	v0: TcpInfoV0,
+	snd_lim_trans_rwin: winapi::ULONG,
+	snd_lim_time_rwin: winapi::ULONG,
+	snd_lim_bytes_rwin: u64,
+	snd_lim_trans_cwnd: winapi::ULONG,
+	snd_lim_time_cwnd: winapi::ULONG,
+	snd_lim_bytes_cwnd: u64,
+	snd_lim_trans_snd: winapi::ULONG,
+	snd_lim_time_snd: winapi::ULONG,
+	snd_lim_bytes_snd: u64
+}
+
+/// Statistics of a TCP connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TcpInfo {
+	/// The TCPSTATE of the connection, such as 4 for established
+	pub state: u32,
+	/// The maximum segment size
+	pub mss: u32,
+	/// How long the connection has existed, in milliseconds
+	pub connection_time_ms: u64,
+	/// Whether TCP timestamps are in use
+	pub timestamps_enabled: bool,
+	/// The smoothed round trip time in microseconds
+	pub rtt_us: u32,
+	/// The minimum round trip time seen, in microseconds
+	pub min_rtt_us: u32,
+	/// The number of bytes sent but not yet acknowledged
+	pub bytes_in_flight: u32,
+	/// The congestion window in bytes
+	pub cwnd: u32,
+	/// The peer's receive window in bytes
+	pub snd_wnd: u32,
+	/// The local receive window in bytes
+	pub rcv_wnd: u32,
+	/// The local receive buffer size in bytes
+	pub rcv_buf: u32,
+	/// The total number of bytes sent
+	pub bytes_out: u64,
+	/// The total number of bytes received
+	pub bytes_in: u64,
+	/// The number of bytes received out of order
+	pub bytes_reordered: u32,
+	/// The number of bytes retransmitted
+	pub bytes_retrans: u32,
+	/// The number of fast retransmissions
+	pub fast_retrans: u32,
+	/// The number of duplicate acknowledgements received
+	pub dup_acks_in: u32,
+	/// The number of retransmission timeouts
+	pub timeout_episodes: u32,
+	/// The number of SYN retransmissions
+	pub syn_retrans: u8,
+	/// What limited sending, available on systems supporting TCP_INFO_v1
+	pub send_limits: Option<TcpSendLimits>
+}
+
+/// How often and for how long sending was limited by each window, from TCP_INFO_v1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TcpSendLimits {
+	/// Transitions into being limited by the peer's receive window
+	pub rwin_transitions: u32,
+	/// Milliseconds spent limited by the peer's receive window
+	pub rwin_time_ms: u32,
+	/// Bytes sent while limited by the peer's receive window
+	pub rwin_bytes: u64,
+	/// Transitions into being limited by the congestion window
+	pub cwnd_transitions: u32,
+	/// Milliseconds spent limited by the congestion window
+	pub cwnd_time_ms: u32,
+	/// Bytes sent while limited by the congestion window
+	pub cwnd_bytes: u64,
+	/// Transitions into being limited by the sender
+	pub sender_transitions: u32,
+	/// Milliseconds spent limited by the sender
+	pub sender_time_ms: u32,
+	/// Bytes sent while limited by the sender
+	pub sender_bytes: u64
+}
+
+/// Fetches the statistics of a connected TCP socket.
+///
+/// Uses TCP_INFO_v1 where available and falls back to TCP_INFO_v0. `AsyncTcpStream::tcp_info`
+/// does the same for a stream.
+pub fn tcp_info(socket: winapi::SOCKET) -> IocpResult<TcpInfo> {
+	let mut info: TcpInfoV1 = unsafe { mem::zeroed() };
+	
+	if query(socket, 1, &mut info as *mut _ as winapi::LPVOID, mem::size_of::<TcpInfoV1>()).is_ok() {
+		let mut result = from_v0(&info.v0);
+		result.send_limits = Some(TcpSendLimits {
+			rwin_transitions: info.snd_lim_trans_rwin,
+			rwin_time_ms: info.snd_lim_time_rwin,
+			rwin_bytes: info.snd_lim_bytes_rwin,
+			cwnd_transitions: info.snd_lim_trans_cwnd,
+			cwnd_time_ms: info.snd_lim_time_cwnd,
+			cwnd_bytes: info.snd_lim_bytes_cwnd,
+			sender_transitions: info.snd_lim_trans_snd,
+			sender_time_ms: info.snd_lim_time_snd,
+			sender_bytes: info.snd_lim_bytes_snd
+		});
+		return Ok(result);
+	}
+	
+	try!(query(socket, 0, &mut info.v0 as *mut _ as winapi::LPVOID, mem::size_of::<TcpInfoV0>()));
+	
+	Ok(from_v0(&info.v0))
+}
+
+fn query(socket: winapi::SOCKET, version: winapi::DWORD, out: winapi::LPVOID, len: usize) -> IocpResult<()> {
+	let mut version = version;
+	let mut returned = 0;
+	
+	let queried = unsafe {
+		ws2_32::WSAIoctl(
+			socket,
+			SIO_TCP_INFO,
+			&mut version as *mut _ as winapi::LPVOID,
+			mem::size_of::<winapi::DWORD>() as winapi::DWORD,
+			out,
+			len as winapi::DWORD,
+			&mut returned,
+			ptr::null_mut(),
+			None
+		)
+	};
+	
+	if queried == winapi::SOCKET_ERROR {
+		return Err(last_error());
+	}
+	
+	Ok(())
+}
+
+fn from_v0(info: &TcpInfoV0) -> TcpInfo {
+	TcpInfo {
+		state: info.state as u32,
+		mss: info.mss,
+		connection_time_ms: info.connection_time_ms,
+		timestamps_enabled: info.timestamps_enabled != 0,
+		rtt_us: info.rtt_us,
+		min_rtt_us: info.min_rtt_us,
+		bytes_in_flight: info.bytes_in_flight,
+		cwnd: info.cwnd,
+		snd_wnd: info.snd_wnd,
+		rcv_wnd: info.rcv_wnd,
+		rcv_buf: info.rcv_buf,
+		bytes_out: info.bytes_out,
+		bytes_in: info.bytes_in,
+		bytes_reordered: info.bytes_reordered,
+		bytes_retrans: info.bytes_retrans,
+		fast_retrans: info.fast_retrans,
+		dup_acks_in: info.dup_acks_in,
+		timeout_episodes: info.timeout_episodes,
+		syn_retrans: info.syn_retrans,
+		send_limits: None
+	}
+}
@@ -0,0 +1,337 @@
+//! Overlapped UDP sockets with segmentation and receive coalescing offloads.
+
+use std::{cmp, mem, ptr, slice};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, IocpResult, IocpError};
+use super::{Socket, to_raw, from_raw, pending_or_error};
+
+use std::io::Error as IOError;
+
+const IPPROTO_UDP: winapi::c_int = 17;
+const UDP_SEND_MSG_SIZE: winapi::c_int = 2;
+const UDP_RECV_MAX_COALESCED_SIZE: winapi::c_int = 3;
+const UDP_COALESCED_INFO: winapi::c_int = 3;
+
+const WSAID_WSARECVMSG: winapi::GUID = winapi::GUID {
+	Data1: 0xf689d7c8,
+	Data2: 0x6f1f,
+	Data3: 0x436b,
+	Data4: [0x8a, 0x53, 0xe5, 0x4f, 0xe3, 0x51, 0xc3, 0x22]
+};
+
+type WsaRecvMsg = unsafe extern "system" fn(
+	winapi::SOCKET,
+	winapi::LPWSAMSG,
+	winapi::LPDWORD,
+	winapi::LPWSAOVERLAPPED,
+	winapi::LPWSAOVERLAPPED_COMPLETION_ROUTINE
+) -> winapi::c_int;
+
+static RECV_MSG: AtomicUsize = AtomicUsize::new(0);
+
+/// Space for the UDP_COALESCED_INFO control message, aligned for WSACMSGHDR.
+const CONTROL_LEN: usize = 32;
+
+#[repr(C)]
+struct ControlHeader {
+	len: usize,
+	level: winapi::c_int,
+	kind: winapi::c_int
+}
+
+struct SendOp {
+	overlapped: winapi::OVERLAPPED,
+	addr: winapi::SOCKADDR_STORAGE,
+	buffer: Vec<u8>
+}
+
+unsafe impl Send for SendOp { }
+
+impl SendOp {
+	fn new() -> Box<SendOp> {
+		Box::new(SendOp {
+			overlapped: unsafe { mem::zeroed() },
+			addr: unsafe { mem::zeroed() },
+			buffer: Vec::new()
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+struct RecvOp {
+	overlapped: winapi::OVERLAPPED,
+	msg: winapi::WSAMSG,
+	buf: winapi::WSABUF,
+	addr: winapi::SOCKADDR_STORAGE,
+	control: [u64; CONTROL_LEN / 8],
+	buffer: Vec<u8>
+}
+
+unsafe impl Send for RecvOp { }
+
+impl RecvOp {
+	fn new(len: usize) -> Box<RecvOp> {
+		Box::new(RecvOp {
+			overlapped: unsafe { mem::zeroed() },
+			msg: unsafe { mem::zeroed() },
+			buf: unsafe { mem::zeroed() },
+			addr: unsafe { mem::zeroed() },
+			control: [0; CONTROL_LEN / 8],
+			buffer: vec![0; len]
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+/// An overlapped UDP socket with one send and one receive in flight at a time.
+///
+/// Completions arrive with the completion key given to `bind` and an OVERLAPPED pointer equal to
+/// `send_overlapped()` or `recv_overlapped()`. Pass receive completions to `datagrams` and send
+/// completions to `sent`.
+///
+/// With UDP segmentation offload, a send buffer holding several datagrams of the configured
+/// message size is split by the network stack. With receive coalescing, one completion can carry
+/// several datagrams from the same source, which `Datagrams::segments` splits again.
+///
+/// Dropping the socket cancels the operations in flight. Their allocations are handed to the
+/// port, which discards the aborted packets.
+pub struct AsyncUdpSocket {
+	socket: Socket,
+	port: IoCompletionPort,
+	recv_msg: WsaRecvMsg,
+	send: Box<SendOp>,
+	recv: Box<RecvOp>,
+	sending: bool,
+	receiving: bool
+}
+
+unsafe impl Send for AsyncUdpSocket { }
+
+/// The datagrams carried by one receive completion.
+pub struct Datagrams<'a> {
+	/// The address the datagrams were sent from
+	pub addr: Option<SocketAddr>,
+	/// The received data, holding one or more datagrams
+	pub data: &'a [u8],
+	/// The size of each coalesced datagram, which equals the length of `data` without coalescing
+	pub segment_size: usize
+}
+
+impl<'a> Datagrams<'a> {
+	/// Returns the individual datagrams; all but the last are `segment_size` bytes long.
+	pub fn segments(&self) -> slice::Chunks<'a, u8> {
+		self.data.chunks(cmp::max(self.segment_size, 1))
+	}
+}
+
+impl AsyncUdpSocket {
+	/// Creates a UDP socket bound to the given address and associates it with the port.
+	pub fn bind(port: &IoCompletionPort, addr: &SocketAddr, completion_key: usize) -> IocpResult<AsyncUdpSocket> {
+		let family = match *addr {
+			SocketAddr::V4(_) => winapi::AF_INET,
+			SocketAddr::V6(_) => winapi::AF_INET6
+		};
+		let socket = try!(Socket::new(family, winapi::SOCK_DGRAM, IPPROTO_UDP));
+		
+		try!(socket.bind(addr));
+		
+		let mut function = RECV_MSG.load(Ordering::SeqCst);
+		if function == 0 {
+			function = try!(socket.extension_function(WSAID_WSARECVMSG));
+			RECV_MSG.store(function, Ordering::SeqCst);
+		}
+		
+		try!(port.associate(socket.as_handle(), completion_key));
+		
+		Ok(AsyncUdpSocket {
+			socket: socket,
+			port: port.clone(),
+			recv_msg: unsafe { mem::transmute::<usize, WsaRecvMsg>(function) },
+			send: SendOp::new(),
+			recv: RecvOp::new(65535),
+			sending: false,
+			receiving: false
+		})
+	}
+	/// Returns the local address the socket is bound to.
+	pub fn local_addr(&self) -> IocpResult<SocketAddr> {
+		self.socket.local_addr()
+	}
+	/// Enables UDP segmentation offload, splitting sends into datagrams of the given size.
+	///
+	/// Zero disables segmentation. Fails on systems without USO support.
+	pub fn set_send_msg_size(&self, size: u32) -> IocpResult<()> {
+		self.socket.set_option(IPPROTO_UDP, UDP_SEND_MSG_SIZE, size as winapi::c_int)
+	}
+	/// Enables UDP receive coalescing, combining datagrams from one source up to the given size.
+	///
+	/// Zero disables coalescing. Fails on systems without URO support.
+	pub fn set_recv_max_coalesced_size(&self, size: u32) -> IocpResult<()> {
+		self.socket.set_option(IPPROTO_UDP, UDP_RECV_MAX_COALESCED_SIZE, size as winapi::c_int)
+	}
+	/// Sets the size of the receive buffer, which limits the data carried by one completion.
+	///
+	/// Has no effect while a receive is pending.
+	pub fn set_recv_buffer_size(&mut self, size: usize) {
+		if !self.receiving {
+			self.recv.buffer.resize(cmp::max(size, 1), 0);
+		}
+	}
+	/// Returns the OVERLAPPED pointer carried by send completions.
+	pub fn send_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.send.as_ptr()
+	}
+	/// Returns the OVERLAPPED pointer carried by receive completions.
+	pub fn recv_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.recv.as_ptr()
+	}
+	/// Posts a send of the given data to the given address.
+	///
+	/// The data is copied, so the slice does not have to outlive the operation. With
+	/// segmentation offload enabled it may hold several datagrams of the configured size.
+	pub fn send_to(&mut self, data: &[u8], addr: &SocketAddr) -> IocpResult<()> {
+		if self.sending {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::WSAEINPROGRESS as i32))
+			);
+		}
+		
+		let (raw, len) = to_raw(addr);
+		
+		self.send.overlapped = unsafe { mem::zeroed() };
+		self.send.addr = raw;
+		self.send.buffer.clear();
+		self.send.buffer.extend_from_slice(data);
+		
+		let mut buf = winapi::WSABUF {
+			len: self.send.buffer.len() as winapi::ULONG,
+			buf: self.send.buffer.as_mut_ptr() as *mut winapi::CHAR
+		};
+		let overlapped = self.send_overlapped();
+		let sent = unsafe {
+			ws2_32::WSASendTo(
+				self.socket.raw,
+				&mut buf,
+				1,
+				ptr::null_mut(),
+				0,
+				&self.send.addr as *const _ as *const winapi::SOCKADDR,
+				len,
+				overlapped,
+				None
+			)
+		};
+		
+		try!(pending_or_error(sent));
+		self.sending = true;
+		
+		Ok(())
+	}
+	/// Completes the pending send if the given packet belongs to it.
+	///
+	/// Returns the number of bytes sent, or `None` if the packet does not belong to the pending send.
+	pub fn sent(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+		if !self.sending || packet.overlapped() != self.send_overlapped() {
+			return None;
+		}
+		
+		self.sending = false;
+		
+		packet.byte_count()
+	}
+	/// Posts a receive for the next datagrams.
+	///
+	/// Does nothing if a receive is already pending.
+	pub fn recv_from(&mut self) -> IocpResult<()> {
+		if self.receiving {
+			return Ok(());
+		}
+		
+		let recv = &mut *self.recv;
+		recv.overlapped = unsafe { mem::zeroed() };
+		recv.control = [0; CONTROL_LEN / 8];
+		recv.buf = winapi::WSABUF {
+			len: recv.buffer.len() as winapi::ULONG,
+			buf: recv.buffer.as_mut_ptr() as *mut winapi::CHAR
+		};
+		recv.msg = winapi::WSAMSG {
+			name: &mut recv.addr as *mut _ as winapi::LPSOCKADDR,
+			namelen: mem::size_of::<winapi::SOCKADDR_STORAGE>() as winapi::INT,
+			lpBuffers: &mut recv.buf,
+			dwBufferCount: 1,
+			Control: winapi::WSABUF {
+				len: CONTROL_LEN as winapi::ULONG,
+				buf: recv.control.as_mut_ptr() as *mut winapi::CHAR
+			},
+			dwFlags: 0
+		};
+		
+		let received = unsafe { (self.recv_msg)(self.socket.raw, &mut recv.msg, ptr::null_mut(), &mut recv.overlapped, None) };
+		
+		try!(pending_or_error(received));
+		self.receiving = true;
+		
+		Ok(())
+	}
+	/// Returns the datagrams carried by the given packet if it completes the pending receive.
+	///
+	/// Returns `None` if the packet does not belong to the pending receive.
+	pub fn datagrams<'a>(&'a mut self, packet: &DequeueResult) -> Option<IocpResult<Datagrams<'a>>> {
+		if !self.receiving || packet.overlapped() != self.recv_overlapped() {
+			return None;
+		}
+		
+		self.receiving = false;
+		
+		let recv = &*self.recv;
+		packet.byte_count().map(|result| result.map(|count| {
+			let data = &recv.buffer[..cmp::min(count, recv.buffer.len())];
+			
+			Datagrams {
+				addr: from_raw(&recv.addr as *const _ as *const winapi::SOCKADDR, recv.msg.namelen),
+				data: data,
+				segment_size: coalesced_segment_size(recv).unwrap_or(data.len())
+			}
+		}))
+	}
+}
+
+/// Reads the segment size from the UDP_COALESCED_INFO control message, if there is one.
+fn coalesced_segment_size(recv: &RecvOp) -> Option<usize> {
+	let available = cmp::min(recv.msg.Control.len as usize, CONTROL_LEN);
+	let header_len = mem::size_of::<ControlHeader>();
+	
+	if available < header_len + mem::size_of::<winapi::DWORD>() {
+		return None;
+	}
+	
+	let header = unsafe { &*(recv.control.as_ptr() as *const ControlHeader) };
+	if header.level != IPPROTO_UDP || header.kind != UDP_COALESCED_INFO {
+		return None;
+	}
+	
+	let size = unsafe { *((recv.control.as_ptr() as *const u8).offset(header_len as isize) as *const winapi::DWORD) };
+	Some(size as usize)
+}
+
+impl Drop for AsyncUdpSocket {
+	fn drop(&mut self) {
+		if self.sending {
+			let retired = mem::replace(&mut self.send, SendOp::new());
+			self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.as_ptr(), retired);
+		}
+		if self.receiving {
+			let retired = mem::replace(&mut self.recv, RecvOp::new(0));
+			self.port.inner.retire_and_cancel(self.socket.as_handle(), retired.as_ptr(), retired);
+		}
+	}
+}
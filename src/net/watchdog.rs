@@ -0,0 +1,282 @@
+//! Detection of half-open connections.
+//!
+//! A peer that vanishes without closing its connection leaves pending receives and sends
+//! outstanding forever, each pinning its buffer. The watchdog notices such connections and cancels
+//! their operations so the buffers are released.
+
+use std::{mem, ptr};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use kernel32;
+use winapi;
+use ws2_32;
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult, IocpError};
+use super::{SocketOpts, last_error, pending_or_error};
+
+const MSG_PEEK: winapi::c_int = 0x2;
+
+struct Watched {
+	overlapped: winapi::OVERLAPPED,
+	pending: bool,
+	last_activity: Instant
+}
+
+unsafe impl Send for Watched { }
+
+/// Watches sockets for peers that have gone away.
+///
+/// Each watched socket gets a zero-length probe receive, which fails once the connection is reset
+/// or keepalive gives up, and completes when the peer closes. An optional idle timeout also treats
+/// connections without any reported activity as gone.
+///
+/// A disconnected socket has all of its outstanding operations cancelled, which complete with
+/// ERROR_OPERATION_ABORTED, and a packet is posted with the watchdog's completion key and the
+/// socket in place of the OVERLAPPED pointer; `disconnected` recovers the socket from it. The
+/// socket itself is left open and is no longer watched.
+///
+/// Probes cancelled by `unwatch` or by dropping the watchdog have their allocations handed to the
+/// port, which discards the aborted packets.
+pub struct DisconnectWatchdog {
+	port: IoCompletionPort,
+	completion_key: usize,
+	keepalive: Option<u32>,
+	idle_timeout: Option<Duration>,
+	watched: HashMap<winapi::SOCKET, Box<Watched>>,
+	probes: HashMap<usize, winapi::SOCKET>
+}
+
+unsafe impl Send for DisconnectWatchdog { }
+
+impl DisconnectWatchdog {
+	/// Creates a watchdog that posts disconnect packets with the given completion key.
+	pub fn new(port: &IoCompletionPort, completion_key: usize) -> DisconnectWatchdog {
+		DisconnectWatchdog {
+			port: port.clone(),
+			completion_key: completion_key,
+			keepalive: None,
+			idle_timeout: None,
+			watched: HashMap::new(),
+			probes: HashMap::new()
+		}
+	}
+	/// Enables TCP keepalive with the given idle time in milliseconds on sockets watched from now on.
+	///
+	/// Without keepalive, a silent peer is only noticed once some data sent to it goes unacknowledged.
+	pub fn set_keepalive(&mut self, keepalive: Option<u32>) {
+		self.keepalive = keepalive;
+	}
+	/// Sets how long a socket may go without reported activity before it is treated as disconnected.
+	///
+	/// The timeout is only enforced by `check`.
+	pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+		self.idle_timeout = idle_timeout;
+	}
+	/// Returns the number of sockets being watched.
+	pub fn len(&self) -> usize {
+		self.watched.len()
+	}
+	/// Returns true if no sockets are being watched.
+	pub fn is_empty(&self) -> bool {
+		self.watched.is_empty()
+	}
+	/// Starts watching the given socket, which must already be associated with the port.
+	///
+	/// Probe completions arrive with the socket's completion key and must be passed to `handle`.
+	pub fn watch(&mut self, socket: winapi::SOCKET) -> IocpResult<()> {
+		if let Some(keepalive) = self.keepalive {
+			try!(SocketOpts::new().keepalive(Some(keepalive)).apply(socket));
+		}
+		
+		let watched = Box::new(Watched {
+			overlapped: unsafe { mem::zeroed() },
+			pending: false,
+			last_activity: Instant::now()
+		});
+		
+		self.unwatch(socket);
+		self.probes.insert(&watched.overlapped as *const winapi::OVERLAPPED as usize, socket);
+		self.watched.insert(socket, watched);
+		
+		self.probe(socket)
+	}
+	/// Stops watching the given socket, cancelling its probe.
+	pub fn unwatch(&mut self, socket: winapi::SOCKET) {
+		if let Some(watched) = self.watched.remove(&socket) {
+			self.probes.remove(&(&watched.overlapped as *const winapi::OVERLAPPED as usize));
+			retire(&self.port, socket, watched);
+		}
+	}
+	/// Records that the given socket made progress, postponing its idle timeout.
+	///
+	/// A probe that completed because data arrived is only posted again once this is called, so
+	/// it should be called after each successful read.
+	pub fn activity(&mut self, socket: winapi::SOCKET) -> IocpResult<()> {
+		match self.watched.get_mut(&socket) {
+			Some(watched) => watched.last_activity = Instant::now(),
+			None => return Ok(())
+		}
+		
+		self.probe(socket)
+	}
+	fn probe(&mut self, socket: winapi::SOCKET) -> IocpResult<()> {
+		let watched = match self.watched.get_mut(&socket) {
+			Some(watched) => watched,
+			None => return Ok(())
+		};
+		
+		if watched.pending {
+			return Ok(());
+		}
+		
+		watched.overlapped = unsafe { mem::zeroed() };
+		
+		let mut buf = winapi::WSABUF {
+			len: 0,
+			buf: ptr::null_mut()
+		};
+		let mut flags = 0;
+		let posted = unsafe { ws2_32::WSARecv(socket, &mut buf, 1, ptr::null_mut(), &mut flags, &mut watched.overlapped, None) };
+		
+		try!(pending_or_error(posted));
+		watched.pending = true;
+		
+		Ok(())
+	}
+	/// Handles a dequeued packet if it is the completion of one of the watchdog's probes.
+	///
+	/// Returns `None` if the packet does not belong to the watchdog. A failed probe, or one that
+	/// finds the peer has closed the connection, disconnects the socket. A probe cancelled by the
+	/// socket's owner, for example because the socket is being closed, only stops the watch.
+	pub fn handle(&mut self, packet: &DequeueResult) -> Option<IocpResult<()>> {
+		let probe = packet.overlapped() as usize;
+		let socket = match self.probes.get(&probe) {
+			Some(&socket) => socket,
+			None => return None
+		};
+		
+		match self.watched.get_mut(&socket) {
+			Some(watched) => watched.pending = false,
+			None => return None
+		}
+		
+		// The socket may already be closed, or even reused, so it must not be touched again
+		if let DequeueResult::Cancelled(_) = *packet {
+			self.probes.remove(&probe);
+			self.watched.remove(&socket);
+			return Some(Ok(()));
+		}
+		
+		let closed = match packet.byte_count() {
+			Some(Ok(_)) => peer_closed(socket),
+			Some(Err(_)) => Ok(true),
+			None => return None
+		};
+		
+		Some(match closed {
+			Ok(true) => self.disconnect(socket),
+			Ok(false) => Ok(()),
+			Err(error) => Err(error)
+		})
+	}
+	/// Disconnects every socket that has exceeded the idle timeout.
+	///
+	/// Returns the number of sockets disconnected. Intended to be called periodically, for example
+	/// whenever a dequeue times out.
+	pub fn check(&mut self) -> IocpResult<usize> {
+		let idle_timeout = match self.idle_timeout {
+			Some(idle_timeout) => idle_timeout,
+			None => return Ok(0)
+		};
+		
+		let now = Instant::now();
+		let expired: Vec<winapi::SOCKET> = self.watched.iter()
+			.filter(|&(_, watched)| now.duration_since(watched.last_activity) >= idle_timeout)
+			.map(|(&socket, _)| socket)
+			.collect();
+		
+		for &socket in expired.iter() {
+			try!(self.disconnect(socket));
+		}
+		
+		Ok(expired.len())
+	}
+	/// Cancels every outstanding operation on the socket and posts its disconnect packet.
+	pub fn disconnect(&mut self, socket: winapi::SOCKET) -> IocpResult<()> {
+		self.unwatch(socket);
+		
+		unsafe { let _ = kernel32::CancelIoEx(socket as winapi::HANDLE, ptr::null_mut()); }
+		
+		self.port.post_queued(CompletionStatus {
+			byte_count: 0,
+			completion_key: self.completion_key,
+			overlapped: socket as *mut winapi::OVERLAPPED
+		})
+	}
+	/// Returns the socket a disconnect packet posted by this watchdog refers to.
+	///
+	/// Returns `None` for any other packet, including the watchdog's own probes. Packets are told
+	/// apart by their completion key, so the watchdog's key must not be used by anything else
+	/// posting to or associated with the port.
+	pub fn disconnected(&self, packet: &DequeueResult) -> Option<winapi::SOCKET> {
+		match *packet {
+			DequeueResult::Completed(ref status) if status.completion_key == self.completion_key && !self.probes.contains_key(&(status.overlapped as usize)) => Some(status.overlapped as winapi::SOCKET),
+			_ => None
+		}
+	}
+}
+
+/// Tells apart a probe that completed because data arrived from one that found the connection closed.
+///
+/// The socket may be in blocking mode, so it is only peeked at once a zero timeout select finds it
+/// readable.
+fn peer_closed(socket: winapi::SOCKET) -> IocpResult<bool> {
+	let mut readable: winapi::fd_set = unsafe { mem::zeroed() };
+	readable.fd_count = 1;
+	readable.fd_array[0] = socket;
+	
+	let poll = winapi::timeval {
+		tv_sec: 0,
+		tv_usec: 0
+	};
+	
+	match unsafe { ws2_32::select(0, &mut readable, ptr::null_mut(), ptr::null_mut(), &poll) } {
+		winapi::SOCKET_ERROR => return Err(last_error()),
+		0 => return Ok(false),
+		_ => { }
+	}
+	
+	let mut byte = 0;
+	let peeked = unsafe { ws2_32::recv(socket, &mut byte, 1, MSG_PEEK) };
+	
+	if peeked == winapi::SOCKET_ERROR {
+		let error = last_error();
+		return match error {
+			IocpError::HostError(ref e) if e.raw_os_error() == Some(winapi::WSAEWOULDBLOCK as i32) => Ok(false),
+			IocpError::HostError(_) => Ok(true),
+			_ => Err(error)
+		};
+	}
+	
+	Ok(peeked == 0)
+}
+
+/// Hands the allocation of a pending probe to the port and cancels the probe.
+fn retire(port: &IoCompletionPort, socket: winapi::SOCKET, watched: Box<Watched>) {
+	if !watched.pending {
+		return;
+	}
+	
+	// The socket is borrowed, so only the probe is cancelled
+	let overlapped = &watched.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED;
+	port.inner.retire_and_cancel(socket as winapi::HANDLE, overlapped, watched);
+}
+
+impl Drop for DisconnectWatchdog {
+	fn drop(&mut self) {
+		for (socket, watched) in self.watched.drain() {
+			retire(&self.port, socket, watched);
+		}
+	}
+}
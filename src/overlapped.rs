@@ -0,0 +1,111 @@
+//! An owned OVERLAPPED carrying per-operation state through the port.
+
+use std::{fmt, mem};
+
+use winapi;
+
+#[repr(C)]
+struct OverlappedInner<T> {
+	// Must stay the first field so that a pointer to it is a pointer to the whole allocation
+	overlapped: winapi::OVERLAPPED,
+	data: T
+}
+
+/// A heap-allocated OVERLAPPED structure together with a user payload.
+///
+/// The OVERLAPPED has a stable address for as long as the Overlapped exists, so `as_ptr` can be
+/// passed to overlapped Win32 calls. To hand the whole allocation to the port, convert it with
+/// `into_raw` and take it back with `from_raw` once the pointer comes out of a CompletionStatus.
+pub struct Overlapped<T> {
+	inner: Box<OverlappedInner<T>>
+}
+
+unsafe impl<T: Send> Send for Overlapped<T> { }
+unsafe impl<T: Sync> Sync for Overlapped<T> { }
+
+impl<T> Overlapped<T> {
+	/// Creates a zeroed OVERLAPPED carrying the given payload.
+	pub fn new(data: T) -> Overlapped<T> {
+		Overlapped {
+			inner: Box::new(OverlappedInner {
+				overlapped: unsafe { mem::zeroed() },
+				data: data
+			})
+		}
+	}
+	/// Creates an OVERLAPPED for an operation at the given file offset.
+	pub fn with_offset(data: T, offset: u64) -> Overlapped<T> {
+		let mut overlapped = Overlapped::new(data);
+		overlapped.set_offset(offset);
+		overlapped
+	}
+	/// Returns the pointer to pass to overlapped Win32 calls.
+	///
+	/// The Overlapped must not be dropped or moved out of while an operation using the pointer is
+	/// in flight.
+	pub fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.inner.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+	/// Returns the OVERLAPPED structure.
+	pub fn raw(&self) -> &winapi::OVERLAPPED {
+		&self.inner.overlapped
+	}
+	/// Returns the OVERLAPPED structure for modification.
+	///
+	/// It must not be modified while an operation using it is in flight.
+	pub fn raw_mut(&mut self) -> &mut winapi::OVERLAPPED {
+		&mut self.inner.overlapped
+	}
+	/// Returns the file offset stored in the OVERLAPPED.
+	pub fn offset(&self) -> u64 {
+		((self.inner.overlapped.OffsetHigh as u64) << 32) | self.inner.overlapped.Offset as u64
+	}
+	/// Sets the file offset stored in the OVERLAPPED.
+	pub fn set_offset(&mut self, offset: u64) {
+		self.inner.overlapped.Offset = offset as u32;
+		self.inner.overlapped.OffsetHigh = (offset >> 32) as u32;
+	}
+	/// Clears the OVERLAPPED so it can be used for another operation, keeping the offset and the payload.
+	pub fn reset(&mut self) {
+		let offset = self.offset();
+		self.inner.overlapped = unsafe { mem::zeroed() };
+		self.set_offset(offset);
+	}
+	/// Returns the payload.
+	pub fn data(&self) -> &T {
+		&self.inner.data
+	}
+	/// Returns the payload for modification.
+	pub fn data_mut(&mut self) -> &mut T {
+		&mut self.inner.data
+	}
+	/// Consumes the Overlapped and returns the payload.
+	pub fn into_data(self) -> T {
+		let inner = *self.inner;
+		inner.data
+	}
+	/// Gives up ownership of the allocation, returning the pointer to the OVERLAPPED.
+	///
+	/// The allocation is leaked unless it is reclaimed with `from_raw`.
+	pub fn into_raw(self) -> *mut winapi::OVERLAPPED {
+		Box::into_raw(self.inner) as *mut winapi::OVERLAPPED
+	}
+	/// Takes back ownership of an allocation given up with `into_raw`.
+	///
+	/// # Safety
+	///
+	/// The pointer must have come from `into_raw` on an `Overlapped<T>` with the same payload type,
+	/// must not be reclaimed twice, and the operation using it must have completed, as it has once
+	/// its packet has been dequeued.
+	pub unsafe fn from_raw(overlapped: *mut winapi::OVERLAPPED) -> Overlapped<T> {
+		Overlapped {
+			inner: Box::from_raw(overlapped as *mut OverlappedInner<T>)
+		}
+	}
+}
+
+impl<T: fmt::Debug> fmt::Debug for Overlapped<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Overlapped {{ ptr: {:p}, offset: {}, data: {:?} }}", self.as_ptr(), self.offset(), self.inner.data)
+	}
+}
@@ -0,0 +1,222 @@
+//! ICMP echo requests whose replies are delivered as completion packets.
+//!
+//! Requests are sent with `IcmpSendEcho2` and an event, which is bridged to the port with a
+//! WaitRegistration, so a monitor can keep many probes in flight from a single thread.
+
+use std::{mem, ptr, slice};
+use std::net::Ipv4Addr;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, IocpResult, IocpError};
+use wait::WaitRegistration;
+
+use std::io::Error as IOError;
+
+#[repr(C)]
+struct IpOptionInformation {
+	ttl: u8,
+	tos: u8,
+	flags: u8,
+	options_size: u8,
+	options_data: *mut u8
+}
+
+#[repr(C)]
+struct IcmpEchoReply {
+	address: u32,
+	status: u32,
+	round_trip_time: u32,
+	data_size: u16,
+	reserved: u16,
+	data: winapi::PVOID,
+	options: IpOptionInformation
+}
+
+#[link(name = "iphlpapi")]
+extern "system" {
+	fn IcmpCreateFile() -> winapi::HANDLE;
+	fn IcmpCloseHandle(icmp_handle: winapi::HANDLE) -> winapi::BOOL;
+	fn IcmpSendEcho2(
+		icmp_handle: winapi::HANDLE,
+		event: winapi::HANDLE,
+		apc_routine: winapi::PVOID,
+		apc_context: winapi::PVOID,
+		destination_address: u32,
+		request_data: winapi::LPVOID,
+		request_size: winapi::WORD,
+		request_options: *mut IpOptionInformation,
+		reply_buffer: winapi::LPVOID,
+		reply_size: winapi::DWORD,
+		timeout: winapi::DWORD
+	) -> winapi::DWORD;
+	fn IcmpParseReplies(reply_buffer: winapi::LPVOID, reply_size: winapi::DWORD) -> winapi::DWORD;
+}
+
+/// The payload sent by `Ping::send`, the same one used by the ping command.
+const DEFAULT_PAYLOAD: &'static [u8] = b"abcdefghijklmnopqrstuvwabcdefghi";
+
+/// The reply to an ICMP echo request.
+#[derive(Debug, Clone)]
+pub struct EchoReply {
+	/// The address that sent the reply
+	pub address: Ipv4Addr,
+	/// The IP status of the reply, zero on success
+	pub status: u32,
+	/// The round trip time in milliseconds
+	pub round_trip_time: u32,
+	/// The time to live of the reply
+	pub ttl: u8,
+	/// The data echoed back by the host
+	pub data: Vec<u8>
+}
+
+/// An ICMP echo request in flight.
+///
+/// When the reply arrives or the request times out, a packet with the completion key given to
+/// `send` and an OVERLAPPED pointer equal to `id()` is posted to the port. Call `reply()` after
+/// that packet has been dequeued.
+pub struct Ping {
+	icmp: winapi::HANDLE,
+	event: winapi::HANDLE,
+	id: Box<winapi::OVERLAPPED>,
+	request: Vec<u8>,
+	// The reply starts with an ICMP_ECHO_REPLY, which holds pointers
+	reply: Vec<u64>,
+	registration: Option<WaitRegistration>,
+	pending: bool
+}
+
+unsafe impl Send for Ping { }
+
+impl Ping {
+	/// Sends an echo request with the default 32 byte payload.
+	///
+	/// The timeout is in milliseconds.
+	pub fn send(port: &IoCompletionPort, address: Ipv4Addr, timeout: u32, completion_key: usize) -> IocpResult<Ping> {
+		Ping::send_with_data(port, address, DEFAULT_PAYLOAD, timeout, completion_key)
+	}
+	/// Sends an echo request with the given payload.
+	///
+	/// The timeout is in milliseconds.
+	pub fn send_with_data(port: &IoCompletionPort, address: Ipv4Addr, data: &[u8], timeout: u32, completion_key: usize) -> IocpResult<Ping> {
+		if data.len() > winapi::WORD::max_value() as usize {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_INVALID_PARAMETER as i32))
+			);
+		}
+		
+		let icmp = unsafe { IcmpCreateFile() };
+		
+		if icmp == winapi::INVALID_HANDLE_VALUE {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		let event = unsafe { kernel32::CreateEventW(ptr::null_mut(), winapi::TRUE, winapi::FALSE, ptr::null()) };
+		
+		if event.is_null() {
+			let error = IOError::last_os_error();
+			unsafe { let _ = IcmpCloseHandle(icmp); }
+			return Err(
+				IocpError::HostError(error)
+			);
+		}
+		
+		let mut ping = Ping {
+			icmp: icmp,
+			event: event,
+			id: Box::new(unsafe { mem::zeroed() }),
+			request: data.to_vec(),
+			reply: vec![0; (mem::size_of::<IcmpEchoReply>() + data.len() + 8 + 16 + 7) / 8],
+			registration: None,
+			pending: false
+		};
+		
+		let sent = unsafe {
+			IcmpSendEcho2(
+				ping.icmp,
+				ping.event,
+				ptr::null_mut(),
+				ptr::null_mut(),
+				u32::from_ne_bytes(address.octets()),
+				ping.request.as_mut_ptr() as winapi::LPVOID,
+				ping.request.len() as winapi::WORD,
+				ptr::null_mut(),
+				ping.reply.as_mut_ptr() as winapi::LPVOID,
+				(ping.reply.len() * 8) as winapi::DWORD,
+				timeout
+			)
+		};
+		
+		if sent == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() != Some(winapi::ERROR_IO_PENDING as i32) {
+				return Err(
+					IocpError::HostError(error)
+				);
+			}
+		} else {
+			// The reply is already in the buffer; signal the event so the packet is still posted
+			unsafe { kernel32::SetEvent(ping.event) };
+		}
+		
+		ping.pending = true;
+		
+		let id = ping.id();
+		ping.registration = Some(try!(WaitRegistration::new(port, ping.event, completion_key, id)));
+		
+		Ok(ping)
+	}
+	/// Returns the OVERLAPPED pointer carried by the completion packet for this request.
+	///
+	/// The pointer only identifies the request and must not be dereferenced.
+	pub fn id(&self) -> *mut winapi::OVERLAPPED {
+		&*self.id as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+	/// Returns the reply to the request.
+	///
+	/// Must only be called once the completion packet for this request has been dequeued. If no
+	/// reply was received, the error carries the IP status, such as IP_REQ_TIMED_OUT.
+	pub fn reply(&self) -> IocpResult<EchoReply> {
+		let replies = unsafe { IcmpParseReplies(self.reply.as_ptr() as winapi::LPVOID, (self.reply.len() * 8) as winapi::DWORD) };
+		
+		if replies == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		let reply = unsafe { &*(self.reply.as_ptr() as *const IcmpEchoReply) };
+		let data = if reply.data.is_null() {
+			Vec::new()
+		} else {
+			unsafe { slice::from_raw_parts(reply.data as *const u8, reply.data_size as usize) }.to_vec()
+		};
+		
+		Ok(EchoReply {
+			address: Ipv4Addr::from(reply.address.to_ne_bytes()),
+			status: reply.status,
+			round_trip_time: reply.round_trip_time,
+			ttl: reply.options.ttl,
+			data: data
+		})
+	}
+}
+
+impl Drop for Ping {
+	fn drop(&mut self) {
+		self.registration.take();
+		
+		unsafe {
+			// The reply buffer has to outlive the request, which always ends within its timeout
+			if self.pending {
+				let _ = kernel32::WaitForSingleObject(self.event, winapi::INFINITE);
+			}
+			let _ = IcmpCloseHandle(self.icmp);
+			let _ = kernel32::CloseHandle(self.event);
+		}
+	}
+}
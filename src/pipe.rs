@@ -0,0 +1,442 @@
+//! Named pipe servers whose connections are driven by an I/O completion port.
+//!
+//! A pipe instance is created for overlapped I/O and waits for a client with an overlapped
+//! ConnectNamedPipe, so one thread can serve many pipe instances. Reads and writes on a connected
+//! instance are overlapped as well.
+
+use std::{cmp, mem, ptr};
+use std::ffi::{OsStr, OsString};
+use std::marker::PhantomData;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, Issued, NotificationModes, IocpResult, IocpError, set_notification_modes};
+
+use std::io::Error as IOError;
+
+#[link(name = "advapi32")]
+extern "system" {
+	fn ImpersonateNamedPipeClient(named_pipe: winapi::HANDLE) -> winapi::BOOL;
+	fn RevertToSelf() -> winapi::BOOL;
+}
+
+#[repr(C)]
+struct PipeOp {
+	overlapped: winapi::OVERLAPPED,
+	buffer: Vec<u8>
+}
+
+unsafe impl Send for PipeOp { }
+
+impl PipeOp {
+	fn new() -> Box<PipeOp> {
+		Box::new(PipeOp {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: Vec::new()
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+/// Who is on the other end of a connected pipe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity {
+	/// The process identifier of the client
+	pub process_id: u32,
+	/// The terminal services session of the client
+	pub session_id: u32,
+	/// The name of the user the client runs as
+	pub user_name: String
+}
+
+/// The server end of one named pipe instance, opened for overlapped I/O.
+///
+/// Every completion arrives with the pipe's completion key. The OVERLAPPED pointer tells the
+/// operations apart: it equals `connect_overlapped()`, `read_overlapped()` or `write_overlapped()`,
+/// and the packet is passed to `connected`, `read_data` or `written` respectively. One read and one
+/// write can be in flight at a time.
+///
+/// Dropping the pipe cancels its operations and closes it. Their allocations are handed to the
+/// port, which discards their aborted packets.
+pub struct AsyncNamedPipe {
+	handle: winapi::HANDLE,
+	port: IoCompletionPort,
+	connect: Box<PipeOp>,
+	read: Box<PipeOp>,
+	write: Box<PipeOp>,
+	connecting: bool,
+	connected: bool,
+	reading: bool,
+	writing: bool,
+	skip_on_success: bool
+}
+
+unsafe impl Send for AsyncNamedPipe { }
+
+impl AsyncNamedPipe {
+	/// Creates a byte-mode, duplex pipe instance with the given name and associates it with the port.
+	///
+	/// The name has the form ```\\.\pipe\name```. Further instances with the same name can be
+	/// created to serve several clients at once.
+	pub fn create(port: &IoCompletionPort, name: &str, completion_key: usize) -> IocpResult<AsyncNamedPipe> {
+		let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+		
+		let handle = unsafe {
+			kernel32::CreateNamedPipeW(
+				name.as_ptr(),
+				winapi::PIPE_ACCESS_DUPLEX | winapi::FILE_FLAG_OVERLAPPED,
+				winapi::PIPE_TYPE_BYTE | winapi::PIPE_READMODE_BYTE | winapi::PIPE_WAIT | winapi::PIPE_REJECT_REMOTE_CLIENTS,
+				winapi::PIPE_UNLIMITED_INSTANCES,
+				4096,
+				4096,
+				0,
+				ptr::null_mut()
+			)
+		};
+		
+		if handle == winapi::INVALID_HANDLE_VALUE {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		let pipe = AsyncNamedPipe {
+			handle: handle,
+			port: port.clone(),
+			connect: PipeOp::new(),
+			read: PipeOp::new(),
+			write: PipeOp::new(),
+			connecting: false,
+			connected: false,
+			reading: false,
+			writing: false,
+			skip_on_success: false
+		};
+		
+		try!(port.associate(handle, completion_key));
+		
+		Ok(pipe)
+	}
+	/// Returns the handle of the pipe instance.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.handle
+	}
+	/// Returns the OVERLAPPED pointer carried by the completion of `connect`.
+	pub fn connect_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.connect.as_ptr()
+	}
+	/// Returns the OVERLAPPED pointer carried by the completion of `read`.
+	pub fn read_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.read.as_ptr()
+	}
+	/// Returns the OVERLAPPED pointer carried by the completion of `write`.
+	pub fn write_overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.write.as_ptr()
+	}
+	/// Sets the notification modes of the pipe instance.
+	///
+	/// Once `SKIP_COMPLETION_PORT_ON_SUCCESS` is set, reads and writes that complete synchronously
+	/// are returned as `Issued::Inline`.
+	pub fn set_notification_modes(&mut self, modes: NotificationModes) -> IocpResult<()> {
+		try!(unsafe { set_notification_modes(self.handle, modes) });
+		
+		if modes.contains(NotificationModes::SKIP_COMPLETION_PORT_ON_SUCCESS) {
+			self.skip_on_success = true;
+		}
+		
+		Ok(())
+	}
+	/// Returns true once a client has connected.
+	pub fn is_connected(&self) -> bool {
+		self.connected
+	}
+	/// Starts waiting for a client to connect.
+	///
+	/// Returns true if a client was already connected, in which case no packet is queued.
+	/// Otherwise the connection is reported by a packet to pass to `connected`.
+	pub fn connect(&mut self) -> IocpResult<bool> {
+		if self.connected || self.connecting {
+			return Ok(self.connected);
+		}
+		
+		self.connect.overlapped = unsafe { mem::zeroed() };
+		
+		if unsafe { kernel32::ConnectNamedPipe(self.handle, self.connect_overlapped()) } != 0 {
+			self.connecting = true;
+			return Ok(false);
+		}
+		
+		let error = IOError::last_os_error();
+		match error.raw_os_error() {
+			Some(code) if code == winapi::ERROR_IO_PENDING as i32 => {
+				self.connecting = true;
+				Ok(false)
+			},
+			Some(code) if code == winapi::ERROR_PIPE_CONNECTED as i32 => {
+				self.connected = true;
+				Ok(true)
+			},
+			_ => Err(IocpError::HostError(error))
+		}
+	}
+	/// Checks whether a dequeued packet completes the pending `connect`.
+	///
+	/// Returns `None` if it does not, and otherwise whether the client connected.
+	pub fn connected(&mut self, packet: &DequeueResult) -> Option<IocpResult<()>> {
+		if !self.connecting || packet.overlapped() != self.connect_overlapped() {
+			return None;
+		}
+		
+		self.connecting = false;
+		
+		packet.byte_count().map(|result| result.map(|_| {
+			self.connected = true;
+		}))
+	}
+	/// Starts reading up to `len` bytes from the connected client.
+	///
+	/// Does nothing if a read is already pending. The data of a pending read is handed out by
+	/// `read_data` once the read's packet has been dequeued, while an inline read returns it
+	/// directly. As with `read_data`, an empty slice means the client closed its end of the pipe.
+	pub fn read(&mut self, len: usize) -> IocpResult<Issued<&[u8]>> {
+		if self.reading {
+			return Ok(Issued::Pending(self.read_overlapped()));
+		}
+		
+		let len = cmp::min(cmp::max(len, 1), winapi::DWORD::max_value() as usize);
+		
+		self.read.overlapped = unsafe { mem::zeroed() };
+		self.read.buffer.clear();
+		self.read.buffer.resize(len, 0);
+		
+		let read = unsafe { kernel32::ReadFile(self.handle, self.read.buffer.as_mut_ptr() as winapi::LPVOID, len as winapi::DWORD, ptr::null_mut(), self.read.as_ptr()) };
+		
+		match started(read) {
+			Ok(()) => { },
+			// A read that fails outright queues no packet, so the client closing is reported inline
+			Err(IocpError::HostError(ref error)) if error.raw_os_error() == Some(winapi::ERROR_BROKEN_PIPE as i32) => return Ok(Issued::Inline(&[])),
+			Err(error) => return Err(error)
+		}
+		
+		if read != 0 && self.skip_on_success {
+			let count = cmp::min(self.transferred(self.read_overlapped()), self.read.buffer.len());
+			return Ok(Issued::Inline(&self.read.buffer[..count]));
+		}
+		
+		self.reading = true;
+		
+		Ok(Issued::Pending(self.read_overlapped()))
+	}
+	/// Returns the data carried by the given packet if it completes the pending `read`.
+	///
+	/// An empty slice means the client closed its end of the pipe. Returns `None` if the packet
+	/// does not belong to the pending read.
+	pub fn read_data<'a>(&'a mut self, packet: &DequeueResult) -> Option<IocpResult<&'a [u8]>> {
+		if !self.reading || packet.overlapped() != self.read_overlapped() {
+			return None;
+		}
+		
+		self.reading = false;
+		
+		let result = match packet.byte_count() {
+			Some(Ok(count)) => Ok(cmp::min(count, self.read.buffer.len())),
+			Some(Err(IocpError::HostError(ref error))) if error.raw_os_error() == Some(winapi::ERROR_BROKEN_PIPE as i32) => Ok(0),
+			Some(Err(error)) => Err(error),
+			None => return None
+		};
+		
+		Some(result.map(move |count| &self.read.buffer[..count]))
+	}
+	/// Starts writing the given data to the connected client.
+	///
+	/// The data is copied, so the slice does not have to outlive the operation. Fails if a write is
+	/// still pending.
+	pub fn write(&mut self, data: &[u8]) -> IocpResult<Issued<usize>> {
+		if self.writing {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_BUSY as i32))
+			);
+		}
+		
+		let len = cmp::min(data.len(), winapi::DWORD::max_value() as usize);
+		
+		self.write.overlapped = unsafe { mem::zeroed() };
+		self.write.buffer.clear();
+		self.write.buffer.extend_from_slice(&data[..len]);
+		
+		let written = unsafe { kernel32::WriteFile(self.handle, self.write.buffer.as_ptr() as winapi::LPCVOID, len as winapi::DWORD, ptr::null_mut(), self.write.as_ptr()) };
+		
+		try!(started(written));
+		
+		if written != 0 && self.skip_on_success {
+			return Ok(Issued::Inline(self.transferred(self.write_overlapped())));
+		}
+		
+		self.writing = true;
+		
+		Ok(Issued::Pending(self.write_overlapped()))
+	}
+	/// Returns the byte count of an operation that completed synchronously.
+	fn transferred(&self, overlapped: *mut winapi::OVERLAPPED) -> usize {
+		// A synchronous success has already filled in the OVERLAPPED, and no packet will follow
+		let mut transferred = 0;
+		unsafe { kernel32::GetOverlappedResult(self.handle, overlapped, &mut transferred, winapi::FALSE) };
+		
+		transferred as usize
+	}
+	/// Checks whether a dequeued packet completes the pending `write`.
+	///
+	/// Returns `None` if it does not, and otherwise the number of bytes written.
+	pub fn written(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+		if !self.writing || packet.overlapped() != self.write_overlapped() {
+			return None;
+		}
+		
+		self.writing = false;
+		
+		packet.byte_count()
+	}
+	/// Disconnects the client so the instance can wait for another one with `connect`.
+	///
+	/// Operations still in flight are cancelled. Their allocations are handed to the port, which
+	/// discards their aborted packets, so they are never returned by `connected`, `read_data` or
+	/// `written`. Data the client has not read yet is lost; wait for pending writes to complete
+	/// first to avoid that.
+	pub fn disconnect(&mut self) -> IocpResult<()> {
+		self.retire_pending();
+		
+		if unsafe { kernel32::DisconnectNamedPipe(self.handle) } == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		self.connected = false;
+		
+		Ok(())
+	}
+	/// Hands the allocations of operations in flight to the port and cancels them.
+	fn retire_pending(&mut self) {
+		let pending = [
+			(self.connecting, &mut self.connect),
+			(self.reading, &mut self.read),
+			(self.writing, &mut self.write)
+		];
+		
+		for (in_flight, operation) in pending {
+			if in_flight {
+				let retired = mem::replace(operation, PipeOp::new());
+				self.port.inner.retire_and_cancel(self.handle, retired.as_ptr(), retired);
+			}
+		}
+		
+		self.connecting = false;
+		self.reading = false;
+		self.writing = false;
+	}
+	/// Makes the calling thread act with the security context of the connected client.
+	///
+	/// The thread reverts to its own context when the returned guard is dropped. The guard cannot
+	/// be sent to another thread, since impersonation applies to the thread that started it.
+	pub fn impersonate(&self) -> IocpResult<Impersonation> {
+		if unsafe { ImpersonateNamedPipeClient(self.handle) } == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(Impersonation {
+			_not_send: PhantomData
+		})
+	}
+	/// Returns the identity of the connected client.
+	///
+	/// The user name can only be retrieved when the client allows at least identification.
+	pub fn client_identity(&self) -> IocpResult<ClientIdentity> {
+		let mut process_id = 0;
+		let mut session_id = 0;
+		let mut user_name = [0u16; winapi::UNLEN as usize + 1];
+		
+		let queried = unsafe {
+			kernel32::GetNamedPipeClientProcessId(self.handle, &mut process_id) != 0 &&
+			kernel32::GetNamedPipeClientSessionId(self.handle, &mut session_id) != 0 &&
+			kernel32::GetNamedPipeHandleStateW(
+				self.handle,
+				ptr::null_mut(),
+				ptr::null_mut(),
+				ptr::null_mut(),
+				ptr::null_mut(),
+				user_name.as_mut_ptr(),
+				user_name.len() as winapi::DWORD
+			) != 0
+		};
+		
+		if !queried {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		let len = user_name.iter().position(|&c| c == 0).unwrap_or(user_name.len());
+		
+		Ok(ClientIdentity {
+			process_id: process_id,
+			session_id: session_id,
+			user_name: OsString::from_wide(&user_name[..len]).to_string_lossy().into_owned()
+		})
+	}
+}
+
+impl Drop for AsyncNamedPipe {
+	fn drop(&mut self) {
+		self.retire_pending();
+		
+		unsafe { let _ = kernel32::CloseHandle(self.handle); }
+	}
+}
+
+/// Reports the outcome of starting ReadFile or WriteFile, treating a pending operation as success.
+fn started(result: winapi::BOOL) -> IocpResult<()> {
+	if result != 0 {
+		return Ok(());
+	}
+	
+	let error = IOError::last_os_error();
+	if error.raw_os_error() == Some(winapi::ERROR_IO_PENDING as i32) {
+		return Ok(());
+	}
+	
+	Err(
+		IocpError::HostError(error)
+	)
+}
+
+/// Impersonation of a pipe client by the current thread, reverted when dropped.
+pub struct Impersonation {
+	_not_send: PhantomData<*const ()>
+}
+
+impl Impersonation {
+	/// Reverts to the thread's own security context, reporting any failure.
+	pub fn revert(self) -> IocpResult<()> {
+		mem::forget(self);
+		
+		if unsafe { RevertToSelf() } == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		Ok(())
+	}
+}
+
+impl Drop for Impersonation {
+	fn drop(&mut self) {
+		unsafe { let _ = RevertToSelf(); }
+	}
+}
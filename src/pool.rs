@@ -0,0 +1,363 @@
+//! Reusable buffers paired with OVERLAPPED structures.
+//!
+//! Allocating a buffer and an OVERLAPPED for every operation shows up in the profile of a busy
+//! server and fragments the heap. A BufferPool keeps fixed-size buffers together with their
+//! OVERLAPPED, checks one out for every operation it submits and takes it back once the
+//! operation's packet has been dequeued.
+
+use std::{cmp, mem, ptr, slice};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use kernel32;
+use winapi;
+#[cfg(feature = "net")]
+use ws2_32;
+
+use {IoCompletionPort, DequeueResult, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+#[repr(C)]
+struct Slot {
+	overlapped: winapi::OVERLAPPED,
+	buffer: Box<[u8]>,
+	len: usize,
+	reading: bool,
+	handle: usize
+}
+
+unsafe impl Send for Slot { }
+
+impl Slot {
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+struct Shared {
+	port: IoCompletionPort,
+	buffer_size: usize,
+	max_idle: usize,
+	idle: Mutex<Vec<Box<Slot>>>,
+	in_flight: Mutex<HashMap<usize, Box<Slot>>>
+}
+
+impl Shared {
+	fn release(&self, mut slot: Box<Slot>) {
+		let mut idle = self.idle.lock().unwrap();
+		
+		if idle.len() < self.max_idle {
+			slot.overlapped = unsafe { mem::zeroed() };
+			slot.len = 0;
+			idle.push(slot);
+		}
+	}
+}
+
+/// The buffer of a cancelled operation, which goes back to its pool once the port discards the
+/// aborted packet.
+struct Returning {
+	slot: Option<Box<Slot>>,
+	pool: Weak<Shared>
+}
+
+impl Drop for Returning {
+	fn drop(&mut self) {
+		if let (Some(slot), Some(pool)) = (self.slot.take(), self.pool.upgrade()) {
+			pool.release(slot);
+		}
+	}
+}
+
+impl Drop for Shared {
+	fn drop(&mut self) {
+		// The operations may still be in flight, and their packets are swallowed by the port
+		for (overlapped, slot) in self.in_flight.get_mut().unwrap().drain() {
+			self.port.inner.retire(overlapped as *mut winapi::OVERLAPPED, slot);
+		}
+	}
+}
+
+/// A buffer checked out of a BufferPool, together with the OVERLAPPED of its operation.
+///
+/// The buffer holds `len()` bytes of data out of a fixed capacity. Dropping it returns it to
+/// the pool.
+pub struct PooledOverlapped {
+	slot: Option<Box<Slot>>,
+	pool: Arc<Shared>
+}
+
+impl PooledOverlapped {
+	fn slot(&self) -> &Slot {
+		self.slot.as_ref().unwrap()
+	}
+	fn slot_mut(&mut self) -> &mut Slot {
+		self.slot.as_mut().unwrap()
+	}
+	/// Returns the pointer of the OVERLAPPED.
+	pub fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		self.slot().as_ptr()
+	}
+	/// Returns the size of the buffer.
+	pub fn capacity(&self) -> usize {
+		self.slot().buffer.len()
+	}
+	/// Returns the number of bytes of data in the buffer.
+	pub fn len(&self) -> usize {
+		self.slot().len
+	}
+	/// Returns true if the buffer holds no data.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	/// Sets the number of bytes of data in the buffer, up to its capacity.
+	pub fn set_len(&mut self, len: usize) {
+		let slot = self.slot_mut();
+		slot.len = cmp::min(len, slot.buffer.len());
+	}
+	/// Returns the data in the buffer.
+	pub fn data(&self) -> &[u8] {
+		let slot = self.slot();
+		&slot.buffer[..slot.len]
+	}
+	/// Returns the whole buffer, regardless of how much of it holds data.
+	pub fn buffer_mut(&mut self) -> &mut [u8] {
+		&mut self.slot_mut().buffer
+	}
+	/// Replaces the data in the buffer with as much of the given data as fits.
+	///
+	/// Returns the number of bytes copied.
+	pub fn fill(&mut self, data: &[u8]) -> usize {
+		let slot = self.slot_mut();
+		let len = cmp::min(data.len(), slot.buffer.len());
+		
+		slot.buffer[..len].copy_from_slice(&data[..len]);
+		slot.len = len;
+		
+		len
+	}
+	/// Sets the file offset stored in the OVERLAPPED.
+	pub fn set_offset(&mut self, offset: u64) {
+		let slot = self.slot_mut();
+		slot.overlapped.Offset = offset as winapi::DWORD;
+		slot.overlapped.OffsetHigh = (offset >> 32) as winapi::DWORD;
+	}
+}
+
+impl Drop for PooledOverlapped {
+	fn drop(&mut self) {
+		if let Some(slot) = self.slot.take() {
+			self.pool.release(slot);
+		}
+	}
+}
+
+/// An operation submitted through a BufferPool that has completed.
+pub struct PooledCompletion {
+	/// The buffer of the operation; after a read, it holds the data that was read
+	pub overlapped: PooledOverlapped,
+	/// The number of bytes transferred, or the error the operation failed with
+	pub result: IocpResult<usize>
+}
+
+/// A thread-safe pool of fixed-size buffers for operations completing through a port.
+///
+/// Buffers are checked out when an operation is submitted and kept by the pool while it is in
+/// flight. Passing the operation's packet to `complete`, including the aborted packet of a
+/// cancelled operation, hands the buffer back, and dropping it returns it to the pool.
+///
+/// Every submitted operation must queue a packet, so handles used with the pool must not have
+/// `SKIP_COMPLETION_PORT_ON_SUCCESS` set. Clones share the same buffers. Once the last clone and
+/// every checked-out buffer are gone, the buffers still in flight are handed to the port, which
+/// discards their packets.
+///
+/// `AssociatedHandle`, `AsyncFile` and `AsyncTcpStream` submit operations through a pool with
+/// their `read_pooled`, `write_pooled`, `recv_pooled` and `send_pooled` methods, and cancel them
+/// with `cancel` when dropped.
+#[derive(Clone)]
+pub struct BufferPool {
+	shared: Arc<Shared>
+}
+
+impl BufferPool {
+	/// Creates a pool of buffers of the given size for operations completing through the port.
+	///
+	/// At most `max_idle` buffers are kept for reuse; further ones are freed when returned.
+	pub fn new(port: &IoCompletionPort, buffer_size: usize, max_idle: usize) -> BufferPool {
+		BufferPool {
+			shared: Arc::new(Shared {
+				port: port.clone(),
+				buffer_size: cmp::min(buffer_size, winapi::DWORD::max_value() as usize),
+				max_idle: max_idle,
+				idle: Mutex::new(Vec::new()),
+				in_flight: Mutex::new(HashMap::new())
+			})
+		}
+	}
+	/// Returns the size of the pool's buffers.
+	pub fn buffer_size(&self) -> usize {
+		self.shared.buffer_size
+	}
+	/// Returns the number of buffers kept for reuse.
+	pub fn idle(&self) -> usize {
+		self.shared.idle.lock().unwrap().len()
+	}
+	/// Returns true if both pools share the same buffers, as clones do.
+	pub fn same_pool(&self, other: &BufferPool) -> bool {
+		Arc::ptr_eq(&self.shared, &other.shared)
+	}
+	/// Returns the number of buffers whose operations are in flight.
+	pub fn in_flight(&self) -> usize {
+		self.shared.in_flight.lock().unwrap().len()
+	}
+	/// Checks out an empty buffer, allocating one if none is idle.
+	pub fn checkout(&self) -> PooledOverlapped {
+		let slot = self.shared.idle.lock().unwrap().pop().unwrap_or_else(|| Box::new(Slot {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: vec![0; self.shared.buffer_size].into_boxed_slice(),
+			len: 0,
+			reading: false,
+			handle: 0
+		}));
+		
+		PooledOverlapped {
+			slot: Some(slot),
+			pool: self.shared.clone()
+		}
+	}
+	/// Submits an operation using a checked-out buffer.
+	///
+	/// The closure issues the operation on the handle with the buffer and the OVERLAPPED, and
+	/// reports whether it was started. ERROR_IO_PENDING counts as started. A read is given the
+	/// whole buffer, and anything else only the data in it. Returns the OVERLAPPED pointer the
+	/// completion will carry; if the operation could not be started, the buffer goes back to the
+	/// pool.
+	pub fn submit<F>(&self, handle: winapi::HANDLE, mut overlapped: PooledOverlapped, reading: bool, start: F) -> IocpResult<*mut winapi::OVERLAPPED>
+		where F: FnOnce(winapi::HANDLE, &mut [u8], *mut winapi::OVERLAPPED) -> winapi::BOOL
+	{
+		let mut slot = overlapped.slot.take().unwrap();
+		slot.reading = reading;
+		slot.handle = handle as usize;
+		
+		let pointer = slot.as_ptr();
+		let len = if reading { slot.buffer.len() } else { slot.len };
+		let buffer = slot.buffer.as_mut_ptr();
+		
+		// Another thread can dequeue the packet as soon as the operation starts
+		self.shared.in_flight.lock().unwrap().insert(pointer as usize, slot);
+		
+		if start(handle, unsafe { slice::from_raw_parts_mut(buffer, len) }, pointer) == 0 {
+			let error = IOError::last_os_error();
+			if error.raw_os_error() != Some(winapi::ERROR_IO_PENDING as i32) {
+				let slot = self.shared.in_flight.lock().unwrap().remove(&(pointer as usize));
+				if let Some(slot) = slot {
+					self.shared.release(slot);
+				}
+				return Err(
+					IocpError::HostError(error)
+				);
+			}
+		}
+		
+		Ok(pointer)
+	}
+	/// Starts an overlapped read into a fresh buffer at the given offset.
+	pub fn read(&self, handle: winapi::HANDLE, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		let mut overlapped = self.checkout();
+		overlapped.set_offset(offset);
+		
+		self.submit(handle, overlapped, true, |handle, buffer, overlapped| unsafe {
+			kernel32::ReadFile(handle, buffer.as_mut_ptr() as winapi::LPVOID, buffer.len() as winapi::DWORD, ptr::null_mut(), overlapped)
+		})
+	}
+	/// Starts an overlapped write of the data in a checked-out buffer at the given offset.
+	pub fn write(&self, handle: winapi::HANDLE, mut overlapped: PooledOverlapped, offset: u64) -> IocpResult<*mut winapi::OVERLAPPED> {
+		overlapped.set_offset(offset);
+		
+		self.submit(handle, overlapped, false, |handle, buffer, overlapped| unsafe {
+			kernel32::WriteFile(handle, buffer.as_ptr() as winapi::LPCVOID, buffer.len() as winapi::DWORD, ptr::null_mut(), overlapped)
+		})
+	}
+	/// Starts an overlapped receive into a fresh buffer on the given socket.
+	#[cfg(feature = "net")]
+	pub fn recv(&self, socket: winapi::SOCKET) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.submit(socket as winapi::HANDLE, self.checkout(), true, |handle, buffer, overlapped| {
+			let mut buf = winapi::WSABUF {
+				len: buffer.len() as winapi::ULONG,
+				buf: buffer.as_mut_ptr() as *mut winapi::CHAR
+			};
+			let mut flags = 0;
+			let result = unsafe { ws2_32::WSARecv(handle as winapi::SOCKET, &mut buf, 1, ptr::null_mut(), &mut flags, overlapped, None) };
+			
+			if result == 0 { winapi::TRUE } else { winapi::FALSE }
+		})
+	}
+	/// Starts an overlapped send of the data in a checked-out buffer on the given socket.
+	#[cfg(feature = "net")]
+	pub fn send(&self, socket: winapi::SOCKET, overlapped: PooledOverlapped) -> IocpResult<*mut winapi::OVERLAPPED> {
+		self.submit(socket as winapi::HANDLE, overlapped, false, |handle, buffer, overlapped| {
+			let mut buf = winapi::WSABUF {
+				len: buffer.len() as winapi::ULONG,
+				buf: buffer.as_mut_ptr() as *mut winapi::CHAR
+			};
+			let result = unsafe { ws2_32::WSASend(handle as winapi::SOCKET, &mut buf, 1, ptr::null_mut(), 0, overlapped, None) };
+			
+			if result == 0 { winapi::TRUE } else { winapi::FALSE }
+		})
+	}
+	/// Cancels the operations submitted through the pool on the given handle.
+	///
+	/// Their buffers are handed to the port, which returns them to the pool once it dequeues the
+	/// aborted packets, so those packets are never passed to `complete`. Returns the number of
+	/// operations that were cancelled.
+	pub fn cancel(&self, handle: winapi::HANDLE) -> usize {
+		let cancelled: Vec<Box<Slot>> = {
+			let mut in_flight = self.shared.in_flight.lock().unwrap();
+			let pointers: Vec<usize> = in_flight.iter().filter(|&(_, slot)| slot.handle == handle as usize).map(|(&pointer, _)| pointer).collect();
+			
+			pointers.iter().filter_map(|pointer| in_flight.remove(pointer)).collect()
+		};
+		let count = cancelled.len();
+		
+		for slot in cancelled {
+			let overlapped = slot.as_ptr();
+			
+			self.shared.port.inner.retire_and_cancel(handle, overlapped, Box::new(Returning {
+				slot: Some(slot),
+				pool: Arc::downgrade(&self.shared)
+			}));
+		}
+		
+		count
+	}
+	/// Takes back the buffer of the operation a dequeued packet completes.
+	///
+	/// Returns `None` if the packet does not belong to an operation submitted through this pool.
+	pub fn complete(&self, packet: &DequeueResult) -> Option<PooledCompletion> {
+		let result = match packet.byte_count() {
+			Some(result) => result,
+			None => return None
+		};
+		
+		let mut slot = match self.shared.in_flight.lock().unwrap().remove(&(packet.overlapped() as usize)) {
+			Some(slot) => slot,
+			None => return None
+		};
+		
+		if slot.reading {
+			slot.len = match result {
+				Ok(count) => cmp::min(count, slot.buffer.len()),
+				Err(_) => 0
+			};
+		}
+		
+		Some(PooledCompletion {
+			overlapped: PooledOverlapped {
+				slot: Some(slot),
+				pool: self.shared.clone()
+			},
+			result: result
+		})
+	}
+}
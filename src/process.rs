@@ -0,0 +1,510 @@
+//! Running many child processes multiplexed on one completion port.
+//!
+//! The output of every child is read through overlapped pipes associated with the port, and its
+//! exit is reported by a job object the pool runs its children in, so a single thread can drive
+//! any number of children.
+
+use std::{mem, process, ptr};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, IocpResult, IocpError};
+use job::JobNotification;
+
+use std::io::Error as IOError;
+
+static PIPE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The output stream of a child process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stream {
+	/// The standard output
+	Stdout,
+	/// The standard error
+	Stderr
+}
+
+/// Something that happened to a child process in a ProcessPool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProcessEvent {
+	/// The child was started
+	Started {
+		/// The job the child runs
+		job: usize,
+		/// The process ID of the child
+		pid: u32
+	},
+	/// The child could not be started
+	SpawnFailed {
+		/// The job that could not be started
+		job: usize,
+		/// The error that occurred
+		message: String
+	},
+	/// The child wrote to one of its output streams
+	Output {
+		/// The job the child runs
+		job: usize,
+		/// The stream that was written to
+		stream: Stream,
+		/// The data that was written
+		data: Vec<u8>
+	},
+	/// The child exited
+	Exited {
+		/// The job the child ran
+		job: usize,
+		/// The exit code, if there is one
+		code: Option<i32>
+	},
+	/// The child exited and all of its output has been delivered
+	Finished {
+		/// The job the child ran
+		job: usize
+	}
+}
+
+struct PipeReader {
+	overlapped: winapi::OVERLAPPED,
+	handle: winapi::HANDLE,
+	buffer: Vec<u8>,
+	pending: bool,
+	closed: bool
+}
+
+unsafe impl Send for PipeReader { }
+
+impl PipeReader {
+	fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+	/// Posts the next read, marking the pipe closed if the child's end is gone.
+	fn read(&mut self) -> IocpResult<()> {
+		self.overlapped = unsafe { mem::zeroed() };
+		
+		let len = self.buffer.len() as winapi::DWORD;
+		let overlapped = self.overlapped();
+		let read = unsafe { kernel32::ReadFile(self.handle, self.buffer.as_mut_ptr() as winapi::LPVOID, len, ptr::null_mut(), overlapped) };
+		
+		if read == 0 {
+			let error = IOError::last_os_error();
+			match error.raw_os_error() {
+				Some(code) if code == winapi::ERROR_IO_PENDING as i32 => { },
+				Some(code) if code == winapi::ERROR_BROKEN_PIPE as i32 => {
+					self.closed = true;
+					return Ok(());
+				},
+				_ => return Err(IocpError::HostError(error))
+			}
+		}
+		
+		self.pending = true;
+		
+		Ok(())
+	}
+}
+
+impl Drop for PipeReader {
+	fn drop(&mut self) {
+		if !self.handle.is_null() {
+			unsafe { let _ = kernel32::CloseHandle(self.handle); }
+		}
+	}
+}
+
+/// Closes the pipe of a reader, handing it to the port first if its read is still pending.
+fn retire(port: &IoCompletionPort, mut reader: Box<PipeReader>) {
+	if !reader.pending {
+		return;
+	}
+	
+	// The reader keeps the buffer alive, but not the handle, which is closed right away
+	let handle = mem::replace(&mut reader.handle, ptr::null_mut());
+	
+	let overlapped = reader.overlapped();
+	port.inner.retire_and_cancel(handle, overlapped, reader);
+	
+	unsafe { let _ = kernel32::CloseHandle(handle); }
+}
+
+struct Running {
+	child: Child,
+	stdout: Box<PipeReader>,
+	stderr: Box<PipeReader>,
+	exited: bool
+}
+
+impl Running {
+	fn reader(&mut self, stream: Stream) -> &mut PipeReader {
+		match stream {
+			Stream::Stdout => &mut self.stdout,
+			Stream::Stderr => &mut self.stderr
+		}
+	}
+	fn is_finished(&self) -> bool {
+		self.exited && self.stdout.closed && self.stderr.closed
+	}
+}
+
+/// Runs a bounded number of child processes at a time, delivering their activity as events.
+///
+/// Every packet the pool needs arrives with the completion key given to `new`. Pass each such
+/// packet, including failed ones, to `handle`, which turns it into a ProcessEvent and keeps the
+/// reads going. Queued commands start as soon as running children have finished.
+///
+/// The children run in a job object associated with the port, whose exit notifications report
+/// them exiting. The system does not guarantee that those are delivered, so a child whose output
+/// pipes have both closed is also checked directly.
+pub struct ProcessPool {
+	port: IoCompletionPort,
+	job_object: winapi::HANDLE,
+	completion_key: usize,
+	max_running: usize,
+	next_job: usize,
+	queue: VecDeque<(usize, Command)>,
+	running: HashMap<usize, Running>,
+	sources: HashMap<usize, (usize, Stream)>,
+	pids: HashMap<u32, usize>,
+	events: VecDeque<ProcessEvent>
+}
+
+unsafe impl Send for ProcessPool { }
+
+impl ProcessPool {
+	/// Creates a pool running at most `max_running` children at a time.
+	pub fn new(port: &IoCompletionPort, completion_key: usize, max_running: usize) -> IocpResult<ProcessPool> {
+		let job_object = unsafe { kernel32::CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+		
+		if job_object.is_null() {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		let pool = ProcessPool {
+			port: port.clone(),
+			job_object: job_object,
+			completion_key: completion_key,
+			max_running: if max_running == 0 { 1 } else { max_running },
+			next_job: 0,
+			queue: VecDeque::new(),
+			running: HashMap::new(),
+			sources: HashMap::new(),
+			pids: HashMap::new(),
+			events: VecDeque::new()
+		};
+		
+		// The pool tells the job's packets apart from its reads before looking at their pointers
+		try!(unsafe { port.associate_job(job_object, completion_key) });
+		
+		Ok(pool)
+	}
+	/// Queues a command, starting it immediately if there is a free slot.
+	///
+	/// The standard output and error of the command are replaced by pipes. Returns the job number
+	/// that identifies the command in events.
+	pub fn submit(&mut self, command: Command) -> usize {
+		let job = self.next_job;
+		self.next_job += 1;
+		
+		self.queue.push_back((job, command));
+		self.start_queued();
+		
+		job
+	}
+	/// Returns the number of running children.
+	pub fn running(&self) -> usize {
+		self.running.len()
+	}
+	/// Returns the number of commands waiting for a free slot.
+	pub fn queued(&self) -> usize {
+		self.queue.len()
+	}
+	/// Returns true when no child is running and no command is queued.
+	pub fn is_idle(&self) -> bool {
+		self.running.is_empty() && self.queue.is_empty()
+	}
+	/// Returns the next event that does not need a packet, such as a child being started.
+	pub fn next_event(&mut self) -> Option<ProcessEvent> {
+		self.events.pop_front()
+	}
+	/// Turns a dequeued packet into an event.
+	///
+	/// Returns `None` if the packet does not belong to this pool. Events that do not need a packet
+	/// are returned first; call `next_event` to drain the rest.
+	pub fn handle(&mut self, packet: &DequeueResult) -> Option<ProcessEvent> {
+		let (overlapped, byte_count, failed) = match *packet {
+			DequeueResult::Completed(ref status) => (status.overlapped, status.byte_count, false),
+			DequeueResult::Cancelled(ref status) | DequeueResult::FailedOperation { ref status, .. } => (status.overlapped, 0, true),
+			DequeueResult::TimedOut => return None
+		};
+		
+		if let Some(&(job, stream)) = self.sources.get(&(overlapped as usize)) {
+			self.output(job, stream, byte_count, failed);
+			return self.events.pop_front();
+		}
+		
+		// Anything else with the pool's key is a notification from the job, carrying a process ID
+		let notification = match *packet {
+			DequeueResult::Completed(ref status) if status.completion_key == self.completion_key => JobNotification::from(status),
+			_ => return None
+		};
+		
+		let job = match notification {
+			JobNotification::ExitProcess(pid) | JobNotification::AbnormalExitProcess(pid) => match self.pids.get(&pid) {
+				Some(&job) => job,
+				None => return None
+			},
+			_ => return None
+		};
+		
+		self.exited(job);
+		self.events.pop_front()
+	}
+	fn output(&mut self, job: usize, stream: Stream, byte_count: usize, failed: bool) {
+		let event = {
+			let running = match self.running.get_mut(&job) {
+				Some(running) => running,
+				None => return
+			};
+			let reader = running.reader(stream);
+			reader.pending = false;
+			
+			// A failed read means the child closed its end of the pipe
+			if failed {
+				reader.closed = true;
+				None
+			} else {
+				let data = reader.buffer[..byte_count].to_vec();
+				if let Err(_) = reader.read() {
+					reader.closed = true;
+				}
+				Some(ProcessEvent::Output {
+					job: job,
+					stream: stream,
+					data: data
+				})
+			}
+		};
+		
+		if let Some(event) = event {
+			self.events.push_back(event);
+		}
+		self.finish_if_done(job);
+	}
+	fn exited(&mut self, job: usize) {
+		let code = match self.running.get_mut(&job) {
+			Some(running) if !running.exited => {
+				running.exited = true;
+				// The process has exited, so this does not block
+				running.child.wait().ok().and_then(|status| status.code())
+			},
+			Some(_) => return,
+			None => return
+		};
+		
+		self.events.push_back(ProcessEvent::Exited {
+			job: job,
+			code: code
+		});
+		self.finish_if_done(job);
+	}
+	fn finish_if_done(&mut self, job: usize) {
+		// Job notifications can be lost, so a child that has closed its output is asked directly
+		let exited_unnoticed = match self.running.get_mut(&job) {
+			Some(running) => !running.exited && running.stdout.closed && running.stderr.closed && running.child.try_wait().ok().map_or(false, |status| status.is_some()),
+			None => false
+		};
+		
+		if exited_unnoticed {
+			return self.exited(job);
+		}
+		
+		let finished = self.running.get(&job).map(|running| running.is_finished()).unwrap_or(false);
+		
+		if finished {
+			if let Some(running) = self.running.remove(&job) {
+				self.sources.remove(&(running.stdout.overlapped() as usize));
+				self.sources.remove(&(running.stderr.overlapped() as usize));
+				self.pids.remove(&running.child.id());
+			}
+			self.events.push_back(ProcessEvent::Finished {
+				job: job
+			});
+			self.start_queued();
+		}
+	}
+	fn start_queued(&mut self) {
+		while self.running.len() < self.max_running {
+			let (job, command) = match self.queue.pop_front() {
+				Some(queued) => queued,
+				None => break
+			};
+			
+			match self.start(job, command) {
+				Ok((pid, exited)) => {
+					self.events.push_back(ProcessEvent::Started {
+						job: job,
+						pid: pid
+					});
+					if exited {
+						self.exited(job);
+					}
+				},
+				Err(error) => self.events.push_back(ProcessEvent::SpawnFailed {
+					job: job,
+					message: format!("{}", error)
+				})
+			}
+		}
+	}
+	/// Starts a command, returning the process ID of the child and whether it already exited.
+	fn start(&mut self, job: usize, mut command: Command) -> IocpResult<(u32, bool)> {
+		let (stdout_read, stdout_write) = try!(pipe());
+		let (stderr_read, stderr_write) = match pipe() {
+			Ok(pair) => pair,
+			Err(error) => {
+				unsafe {
+					let _ = kernel32::CloseHandle(stdout_read);
+					let _ = kernel32::CloseHandle(stdout_write);
+				}
+				return Err(error);
+			}
+		};
+		
+		let mut stdout = Box::new(PipeReader {
+			overlapped: unsafe { mem::zeroed() },
+			handle: stdout_read,
+			buffer: vec![0; 4096],
+			pending: false,
+			closed: false
+		});
+		let mut stderr = Box::new(PipeReader {
+			overlapped: unsafe { mem::zeroed() },
+			handle: stderr_read,
+			buffer: vec![0; 4096],
+			pending: false,
+			closed: false
+		});
+		
+		command.stdout(unsafe { Stdio::from_raw_handle(stdout_write as _) });
+		command.stderr(unsafe { Stdio::from_raw_handle(stderr_write as _) });
+		
+		// Dropping the command closes our copies of the write ends, so the reads see the end of the pipe
+		let spawned = command.spawn();
+		drop(command);
+		let mut child = try!(spawned.map_err(IocpError::HostError));
+		
+		// A process that exits before it is assigned never shows up in the job's notifications
+		let assigned = unsafe { kernel32::AssignProcessToJobObject(self.job_object, child.as_raw_handle() as winapi::HANDLE) };
+		let exited = if assigned == 0 {
+			let error = IOError::last_os_error();
+			match child.try_wait() {
+				Ok(Some(_)) => true,
+				_ => {
+					let _ = child.kill();
+					let _ = child.wait();
+					return Err(IocpError::HostError(error));
+				}
+			}
+		} else {
+			false
+		};
+		
+		let started = self.port.associate(stdout.handle, self.completion_key)
+			.and_then(|_| self.port.associate(stderr.handle, self.completion_key))
+			.and_then(|_| stdout.read())
+			.and_then(|_| stderr.read());
+		
+		if let Err(error) = started {
+			retire(&self.port, stdout);
+			retire(&self.port, stderr);
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(error);
+		}
+		
+		let pid = child.id();
+		let running = Running {
+			child: child,
+			stdout: stdout,
+			stderr: stderr,
+			exited: false
+		};
+		
+		self.sources.insert(running.stdout.overlapped() as usize, (job, Stream::Stdout));
+		self.sources.insert(running.stderr.overlapped() as usize, (job, Stream::Stderr));
+		self.pids.insert(pid, job);
+		self.running.insert(job, running);
+		
+		Ok((pid, exited))
+	}
+}
+
+impl Drop for ProcessPool {
+	fn drop(&mut self) {
+		for (_, mut running) in self.running.drain() {
+			let _ = running.child.kill();
+			let _ = running.child.wait();
+			
+			retire(&self.port, running.stdout);
+			retire(&self.port, running.stderr);
+		}
+		
+		unsafe { let _ = kernel32::CloseHandle(self.job_object); }
+	}
+}
+
+/// Creates a pipe whose read end is overlapped, returning the read and write ends.
+fn pipe() -> IocpResult<(winapi::HANDLE, winapi::HANDLE)> {
+	let name = format!(r"\\.\pipe\iocp-process-{}-{}", process::id(), PIPE_COUNTER.fetch_add(1, Ordering::SeqCst));
+	let name: Vec<u16> = OsStr::new(&name).encode_wide().chain(Some(0)).collect();
+	
+	let read = unsafe {
+		kernel32::CreateNamedPipeW(
+			name.as_ptr(),
+			winapi::PIPE_ACCESS_INBOUND | winapi::FILE_FLAG_OVERLAPPED | winapi::FILE_FLAG_FIRST_PIPE_INSTANCE,
+			winapi::PIPE_TYPE_BYTE | winapi::PIPE_READMODE_BYTE | winapi::PIPE_WAIT,
+			1,
+			4096,
+			4096,
+			0,
+			ptr::null_mut()
+		)
+	};
+	
+	if read == winapi::INVALID_HANDLE_VALUE {
+		return Err(
+			IocpError::HostError(IOError::last_os_error())
+		);
+	}
+	
+	let write = unsafe {
+		kernel32::CreateFileW(
+			name.as_ptr(),
+			winapi::GENERIC_WRITE,
+			0,
+			ptr::null_mut(),
+			winapi::OPEN_EXISTING,
+			0,
+			ptr::null_mut()
+		)
+	};
+	
+	if write == winapi::INVALID_HANDLE_VALUE {
+		let error = IOError::last_os_error();
+		unsafe { let _ = kernel32::CloseHandle(read); }
+		return Err(
+			IocpError::HostError(error)
+		);
+	}
+	
+	Ok((read, write))
+}
@@ -0,0 +1,105 @@
+//! Completion keys that stand for shared values instead of raw pointers.
+//!
+//! Code that casts a pointer to its connection state into the completion key has to keep the
+//! state alive until the last packet carrying that key is dequeued. A Registry hands out keys
+//! that are looked up instead, so a packet arriving after its value was deregistered finds nothing
+//! rather than freed memory.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use winapi;
+
+use {IoCompletionPort, DequeueResult, IocpResult};
+
+/// A completion key handed out by a Registry.
+///
+/// Keys are never reused by the registry that handed them out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token(usize);
+
+impl Token {
+	/// Returns the completion key the token stands for.
+	pub fn key(&self) -> usize {
+		self.0
+	}
+}
+
+/// Maps the completion keys of a port to shared values.
+///
+/// Every key used on the port should come from the registry, since packets with any other key
+/// are returned without a value.
+pub struct Registry<T> {
+	port: IoCompletionPort,
+	values: RwLock<HashMap<usize, Arc<T>>>,
+	next: AtomicUsize
+}
+
+impl<T> Registry<T> {
+	/// Creates an empty registry for the given port.
+	pub fn new(port: &IoCompletionPort) -> Registry<T> {
+		Registry {
+			port: port.clone(),
+			values: RwLock::new(HashMap::new()),
+			next: AtomicUsize::new(1)
+		}
+	}
+	/// Returns the port the registry hands out keys for.
+	pub fn port(&self) -> &IoCompletionPort {
+		&self.port
+	}
+	/// Returns the number of registered values.
+	pub fn len(&self) -> usize {
+		self.values.read().unwrap().len()
+	}
+	/// Returns true if no values are registered.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	/// Registers a value without associating a handle, for packets posted with `post_queued`.
+	pub fn insert(&self, value: Arc<T>) -> Token {
+		let key = self.next.fetch_add(1, Ordering::SeqCst);
+		self.values.write().unwrap().insert(key, value);
+		
+		Token(key)
+	}
+	/// Associates the given file handle with the port, with a fresh key standing for the value.
+	///
+	/// Nothing is registered if the association fails.
+	pub fn associate_token(&self, handle: winapi::HANDLE, value: Arc<T>) -> IocpResult<Token> {
+		let key = self.next.fetch_add(1, Ordering::SeqCst);
+		
+		try!(self.port.associate(handle, key));
+		self.values.write().unwrap().insert(key, value);
+		
+		Ok(Token(key))
+	}
+	/// Removes the value a token stands for, returning it if it was still registered.
+	///
+	/// The handle stays associated with the port, but packets still carrying the token's key are
+	/// returned without a value from now on.
+	pub fn deregister(&self, token: Token) -> Option<Arc<T>> {
+		self.values.write().unwrap().remove(&token.0)
+	}
+	/// Returns the value a token stands for.
+	pub fn get(&self, token: Token) -> Option<Arc<T>> {
+		self.values.read().unwrap().get(&token.0).cloned()
+	}
+	/// Returns the value standing for the completion key of a dequeued packet.
+	///
+	/// Returns `None` if the wait timed out or the key is not registered.
+	pub fn lookup(&self, packet: &DequeueResult) -> Option<Arc<T>> {
+		packet.status().and_then(|status| self.values.read().unwrap().get(&status.completion_key).cloned())
+	}
+	/// Dequeues a packet from the port along with the value standing for its completion key.
+	///
+	/// Behaves like `IoCompletionPort::get_queued`.
+	pub fn get_queued(&self, timeout: Option<Duration>) -> IocpResult<(DequeueResult, Option<Arc<T>>)> {
+		let packet = try!(self.port.get_queued(timeout));
+		let value = self.lookup(&packet);
+		
+		Ok((packet, value))
+	}
+}
@@ -0,0 +1,66 @@
+//! Serde support for the packet types.
+//!
+//! The OVERLAPPED pointer of a CompletionStatus is written as an integer. It is only meaningful
+//! inside the process it came from, so a deserialized pointer must not be dereferenced.
+//!
+//! The crate has no metrics snapshot or trace recorder to serialize. The counters it does keep,
+//! `batch::BatchStats`, `fault::FaultCounts`, `net::TcpInfo` and `net::TcpSendLimits`, derive the
+//! serde traits in their own modules.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+use CompletionStatus;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "CompletionStatus")]
+struct RawCompletionStatus {
+	byte_count: usize,
+	completion_key: usize,
+	overlapped: usize
+}
+
+impl Serialize for CompletionStatus {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		RawCompletionStatus {
+			byte_count: self.byte_count,
+			completion_key: self.completion_key,
+			overlapped: self.overlapped as usize
+		}.serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for CompletionStatus {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CompletionStatus, D::Error> {
+		let raw = try!(RawCompletionStatus::deserialize(deserializer));
+		
+		Ok(CompletionStatus {
+			byte_count: raw.byte_count,
+			completion_key: raw.completion_key,
+			overlapped: raw.overlapped as *mut ::winapi::OVERLAPPED
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json;
+	
+	use CompletionStatus;
+	
+	#[test]
+	fn overlapped_round_trips_as_an_integer() {
+		let status = CompletionStatus {
+			byte_count: 512,
+			completion_key: 7,
+			overlapped: 0x1000 as *mut ::winapi::OVERLAPPED
+		};
+		
+		let json = serde_json::to_string(&status).unwrap();
+		assert_eq!(json, r#"{"byte_count":512,"completion_key":7,"overlapped":4096}"#);
+		
+		let decoded: CompletionStatus = serde_json::from_str(&json).unwrap();
+		assert_eq!(decoded.byte_count, 512);
+		assert_eq!(decoded.completion_key, 7);
+		assert_eq!(decoded.overlapped, status.overlapped);
+	}
+}
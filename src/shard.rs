@@ -0,0 +1,259 @@
+//! Spreads handles over one I/O completion port per processor.
+//!
+//! A single shared port serialises its waiters on an internal lock. When that lock becomes the
+//! bottleneck, giving every core its own port and its own pinned worker removes the contention.
+
+use std::{mem, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+/// Chooses which shard a newly assigned handle is associated with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+	/// Handles are handed to each shard in turn.
+	RoundRobin,
+	/// Handles are handed to the shard with the fewest assigned handles.
+	LeastLoaded
+}
+
+/// A set of I/O completion ports, one per processor, each intended to be served by a single worker.
+pub struct ShardedPorts {
+	ports: Vec<IoCompletionPort>,
+	loads: Vec<AtomicUsize>,
+	next: AtomicUsize,
+	placement: Placement
+}
+
+static STOP: u8 = 0;
+
+/// The OVERLAPPED pointer carried by the packet that tells a worker to exit.
+fn stop_marker() -> *mut winapi::OVERLAPPED {
+	&STOP as *const u8 as *mut winapi::OVERLAPPED
+}
+
+/// Returns the number of logical processors in the system.
+pub fn processor_count() -> usize {
+	let mut info: winapi::SYSTEM_INFO = unsafe { mem::zeroed() };
+	unsafe { kernel32::GetSystemInfo(&mut info) };
+	
+	if info.dwNumberOfProcessors == 0 { 1 } else { info.dwNumberOfProcessors as usize }
+}
+
+impl ShardedPorts {
+	/// Creates one port per logical processor.
+	pub fn new(placement: Placement) -> IocpResult<ShardedPorts> {
+		ShardedPorts::with_shards(processor_count(), placement)
+	}
+	/// Creates the specified number of ports.
+	///
+	/// Each port allows a single concurrently running thread. Fails with ERROR_INVALID_PARAMETER
+	/// if `shards` is zero.
+	pub fn with_shards(shards: usize, placement: Placement) -> IocpResult<ShardedPorts> {
+		if shards == 0 {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_INVALID_PARAMETER as i32))
+			);
+		}
+		
+		let mut ports = Vec::with_capacity(shards);
+		let mut loads = Vec::with_capacity(shards);
+		
+		for _ in 0..shards {
+			ports.push(try!(IoCompletionPort::new(1)));
+			loads.push(AtomicUsize::new(0));
+		}
+		
+		Ok(ShardedPorts {
+			ports: ports,
+			loads: loads,
+			next: AtomicUsize::new(0),
+			placement: placement
+		})
+	}
+	/// Returns the number of shards.
+	pub fn len(&self) -> usize {
+		self.ports.len()
+	}
+	/// Returns true if there are no shards.
+	pub fn is_empty(&self) -> bool {
+		self.ports.is_empty()
+	}
+	/// Returns the port belonging to the given shard.
+	pub fn port(&self, shard: usize) -> &IoCompletionPort {
+		&self.ports[shard]
+	}
+	/// Returns the number of handles currently assigned to the given shard.
+	pub fn load(&self, shard: usize) -> usize {
+		self.loads[shard].load(Ordering::SeqCst)
+	}
+	/// Associates the given file handle with one of the shards.
+	///
+	/// Returns the index of the shard the handle was associated with.
+	pub fn assign(&self, handle: winapi::HANDLE, completion_key: usize) -> IocpResult<usize> {
+		let shard = self.choose();
+		
+		try!(self.ports[shard].associate(handle, completion_key));
+		self.loads[shard].fetch_add(1, Ordering::SeqCst);
+		
+		Ok(shard)
+	}
+	/// Records that a handle assigned to the given shard has been closed.
+	///
+	/// This only affects the LeastLoaded placement.
+	pub fn release(&self, shard: usize) {
+		let _ = self.loads[shard].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |load| load.checked_sub(1));
+	}
+	fn choose(&self) -> usize {
+		match self.placement {
+			Placement::RoundRobin => self.next.fetch_add(1, Ordering::SeqCst) % self.ports.len(),
+			Placement::LeastLoaded => {
+				let mut best = 0;
+				for (shard, load) in self.loads.iter().enumerate() {
+					if load.load(Ordering::SeqCst) < self.loads[best].load(Ordering::SeqCst) {
+						best = shard;
+					}
+				}
+				best
+			}
+		}
+	}
+	/// Spawns one worker thread per shard, each pinned to the processor matching its shard index.
+	///
+	/// Every dequeued packet is passed to the handler along with the shard it came from, including
+	/// those of failed I/O operations. A worker exits when `stop_workers` is called or when waiting
+	/// on its port fails.
+	pub fn spawn_workers<F>(&self, handler: F) -> Vec<JoinHandle<()>>
+		where F: Fn(usize, DequeueResult) + Send + Sync + 'static
+	{
+		spawn_workers(&self.ports, handler)
+	}
+	/// Tells every worker spawned by `spawn_workers` to exit once it has handled the packets queued before it.
+	pub fn stop_workers(&self) -> IocpResult<()> {
+		stop_workers(&self.ports)
+	}
+}
+
+/// A set of I/O completion ports where each completion key is always routed to the same shard.
+///
+/// Since all packets for one key land on the same port, state belonging to a single connection
+/// is only ever touched by the worker serving that port and needs no locking.
+pub struct PortGroup {
+	ports: Vec<IoCompletionPort>
+}
+
+impl PortGroup {
+	/// Creates one port per logical processor.
+	pub fn new() -> IocpResult<PortGroup> {
+		PortGroup::with_shards(processor_count())
+	}
+	/// Creates the specified number of ports.
+	///
+	/// Each port allows a single concurrently running thread. Fails with ERROR_INVALID_PARAMETER
+	/// if `shards` is zero.
+	pub fn with_shards(shards: usize) -> IocpResult<PortGroup> {
+		if shards == 0 {
+			return Err(
+				IocpError::HostError(IOError::from_raw_os_error(winapi::ERROR_INVALID_PARAMETER as i32))
+			);
+		}
+		
+		let mut ports = Vec::with_capacity(shards);
+		
+		for _ in 0..shards {
+			ports.push(try!(IoCompletionPort::new(1)));
+		}
+		
+		Ok(PortGroup {
+			ports: ports
+		})
+	}
+	/// Returns the number of shards.
+	pub fn len(&self) -> usize {
+		self.ports.len()
+	}
+	/// Returns true if there are no shards.
+	pub fn is_empty(&self) -> bool {
+		self.ports.is_empty()
+	}
+	/// Returns the port belonging to the given shard.
+	pub fn port(&self, shard: usize) -> &IoCompletionPort {
+		&self.ports[shard]
+	}
+	/// Returns the shard the given completion key is routed to.
+	pub fn shard_for(&self, completion_key: usize) -> usize {
+		let hash = (completion_key as u64).wrapping_mul(0x9E3779B97F4A7C15) >> 32;
+		(hash % self.ports.len() as u64) as usize
+	}
+	/// Returns the port the given completion key is routed to.
+	pub fn port_for(&self, completion_key: usize) -> &IoCompletionPort {
+		&self.ports[self.shard_for(completion_key)]
+	}
+	/// Associates the given file handle with the shard its completion key is routed to.
+	///
+	/// Returns the index of the shard the handle was associated with.
+	pub fn associate(&self, handle: winapi::HANDLE, completion_key: usize) -> IocpResult<usize> {
+		let shard = self.shard_for(completion_key);
+		try!(self.ports[shard].associate(handle, completion_key));
+		Ok(shard)
+	}
+	/// Posts an I/O completion packet to the shard its completion key is routed to.
+	pub fn post_queued(&self, packet: CompletionStatus) -> IocpResult<()> {
+		self.port_for(packet.completion_key).post_queued(packet)
+	}
+	/// Spawns one worker thread per shard, each pinned to the processor matching its shard index.
+	///
+	/// Behaves like `ShardedPorts::spawn_workers`.
+	pub fn spawn_workers<F>(&self, handler: F) -> Vec<JoinHandle<()>>
+		where F: Fn(usize, DequeueResult) + Send + Sync + 'static
+	{
+		spawn_workers(&self.ports, handler)
+	}
+	/// Tells every worker spawned by `spawn_workers` to exit once it has handled the packets queued before it.
+	pub fn stop_workers(&self) -> IocpResult<()> {
+		stop_workers(&self.ports)
+	}
+}
+
+fn spawn_workers<F>(ports: &[IoCompletionPort], handler: F) -> Vec<JoinHandle<()>>
+	where F: Fn(usize, DequeueResult) + Send + Sync + 'static
+{
+	let handler = Arc::new(handler);
+	
+	ports.iter().enumerate().map(|(shard, port)| {
+		let port = port.clone();
+		let handler = handler.clone();
+		
+		thread::spawn(move || {
+			let mask = 1usize << (shard % (mem::size_of::<usize>() * 8));
+			unsafe { kernel32::SetThreadAffinityMask(kernel32::GetCurrentThread(), mask as winapi::DWORD_PTR) };
+			
+			loop {
+				match port.get_queued(None) {
+					Ok(ref result) if result.overlapped() == stop_marker() => break,
+					Ok(result) => handler(shard, result),
+					Err(_) => break
+				}
+			}
+		})
+	}).collect()
+}
+
+fn stop_workers(ports: &[IoCompletionPort]) -> IocpResult<()> {
+	for port in ports.iter() {
+		try!(port.post_queued(CompletionStatus {
+			byte_count: 0,
+			completion_key: 0,
+			overlapped: stop_marker()
+		}));
+	}
+	
+	Ok(())
+}
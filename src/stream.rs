@@ -0,0 +1,442 @@
+//! futures-io `AsyncRead`, `AsyncWrite` and `AsyncSeek` for the crate's stream types.
+//!
+//! An AsyncStream drives a file, pipe or socket through a WakerTable. The thread dequeuing packets
+//! hands them to the table, which wakes the task polling the stream, so the stream can be used with
+//! the codecs and combinators built on futures-io.
+
+use std::{cmp, io};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use futures_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "fs")]
+use futures_io::AsyncSeek;
+#[cfg(feature = "fs")]
+use std::io::SeekFrom;
+
+use winapi;
+
+use {DequeueResult, Issued, IocpResult};
+use waker::WakerTable;
+
+#[cfg(feature = "fs")]
+use std::io::Error as IOError;
+
+/// A stream type with one read and one write in flight at a time that an AsyncStream can drive.
+pub trait CompletionStream {
+	/// Starts reading up to `len` bytes, returning the data if the read finished inline.
+	///
+	/// An empty buffer means the end of the stream. The offset is only used by files.
+	fn start_read(&mut self, len: usize, offset: u64) -> IocpResult<Issued<Vec<u8>>>;
+	/// Returns the data read by the pending read if the packet completes it.
+	fn finish_read(&mut self, packet: &DequeueResult) -> Option<IocpResult<Vec<u8>>>;
+	/// Starts writing the data, returning the number of bytes written if the write finished inline.
+	///
+	/// The offset is only used by files.
+	fn start_write(&mut self, data: &[u8], offset: u64) -> IocpResult<Issued<usize>>;
+	/// Returns the number of bytes written by the pending write if the packet completes it.
+	fn finish_write(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>>;
+	/// Tells the other end that no more data follows.
+	fn close(&mut self) -> IocpResult<()> {
+		Ok(())
+	}
+}
+
+/// A stream whose completions are delivered through a WakerTable, usable as a futures-io stream.
+///
+/// Every packet carrying the stream's completion key must be handed to the table with
+/// `WakerTable::dispatch`, so the key must not be used by anything else. Packets completing
+/// neither the pending read nor the pending write are discarded. A write whose future is polled
+/// again after the data changed still reports the outcome of the data it was started with.
+///
+/// Files keep a position that reads and writes advance and that can be moved with `AsyncSeek`.
+pub struct AsyncStream<S: CompletionStream> {
+	inner: S,
+	table: Arc<WakerTable>,
+	completion_key: usize,
+	position: u64,
+	reading: Option<*mut winapi::OVERLAPPED>,
+	read: Option<IocpResult<Vec<u8>>>,
+	read_buffer: Vec<u8>,
+	read_offset: usize,
+	read_waker: Option<Waker>,
+	writing: Option<*mut winapi::OVERLAPPED>,
+	written: Option<IocpResult<usize>>,
+	write_waker: Option<Waker>
+}
+
+unsafe impl<S: CompletionStream + Send> Send for AsyncStream<S> { }
+
+impl<S: CompletionStream> AsyncStream<S> {
+	/// Wraps a stream whose packets carry the given completion key and are dispatched to the table.
+	pub fn new(inner: S, table: &Arc<WakerTable>, completion_key: usize) -> AsyncStream<S> {
+		AsyncStream {
+			inner: inner,
+			table: table.clone(),
+			completion_key: completion_key,
+			position: 0,
+			reading: None,
+			read: None,
+			read_buffer: Vec::new(),
+			read_offset: 0,
+			read_waker: None,
+			writing: None,
+			written: None,
+			write_waker: None
+		}
+	}
+	/// Returns the wrapped stream.
+	pub fn get_ref(&self) -> &S {
+		&self.inner
+	}
+	/// Returns the wrapped stream for modification.
+	///
+	/// Starting operations on it directly confuses the AsyncStream.
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+	/// Takes the packets the table holds for the stream, registering the waker if there are none.
+	fn drain(&mut self, waker: &Waker) {
+		while let Poll::Ready(packet) = self.table.poll_key(self.completion_key, waker) {
+			self.route(&packet);
+		}
+	}
+	/// Hands a packet to the pending read or write it completes, waking the task waiting for it.
+	fn route(&mut self, packet: &DequeueResult) {
+		let overlapped = packet.overlapped();
+		
+		if self.reading == Some(overlapped) {
+			if let Some(result) = self.inner.finish_read(packet) {
+				self.reading = None;
+				self.read = Some(result);
+				if let Some(waker) = self.read_waker.take() {
+					waker.wake();
+				}
+			}
+		} else if self.writing == Some(overlapped) {
+			if let Some(result) = self.inner.finish_write(packet) {
+				self.writing = None;
+				self.written = Some(result);
+				if let Some(waker) = self.write_waker.take() {
+					waker.wake();
+				}
+			}
+		}
+	}
+	/// Copies the data left over from the last read into the buffer.
+	fn copy_read(&mut self, buf: &mut [u8]) -> usize {
+		let count = cmp::min(buf.len(), self.read_buffer.len() - self.read_offset);
+		
+		buf[..count].copy_from_slice(&self.read_buffer[self.read_offset..self.read_offset + count]);
+		self.read_offset += count;
+		self.position += count as u64;
+		
+		count
+	}
+}
+
+impl<S: CompletionStream + Unpin> AsyncRead for AsyncStream<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		this.read_waker = Some(cx.waker().clone());
+		
+		loop {
+			if this.read_offset < this.read_buffer.len() {
+				return Poll::Ready(Ok(this.copy_read(buf)));
+			}
+			
+			this.drain(cx.waker());
+			
+			match this.read.take() {
+				Some(Ok(data)) => {
+					if data.is_empty() {
+						return Poll::Ready(Ok(0));
+					}
+					this.read_buffer = data;
+					this.read_offset = 0;
+					continue;
+				},
+				Some(Err(error)) => return Poll::Ready(Err(error.into())),
+				None => { }
+			}
+			
+			if this.reading.is_some() {
+				return Poll::Pending;
+			}
+			if buf.is_empty() {
+				return Poll::Ready(Ok(0));
+			}
+			
+			match this.inner.start_read(buf.len(), this.position) {
+				Ok(Issued::Inline(data)) => this.read = Some(Ok(data)),
+				Ok(Issued::Pending(overlapped)) => this.reading = Some(overlapped),
+				Err(error) => return Poll::Ready(Err(error.into()))
+			}
+		}
+	}
+}
+
+impl<S: CompletionStream + Unpin> AsyncWrite for AsyncStream<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		this.write_waker = Some(cx.waker().clone());
+		
+		loop {
+			this.drain(cx.waker());
+			
+			match this.written.take() {
+				Some(Ok(count)) => {
+					this.position += count as u64;
+					return Poll::Ready(Ok(count));
+				},
+				Some(Err(error)) => return Poll::Ready(Err(error.into())),
+				None => { }
+			}
+			
+			if this.writing.is_some() {
+				return Poll::Pending;
+			}
+			if buf.is_empty() {
+				return Poll::Ready(Ok(0));
+			}
+			
+			match this.inner.start_write(buf, this.position) {
+				Ok(Issued::Inline(count)) => this.written = Some(Ok(count)),
+				Ok(Issued::Pending(overlapped)) => this.writing = Some(overlapped),
+				Err(error) => return Poll::Ready(Err(error.into()))
+			}
+		}
+	}
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		this.write_waker = Some(cx.waker().clone());
+		
+		this.drain(cx.waker());
+		
+		// The outcome of a write nobody waited for is only reported by flushing
+		match this.written.take() {
+			Some(Ok(count)) => this.position += count as u64,
+			Some(Err(error)) => return Poll::Ready(Err(error.into())),
+			None => { }
+		}
+		
+		if this.writing.is_some() {
+			return Poll::Pending;
+		}
+		
+		Poll::Ready(Ok(()))
+	}
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		match self.as_mut().poll_flush(cx) {
+			Poll::Ready(Ok(())) => { },
+			other => return other
+		}
+		
+		Poll::Ready(self.get_mut().inner.close().map_err(|error| error.into()))
+	}
+}
+
+#[cfg(feature = "fs")]
+impl AsyncSeek for AsyncStream<::fs::AsyncFile> {
+	/// Moves the position of the file.
+	///
+	/// Fails with an error of kind `Other` while a read or write is in flight, which only happens
+	/// after its future was dropped. Data left over from the last read is discarded.
+	fn poll_seek(self: Pin<&mut Self>, cx: &mut Context, pos: SeekFrom) -> Poll<io::Result<u64>> {
+		let this = self.get_mut();
+		
+		this.drain(cx.waker());
+		
+		if this.reading.is_some() || this.writing.is_some() {
+			return Poll::Ready(Err(IOError::new(io::ErrorKind::Other, "cannot seek while an operation is in flight")));
+		}
+		
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::Current(delta) => offset(this.position, delta),
+			SeekFrom::End(delta) => match this.inner.size() {
+				Ok(size) => offset(size, delta),
+				Err(error) => return Poll::Ready(Err(error.into()))
+			}
+		};
+		
+		match position {
+			Some(position) => {
+				this.position = position;
+				this.read = None;
+				this.read_buffer.clear();
+				this.read_offset = 0;
+				Poll::Ready(Ok(position))
+			},
+			None => Poll::Ready(Err(IOError::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")))
+		}
+	}
+}
+
+#[cfg(feature = "fs")]
+fn offset(base: u64, delta: i64) -> Option<u64> {
+	if delta < 0 {
+		base.checked_sub(delta.unsigned_abs())
+	} else {
+		base.checked_add(delta as u64)
+	}
+}
+
+impl<S: CompletionStream> Drop for AsyncStream<S> {
+	fn drop(&mut self) {
+		// Packets the table already holds have left the port, so the stream has to take them back itself
+		self.drain(Waker::noop());
+		self.table.deregister_key(self.completion_key);
+	}
+}
+
+#[cfg(feature = "fs")]
+mod file {
+	use winapi;
+	
+	use {DequeueResult, Issued, IocpResult};
+	use fs::{AsyncFile, Completed};
+	use super::CompletionStream;
+	
+	fn data(completed: Completed) -> IocpResult<Vec<u8>> {
+		let Completed { buffer, result, .. } = completed;
+		
+		result.map(move |count| {
+			let mut buffer = buffer;
+			buffer.truncate(count);
+			buffer
+		})
+	}
+
+	impl CompletionStream for AsyncFile {
+		fn start_read(&mut self, len: usize, offset: u64) -> IocpResult<Issued<Vec<u8>>> {
+			match self.read_at(vec![0; len], offset) {
+				Ok(Issued::Inline(completed)) => data(completed).map(Issued::Inline),
+				Ok(Issued::Pending(overlapped)) => Ok(Issued::Pending(overlapped)),
+				Err(ref error) if error.raw_os_error() == Some(winapi::ERROR_HANDLE_EOF as i32) => Ok(Issued::Inline(Vec::new())),
+				Err(error) => Err(error)
+			}
+		}
+		fn finish_read(&mut self, packet: &DequeueResult) -> Option<IocpResult<Vec<u8>>> {
+			self.complete(packet).map(data)
+		}
+		fn start_write(&mut self, data: &[u8], offset: u64) -> IocpResult<Issued<usize>> {
+			match try!(self.write_at(data.to_vec(), offset)) {
+				Issued::Inline(completed) => completed.result.map(Issued::Inline),
+				Issued::Pending(overlapped) => Ok(Issued::Pending(overlapped))
+			}
+		}
+		fn finish_write(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+			self.complete(packet).map(|completed| completed.result)
+		}
+	}
+}
+
+#[cfg(feature = "pipe")]
+mod pipe {
+	use {DequeueResult, Issued, IocpResult};
+	use pipe::AsyncNamedPipe;
+	use super::CompletionStream;
+	
+	impl CompletionStream for AsyncNamedPipe {
+		fn start_read(&mut self, len: usize, _: u64) -> IocpResult<Issued<Vec<u8>>> {
+			self.read(len).map(|issued| issued.map(|data| data.to_vec()))
+		}
+		fn finish_read(&mut self, packet: &DequeueResult) -> Option<IocpResult<Vec<u8>>> {
+			self.read_data(packet).map(|result| result.map(|data| data.to_vec()))
+		}
+		fn start_write(&mut self, data: &[u8], _: u64) -> IocpResult<Issued<usize>> {
+			self.write(data)
+		}
+		fn finish_write(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+			self.written(packet)
+		}
+	}
+}
+
+#[cfg(feature = "net")]
+mod tcp {
+	use {DequeueResult, Issued, IocpResult};
+	use net::AsyncTcpStream;
+	use super::CompletionStream;
+	
+	impl CompletionStream for AsyncTcpStream {
+		fn start_read(&mut self, len: usize, _: u64) -> IocpResult<Issued<Vec<u8>>> {
+			self.set_recv_buffer_size(len);
+			self.recv().map(|issued| issued.map(|data| data.to_vec()))
+		}
+		fn finish_read(&mut self, packet: &DequeueResult) -> Option<IocpResult<Vec<u8>>> {
+			self.received(packet).map(|result| result.map(|data| data.to_vec()))
+		}
+		fn start_write(&mut self, data: &[u8], _: u64) -> IocpResult<Issued<usize>> {
+			self.send(data)
+		}
+		fn finish_write(&mut self, packet: &DequeueResult) -> Option<IocpResult<usize>> {
+			self.sent(packet)
+		}
+		fn close(&mut self) -> IocpResult<()> {
+			self.shutdown_send()
+		}
+	}
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+	use std::{env, fs, process};
+	use std::fs::OpenOptions;
+	use std::io::SeekFrom;
+	use std::pin::Pin;
+	use std::sync::Arc;
+	use std::task::{Context, Poll, Waker};
+	use std::time::Duration;
+	
+	use futures_io::{AsyncRead, AsyncSeek, AsyncWrite};
+	
+	use IoCompletionPort;
+	use fs::AsyncFile;
+	use waker::WakerTable;
+	use super::AsyncStream;
+	
+	/// Polls until the operation is ready, dispatching dequeued packets to the table in between.
+	fn run<T, F>(port: &IoCompletionPort, table: &WakerTable, mut poll: F) -> T
+		where F: FnMut(&mut Context) -> Poll<T>
+	{
+		let mut cx = Context::from_waker(Waker::noop());
+		
+		loop {
+			if let Poll::Ready(value) = poll(&mut cx) {
+				return value;
+			}
+			
+			let packet = port.get_queued(Some(Duration::from_secs(5))).unwrap();
+			assert!(table.dispatch(packet).is_none());
+		}
+	}
+
+	#[test]
+	fn file_round_trip() {
+		let path = env::temp_dir().join(format!("iocp-stream-{}", process::id()));
+		let port = IoCompletionPort::new(1).unwrap();
+		let table = Arc::new(WakerTable::new());
+		
+		let file = AsyncFile::open_with(&port, &path, OpenOptions::new().read(true).write(true).create(true).truncate(true), 7).unwrap();
+		let mut stream = AsyncStream::new(file, &table, 7);
+		
+		let written = run(&port, &table, |cx| Pin::new(&mut stream).poll_write(cx, b"hello world"));
+		assert_eq!(written.unwrap(), 11);
+		
+		let position = run(&port, &table, |cx| Pin::new(&mut stream).poll_seek(cx, SeekFrom::Current(-5)));
+		assert_eq!(position.unwrap(), 6);
+		
+		let mut buf = [0; 16];
+		let read = run(&port, &table, |cx| Pin::new(&mut stream).poll_read(cx, &mut buf)).unwrap();
+		assert_eq!(&buf[..read], b"world");
+		
+		let read = run(&port, &table, |cx| Pin::new(&mut stream).poll_read(cx, &mut buf));
+		assert_eq!(read.unwrap(), 0);
+		
+		drop(stream);
+		let _ = fs::remove_file(&path);
+	}
+}
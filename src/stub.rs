@@ -0,0 +1,71 @@
+//! Stand-ins for the Windows types used by the port, for building on other platforms.
+//!
+//! Nothing here performs I/O: a port can never be created, so the methods that would use one
+//! cannot be reached.
+
+#![allow(non_snake_case)]
+
+use std::io::ErrorKind;
+use std::os::raw::c_void;
+use std::time::Duration;
+
+use {IocpImp, CompletionStatus, DequeueResult, ClosedPostPolicy, NotificationModes, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+/// A handle to an object, which no API accepts on this platform.
+pub type HANDLE = *mut c_void;
+
+/// A stand-in with the layout of the Windows OVERLAPPED structure.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct OVERLAPPED {
+	pub Internal: usize,
+	pub InternalHigh: usize,
+	pub Offset: u32,
+	pub OffsetHigh: u32,
+	pub hEvent: HANDLE
+}
+
+fn unsupported() -> IocpError {
+	IocpError::HostError(IOError::new(ErrorKind::Unsupported, "I/O completion ports are only available on Windows"))
+}
+
+/// Fails, since there are no handles to set the modes of on this platform.
+///
+/// # Safety
+///
+/// Always safe to call here; it is unsafe to match the Windows version.
+pub unsafe fn set_notification_modes(_handle: HANDLE, _modes: NotificationModes) -> IocpResult<()> {
+	Err(unsupported())
+}
+
+impl IocpImp {
+	pub fn new(_concurrent_threads: usize, _closed_post_policy: ClosedPostPolicy) -> IocpResult<IocpImp> {
+		Err(unsupported())
+	}
+	pub fn associate(&self, _handle: HANDLE, _completion_key: usize) -> IocpResult<()> {
+		Err(unsupported())
+	}
+	pub fn get_queued(&self, _timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		Err(unsupported())
+	}
+	pub fn get_many_queued(&self, _buf: &mut [CompletionStatus], _timeout: Option<Duration>, _alertable: bool) -> IocpResult<usize> {
+		Err(unsupported())
+	}
+	pub fn wait_for(&self, _overlapped: *mut OVERLAPPED, _timeout: Option<Duration>) -> IocpResult<DequeueResult> {
+		Err(unsupported())
+	}
+	pub fn post_queued(&self, _packet: CompletionStatus) -> IocpResult<()> {
+		Err(unsupported())
+	}
+	pub fn cancel(&self, _handle: HANDLE, _overlapped: *mut OVERLAPPED) -> IocpResult<bool> {
+		Err(unsupported())
+	}
+	pub fn shutdown(&self) -> IocpResult<()> {
+		Err(unsupported())
+	}
+	pub fn try_clone_handle(&self) -> IocpResult<HANDLE> {
+		Err(unsupported())
+	}
+}
@@ -0,0 +1,384 @@
+//! Delivers the signaling of waitable handles as completion packets.
+//!
+//! On Windows 8 and later the kernel queues the packet itself through a wait completion packet.
+//! Elsewhere the wait is performed by the system thread pool, which posts a packet to the port
+//! once the handle becomes signaled.
+
+use std::{mem, ptr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, CompletionStatus, DequeueResult, IocpResult, IocpError, timeout_millis};
+
+use std::io::Error as IOError;
+
+#[link(name = "ntdll")]
+extern "system" {
+	fn RtlNtStatusToDosError(status: winapi::NTSTATUS) -> winapi::ULONG;
+}
+
+type NtCreateWaitCompletionPacket = unsafe extern "system" fn(
+	wait_completion_packet: *mut winapi::HANDLE,
+	desired_access: winapi::ACCESS_MASK,
+	object_attributes: winapi::PVOID
+) -> winapi::NTSTATUS;
+
+type NtAssociateWaitCompletionPacket = unsafe extern "system" fn(
+	wait_completion_packet: winapi::HANDLE,
+	io_completion: winapi::HANDLE,
+	target_object: winapi::HANDLE,
+	key_context: winapi::PVOID,
+	apc_context: winapi::PVOID,
+	io_status: winapi::NTSTATUS,
+	io_status_information: winapi::ULONG_PTR,
+	already_signaled: *mut winapi::BOOLEAN
+) -> winapi::NTSTATUS;
+
+type NtCancelWaitCompletionPacket = unsafe extern "system" fn(
+	wait_completion_packet: winapi::HANDLE,
+	remove_signaled_packet: winapi::BOOLEAN
+) -> winapi::NTSTATUS;
+
+/// Stored in a function cache once the function is known not to exist.
+const MISSING: usize = 1;
+
+static CREATE_WAIT_PACKET: AtomicUsize = AtomicUsize::new(0);
+static ASSOCIATE_WAIT_PACKET: AtomicUsize = AtomicUsize::new(0);
+static CANCEL_WAIT_PACKET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the cached ntdll function, looking it up the first time.
+///
+/// Returns `None` on systems older than Windows 8, which lack wait completion packets.
+fn load(cache: &AtomicUsize, name: &[u8]) -> Option<usize> {
+	let function = cache.load(Ordering::SeqCst);
+	if function != 0 {
+		return if function == MISSING { None } else { Some(function) };
+	}
+	
+	let ntdll: Vec<u16> = "ntdll.dll".encode_utf16().chain(Some(0)).collect();
+	let function = unsafe {
+		let module = kernel32::GetModuleHandleW(ntdll.as_ptr());
+		if module.is_null() {
+			ptr::null_mut()
+		} else {
+			kernel32::GetProcAddress(module, name.as_ptr() as *const winapi::CHAR) as winapi::PVOID
+		}
+	};
+	
+	let function = if function.is_null() { MISSING } else { function as usize };
+	cache.store(function, Ordering::SeqCst);
+	
+	if function == MISSING { None } else { Some(function) }
+}
+
+/// The wait completion packet functions, if the system has them.
+#[derive(Clone, Copy)]
+struct WaitPacketApi {
+	create: NtCreateWaitCompletionPacket,
+	associate: NtAssociateWaitCompletionPacket,
+	cancel: NtCancelWaitCompletionPacket
+}
+
+fn wait_packet_api() -> Option<WaitPacketApi> {
+	let create = load(&CREATE_WAIT_PACKET, b"NtCreateWaitCompletionPacket\0");
+	let associate = load(&ASSOCIATE_WAIT_PACKET, b"NtAssociateWaitCompletionPacket\0");
+	let cancel = load(&CANCEL_WAIT_PACKET, b"NtCancelWaitCompletionPacket\0");
+	
+	match (create, associate, cancel) {
+		(Some(create), Some(associate), Some(cancel)) => Some(WaitPacketApi {
+			create: unsafe { mem::transmute::<usize, NtCreateWaitCompletionPacket>(create) },
+			associate: unsafe { mem::transmute::<usize, NtAssociateWaitCompletionPacket>(associate) },
+			cancel: unsafe { mem::transmute::<usize, NtCancelWaitCompletionPacket>(cancel) }
+		}),
+		_ => None
+	}
+}
+
+/// Translates an NTSTATUS into the corresponding Win32 error.
+fn nt_error(status: winapi::NTSTATUS) -> IocpError {
+	let code = unsafe { RtlNtStatusToDosError(status) };
+	IocpError::HostError(IOError::from_raw_os_error(code as i32))
+}
+
+/// A wait on a handle that posts a completion packet when the handle becomes signaled.
+///
+/// On Windows 8 and later the wait is a wait completion packet, which the kernel queues to the
+/// port itself. Older systems fall back to a thread pool wait whose callback posts the packet.
+///
+/// A wait fires once. Call `rearm` after its packet has been dequeued to wait for the handle
+/// to become signaled again. Dropping the WaitRegistration cancels the wait if it has not fired
+/// yet, and blocks until a callback that is already running has finished.
+pub struct WaitRegistration {
+	port: IoCompletionPort,
+	handle: winapi::HANDLE,
+	completion_key: usize,
+	overlapped: *mut winapi::OVERLAPPED,
+	wait: Wait
+}
+
+unsafe impl Send for WaitRegistration { }
+
+enum Wait {
+	/// A wait completion packet and the functions driving it
+	Packet {
+		packet: winapi::HANDLE,
+		api: WaitPacketApi
+	},
+	/// A thread pool wait and the context of its callback
+	ThreadPool {
+		wait: winapi::HANDLE,
+		context: *mut WaitContext
+	}
+}
+
+struct WaitContext {
+	port: IoCompletionPort,
+	completion_key: usize,
+	overlapped: *mut winapi::OVERLAPPED,
+	fired: AtomicBool
+}
+
+impl WaitRegistration {
+	/// Registers a wait on the given handle.
+	///
+	/// When the handle becomes signaled, a packet with the given completion key and OVERLAPPED
+	/// pointer and a byte count of zero is posted to the port. The OVERLAPPED pointer is not
+	/// dereferenced and can be any value that identifies the wait.
+	pub fn new(port: &IoCompletionPort, handle: winapi::HANDLE, completion_key: usize, overlapped: *mut winapi::OVERLAPPED) -> IocpResult<WaitRegistration> {
+		let wait = match wait_packet_api() {
+			Some(api) => {
+				let mut packet = ptr::null_mut();
+				let status = unsafe { (api.create)(&mut packet, winapi::GENERIC_ALL, ptr::null_mut()) };
+				
+				if status < 0 {
+					return Err(nt_error(status));
+				}
+				
+				Wait::Packet {
+					packet: packet,
+					api: api
+				}
+			},
+			None => Wait::ThreadPool {
+				wait: ptr::null_mut(),
+				context: Box::into_raw(Box::new(WaitContext {
+					port: port.clone(),
+					completion_key: completion_key,
+					overlapped: overlapped,
+					fired: AtomicBool::new(false)
+				}))
+			}
+		};
+		
+		let mut registration = WaitRegistration {
+			port: port.clone(),
+			handle: handle,
+			completion_key: completion_key,
+			overlapped: overlapped,
+			wait: wait
+		};
+		
+		try!(registration.arm());
+		
+		Ok(registration)
+	}
+	/// Returns true if the wait is delivered by a wait completion packet rather than the thread pool.
+	pub fn is_wait_packet(&self) -> bool {
+		match self.wait {
+			Wait::Packet { .. } => true,
+			Wait::ThreadPool { .. } => false
+		}
+	}
+	fn arm(&mut self) -> IocpResult<()> {
+		match self.wait {
+			Wait::Packet { packet, api } => {
+				let mut already_signaled = 0;
+				let status = unsafe {
+					(api.associate)(
+						packet,
+						self.port.inner.inner,
+						self.handle,
+						self.completion_key as winapi::PVOID,
+						self.overlapped as winapi::PVOID,
+						0,
+						0,
+						&mut already_signaled
+					)
+				};
+				
+				if status < 0 {
+					return Err(nt_error(status));
+				}
+			},
+			Wait::ThreadPool { ref mut wait, context } => {
+				let registered = unsafe {
+					kernel32::RegisterWaitForSingleObject(
+						wait,
+						self.handle,
+						Some(wait_callback),
+						context as winapi::PVOID,
+						winapi::INFINITE,
+						winapi::WT_EXECUTEONLYONCE
+					)
+				};
+				
+				if registered == 0 {
+					*wait = ptr::null_mut();
+					return Err(
+						IocpError::HostError(IOError::last_os_error())
+					);
+				}
+			}
+		}
+		
+		Ok(())
+	}
+	/// Stops the pending wait, returning true if it had already fired.
+	fn disarm(&mut self) -> bool {
+		match self.wait {
+			Wait::Packet { packet, api } => {
+				// Anything but success means the packet has already been queued to the port
+				unsafe { (api.cancel)(packet, 0) != 0 }
+			},
+			Wait::ThreadPool { ref mut wait, context } => {
+				if !wait.is_null() {
+					unsafe { let _ = kernel32::UnregisterWaitEx(*wait, winapi::INVALID_HANDLE_VALUE); }
+					*wait = ptr::null_mut();
+				}
+				unsafe { (*context).fired.swap(false, Ordering::SeqCst) }
+			}
+		}
+	}
+	/// Waits for the handle to become signaled again, posting another packet when it does.
+	///
+	/// Must only be called once the packet of the previous wait has been dequeued, or before the
+	/// wait fired, in which case the wait is simply restarted.
+	pub fn rearm(&mut self) -> IocpResult<()> {
+		self.disarm();
+		self.arm()
+	}
+	/// Cancels the wait.
+	///
+	/// Returns true if the wait had already fired, in which case its packet has been posted.
+	pub fn cancel(mut self) -> bool {
+		let fired = self.disarm();
+		self.release();
+		mem::forget(self);
+		
+		fired
+	}
+	fn release(&mut self) {
+		match self.wait {
+			Wait::Packet { packet, .. } => unsafe { let _ = kernel32::CloseHandle(packet); },
+			Wait::ThreadPool { context, .. } => unsafe { drop(Box::from_raw(context)) }
+		}
+	}
+}
+
+/// The outcome of `select2`.
+pub enum Selected {
+	/// A packet was dequeued for an operation that succeeded, or was posted with `post_queued`
+	Completed(CompletionStatus),
+	/// A packet was dequeued for an operation that was cancelled
+	Cancelled(CompletionStatus),
+	/// A packet was dequeued for an operation that failed
+	FailedOperation {
+		/// The packet of the operation
+		status: CompletionStatus,
+		/// The error the operation failed with
+		error: IOError
+	},
+	/// The handle became signaled
+	Signaled,
+	/// Neither happened before the timeout elapsed
+	TimedOut
+}
+
+impl From<DequeueResult> for Selected {
+	fn from(result: DequeueResult) -> Selected {
+		match result {
+			DequeueResult::Completed(status) => Selected::Completed(status),
+			DequeueResult::Cancelled(status) => Selected::Cancelled(status),
+			DequeueResult::FailedOperation { status, error } => Selected::FailedOperation {
+				status: status,
+				error: error
+			},
+			DequeueResult::TimedOut => Selected::TimedOut
+		}
+	}
+}
+
+/// Waits for either a packet on the port or the given handle becoming signaled.
+///
+/// The wait on the handle is bridged to the port with a WaitRegistration whose packet is
+/// recognised and swallowed. If the handle is signaled just as another packet arrives, the handle
+/// wins and the packet is kept for the next dequeue. As with `get_queued`, an error is only
+/// returned if the wait itself failed.
+///
+/// Waiting consumes the signal of auto-reset events and semaphores even when a packet is returned.
+pub fn select2(port: &IoCompletionPort, handle: winapi::HANDLE, timeout: Option<Duration>) -> IocpResult<Selected> {
+	if let Some(packet) = port.inner.unstash(None) {
+		return Ok(Selected::from(packet.into_result()));
+	}
+	
+	// A fresh allocation gives each call a marker no other packet can carry
+	let allocation = Box::new(0u8);
+	let marker = &*allocation as *const u8 as *mut winapi::OVERLAPPED;
+	
+	let registration = try!(WaitRegistration::new(port, handle, 0, marker));
+	
+	let packet = match port.inner.dequeue(timeout_millis(timeout)) {
+		Ok(packet) => packet,
+		Err(error) => {
+			if registration.cancel() {
+				port.inner.retire(marker, allocation);
+			}
+			return Err(error);
+		}
+	};
+	
+	if let Some(ref packet) = packet {
+		if packet.status.overlapped == marker {
+			registration.cancel();
+			return Ok(Selected::Signaled);
+		}
+	}
+	
+	// The marker's packet is still on its way, or never comes if posting it failed, so rather than
+	// waiting for it the port is left to discard it
+	if registration.cancel() {
+		port.inner.retire(marker, allocation);
+		if let Some(packet) = packet {
+			port.inner.stash(packet);
+			port.inner.notify_stashed(1);
+		}
+		return Ok(Selected::Signaled);
+	}
+	
+	match packet {
+		Some(packet) => Ok(Selected::from(packet.into_result())),
+		None => Ok(Selected::TimedOut)
+	}
+}
+
+unsafe extern "system" fn wait_callback(context: winapi::PVOID, _timed_out: winapi::BOOLEAN) {
+	let context = &*(context as *const WaitContext);
+	context.fired.store(true, Ordering::SeqCst);
+	
+	// Posting through the port's policy could panic, which is not allowed to unwind out of the callback
+	let _ = context.port.inner.post_internal(CompletionStatus {
+		byte_count: 0,
+		completion_key: context.completion_key,
+		overlapped: context.overlapped
+	});
+}
+
+impl Drop for WaitRegistration {
+	fn drop(&mut self) {
+		self.disarm();
+		self.release();
+	}
+}
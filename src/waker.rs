@@ -0,0 +1,147 @@
+//! A table of wakers woken by the dequeue loop, for integrating custom executors.
+//!
+//! Futures register a `Waker` against the operation they are waiting for and whichever thread
+//! dequeues packets hands them to the table, which stores the result and wakes the future.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::task::{Poll, Waker};
+
+use winapi;
+
+use DequeueResult;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Target {
+	Overlapped(usize),
+	Key(usize)
+}
+
+#[derive(Default)]
+struct Slot {
+	waker: Option<Waker>,
+	packets: VecDeque<DequeueResult>
+}
+
+impl Slot {
+	fn set_waker(&mut self, waker: &Waker) {
+		match self.waker {
+			Some(ref existing) if existing.will_wake(waker) => (),
+			_ => self.waker = Some(waker.clone())
+		}
+	}
+}
+
+/// Wakers registered against pending operations or completion keys.
+///
+/// A waker registered for an OVERLAPPED pointer is woken by the packet completing that operation,
+/// and one registered for a completion key by any packet with that key that matches no operation.
+/// The packet is kept until it is taken with `poll` or `poll_key`.
+pub struct WakerTable {
+	slots: Mutex<HashMap<Target, Slot>>
+}
+
+unsafe impl Send for WakerTable { }
+unsafe impl Sync for WakerTable { }
+
+impl WakerTable {
+	/// Creates an empty table.
+	pub fn new() -> WakerTable {
+		WakerTable {
+			slots: Mutex::new(HashMap::new())
+		}
+	}
+	/// Registers a waker to be woken when the operation using the given OVERLAPPED completes.
+	///
+	/// Replaces any waker already registered for the operation.
+	pub fn register(&self, overlapped: *mut winapi::OVERLAPPED, waker: &Waker) {
+		self.set_waker(Target::Overlapped(overlapped as usize), waker);
+	}
+	/// Registers a waker to be woken by packets with the given completion key.
+	///
+	/// Replaces any waker already registered for the key.
+	pub fn register_key(&self, completion_key: usize, waker: &Waker) {
+		self.set_waker(Target::Key(completion_key), waker);
+	}
+	fn set_waker(&self, target: Target, waker: &Waker) {
+		self.slots.lock().unwrap().entry(target).or_insert_with(Slot::default).set_waker(waker);
+	}
+	/// Removes the registration for the given operation, discarding a completion that was not taken.
+	pub fn deregister(&self, overlapped: *mut winapi::OVERLAPPED) {
+		self.slots.lock().unwrap().remove(&Target::Overlapped(overlapped as usize));
+	}
+	/// Removes the registration for the given completion key, discarding packets that were not taken.
+	pub fn deregister_key(&self, completion_key: usize) {
+		self.slots.lock().unwrap().remove(&Target::Key(completion_key));
+	}
+	/// Takes the completion of the given operation, or registers the waker if it has not arrived yet.
+	///
+	/// Once the completion has been taken the operation is no longer registered.
+	pub fn poll(&self, overlapped: *mut winapi::OVERLAPPED, waker: &Waker) -> Poll<DequeueResult> {
+		self.poll_target(Target::Overlapped(overlapped as usize), waker, true)
+	}
+	/// Takes the oldest packet stored for the given completion key, or registers the waker if there is none.
+	///
+	/// The key stays registered until `deregister_key` is called.
+	pub fn poll_key(&self, completion_key: usize, waker: &Waker) -> Poll<DequeueResult> {
+		self.poll_target(Target::Key(completion_key), waker, false)
+	}
+	fn poll_target(&self, target: Target, waker: &Waker, once: bool) -> Poll<DequeueResult> {
+		let mut slots = self.slots.lock().unwrap();
+		
+		let packet = {
+			let slot = slots.entry(target).or_insert_with(Slot::default);
+			let packet = slot.packets.pop_front();
+			
+			if packet.is_none() {
+				slot.set_waker(waker);
+			}
+			
+			packet
+		};
+		
+		match packet {
+			Some(packet) => {
+				if once {
+					slots.remove(&target);
+				}
+				Poll::Ready(packet)
+			},
+			None => Poll::Pending
+		}
+	}
+	/// Hands a dequeued packet to the table, waking the waker registered for it.
+	///
+	/// Returns the packet back if nothing is registered for it, including timed out waits.
+	pub fn dispatch(&self, packet: DequeueResult) -> Option<DequeueResult> {
+		let (overlapped, completion_key) = match packet.status() {
+			Some(status) => (status.overlapped, status.completion_key),
+			None => return Some(packet)
+		};
+		
+		let mut slots = self.slots.lock().unwrap();
+		
+		let target = if slots.contains_key(&Target::Overlapped(overlapped as usize)) {
+			Target::Overlapped(overlapped as usize)
+		} else {
+			if !slots.contains_key(&Target::Key(completion_key)) {
+				return Some(packet);
+			}
+			Target::Key(completion_key)
+		};
+		
+		let waker = {
+			let slot = slots.get_mut(&target).unwrap();
+			slot.packets.push_back(packet);
+			slot.waker.take()
+		};
+		
+		drop(slots);
+		
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+		
+		None
+	}
+}
@@ -0,0 +1,380 @@
+//! Directory change notifications delivered through a port.
+//!
+//! A directory is opened for overlapped I/O and watched with an overlapped
+//! ReadDirectoryChangesW, so one thread can watch many directories alongside its other I/O.
+
+use std::{cmp, mem, ptr, slice};
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::os::windows::ffi::OsStringExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::IntoRawHandle;
+use std::path::{Path, PathBuf};
+
+use kernel32;
+use winapi;
+
+use {IoCompletionPort, DequeueResult, IocpResult, IocpError};
+
+use std::io::Error as IOError;
+
+/// Reported instead of data when the changes did not fit in the system's buffer.
+const ERROR_NOTIFY_ENUM_DIR: i32 = 1022;
+
+/// The size in bytes of the buffer changes are read into.
+///
+/// Watches on network shares fail with larger buffers.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The changes watched by `DirectoryWatcher::new`.
+pub const DEFAULT_FILTER: winapi::DWORD = winapi::FILE_NOTIFY_CHANGE_FILE_NAME | winapi::FILE_NOTIFY_CHANGE_DIR_NAME | winapi::FILE_NOTIFY_CHANGE_SIZE | winapi::FILE_NOTIFY_CHANGE_LAST_WRITE;
+
+#[repr(C)]
+struct WatchOp {
+	overlapped: winapi::OVERLAPPED,
+	// ReadDirectoryChangesW needs a DWORD-aligned buffer
+	buffer: Vec<winapi::DWORD>
+}
+
+unsafe impl Send for WatchOp { }
+
+impl WatchOp {
+	fn new(len: usize) -> Box<WatchOp> {
+		Box::new(WatchOp {
+			overlapped: unsafe { mem::zeroed() },
+			buffer: vec![0; len / mem::size_of::<winapi::DWORD>()]
+		})
+	}
+	fn as_ptr(&self) -> *mut winapi::OVERLAPPED {
+		&self.overlapped as *const winapi::OVERLAPPED as *mut winapi::OVERLAPPED
+	}
+}
+
+/// A change to an entry of a watched directory.
+///
+/// Paths are the watched directory's path joined with the name of the changed entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DirectoryChange {
+	/// An entry was created, or moved into the directory
+	Created(PathBuf),
+	/// An entry was removed, or moved out of the directory
+	Removed(PathBuf),
+	/// An entry's contents or attributes were modified
+	Modified(PathBuf),
+	/// An entry was renamed
+	Renamed {
+		/// The path before the rename
+		from: PathBuf,
+		/// The path after the rename
+		to: PathBuf
+	},
+	/// More changes happened than could be reported, and some have been lost
+	///
+	/// The directory should be rescanned to find out its current state.
+	Overflow
+}
+
+/// A watch on a directory whose changes are reported by completion packets.
+///
+/// Every completion arrives with the watcher's completion key and an OVERLAPPED pointer equal to
+/// `overlapped()`; pass the packet to `changes`, which decodes it and re-arms the watch, so the
+/// watcher keeps reporting changes until it is dropped.
+///
+/// Dropping the watcher cancels the watch and closes the directory. The watch's allocation is
+/// handed to the port, which discards its aborted packet.
+pub struct DirectoryWatcher {
+	handle: winapi::HANDLE,
+	port: IoCompletionPort,
+	path: PathBuf,
+	recursive: bool,
+	filter: winapi::DWORD,
+	read: Box<WatchOp>,
+	watching: bool
+}
+
+unsafe impl Send for DirectoryWatcher { }
+
+impl DirectoryWatcher {
+	/// Starts watching the given directory for entries being created, removed, renamed or written to.
+	///
+	/// If `recursive` is true, the whole tree below the directory is watched.
+	pub fn new<P: AsRef<Path>>(port: &IoCompletionPort, path: P, recursive: bool, completion_key: usize) -> IocpResult<DirectoryWatcher> {
+		DirectoryWatcher::with_filter(port, path, recursive, DEFAULT_FILTER, completion_key)
+	}
+	/// Starts watching the given directory for the changes selected by the FILE_NOTIFY_CHANGE flags of `filter`.
+	pub fn with_filter<P: AsRef<Path>>(port: &IoCompletionPort, path: P, recursive: bool, filter: winapi::DWORD, completion_key: usize) -> IocpResult<DirectoryWatcher> {
+		let path = path.as_ref();
+		
+		let directory = OpenOptions::new()
+			.read(true)
+			.access_mode(winapi::FILE_LIST_DIRECTORY)
+			.share_mode(winapi::FILE_SHARE_READ | winapi::FILE_SHARE_WRITE | winapi::FILE_SHARE_DELETE)
+			.custom_flags(winapi::FILE_FLAG_BACKUP_SEMANTICS | winapi::FILE_FLAG_OVERLAPPED)
+			.open(path);
+		
+		let handle = match directory {
+			Ok(directory) => directory.into_raw_handle() as winapi::HANDLE,
+			Err(error) => return Err(IocpError::HostError(error))
+		};
+		
+		let mut watcher = DirectoryWatcher {
+			handle: handle,
+			port: port.clone(),
+			path: path.to_path_buf(),
+			recursive: recursive,
+			filter: filter,
+			read: WatchOp::new(BUFFER_SIZE),
+			watching: false
+		};
+		
+		try!(port.associate(handle, completion_key));
+		try!(watcher.arm());
+		
+		Ok(watcher)
+	}
+	/// Returns the handle of the watched directory.
+	pub fn handle(&self) -> winapi::HANDLE {
+		self.handle
+	}
+	/// Returns the path of the watched directory.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+	/// Returns the OVERLAPPED pointer carried by the watcher's completions.
+	pub fn overlapped(&self) -> *mut winapi::OVERLAPPED {
+		self.read.as_ptr()
+	}
+	/// Returns true while a watch is pending.
+	pub fn is_watching(&self) -> bool {
+		self.watching
+	}
+	fn arm(&mut self) -> IocpResult<()> {
+		self.read.overlapped = unsafe { mem::zeroed() };
+		
+		let len = self.read.buffer.len() * mem::size_of::<winapi::DWORD>();
+		let recursive = if self.recursive { winapi::TRUE } else { winapi::FALSE };
+		
+		let armed = unsafe {
+			kernel32::ReadDirectoryChangesW(
+				self.handle,
+				self.read.buffer.as_mut_ptr() as winapi::LPVOID,
+				len as winapi::DWORD,
+				recursive,
+				self.filter,
+				ptr::null_mut(),
+				self.read.as_ptr(),
+				None
+			)
+		};
+		
+		if armed == 0 {
+			return Err(
+				IocpError::HostError(IOError::last_os_error())
+			);
+		}
+		
+		self.watching = true;
+		
+		Ok(())
+	}
+	/// Returns the changes carried by the given packet if it belongs to this watcher, and re-arms the watch.
+	///
+	/// Returns `None` if the packet does not belong to the watcher. If the watch failed, for
+	/// example because the directory was deleted, or cannot be re-armed, the error is returned and
+	/// the watcher stops reporting changes.
+	pub fn changes(&mut self, packet: &DequeueResult) -> Option<IocpResult<Vec<DirectoryChange>>> {
+		if !self.watching || packet.overlapped() != self.overlapped() {
+			return None;
+		}
+		
+		self.watching = false;
+		
+		let changes = match packet.byte_count() {
+			Some(result) => decode(&self.path, &self.read.buffer, result),
+			None => return None
+		};
+		
+		Some(changes.and_then(|changes| self.arm().map(|_| changes)))
+	}
+}
+
+/// Decodes the result of a watch that read into the buffer.
+///
+/// A watch that read nothing, or whose changes did not fit, reports an overflow.
+fn decode(directory: &Path, buffer: &[winapi::DWORD], result: IocpResult<usize>) -> IocpResult<Vec<DirectoryChange>> {
+	match result {
+		Ok(0) => Ok(vec![DirectoryChange::Overflow]),
+		Ok(count) => Ok(parse(directory, buffer, count)),
+		Err(IocpError::HostError(ref error)) if error.raw_os_error() == Some(ERROR_NOTIFY_ENUM_DIR) => Ok(vec![DirectoryChange::Overflow]),
+		Err(error) => Err(error)
+	}
+}
+
+/// Decodes the FILE_NOTIFY_INFORMATION records in the first `count` bytes of the buffer.
+///
+/// The names of the changed entries are joined to `directory`.
+fn parse(directory: &Path, buffer: &[winapi::DWORD], count: usize) -> Vec<DirectoryChange> {
+	let base = buffer.as_ptr() as *const u8;
+	let count = cmp::min(count, buffer.len() * mem::size_of::<winapi::DWORD>());
+	let header = mem::size_of::<winapi::FILE_NOTIFY_INFORMATION>();
+	
+	let mut changes = Vec::new();
+	let mut renamed_from = None;
+	let mut offset = 0;
+	
+	while offset + header <= count {
+		let info = unsafe { &*(base.offset(offset as isize) as *const winapi::FILE_NOTIFY_INFORMATION) };
+		let name_len = cmp::min(info.FileNameLength as usize, count - offset - header) / 2;
+		let name = unsafe { slice::from_raw_parts(base.offset((offset + header) as isize) as *const u16, name_len) };
+		let path = directory.join(OsString::from_wide(name));
+		
+		// An old name without a new one following it means the entry was moved out of the watch
+		if info.Action != winapi::FILE_ACTION_RENAMED_NEW_NAME {
+			if let Some(from) = renamed_from.take() {
+				changes.push(DirectoryChange::Removed(from));
+			}
+		}
+		
+		match info.Action {
+			winapi::FILE_ACTION_ADDED => changes.push(DirectoryChange::Created(path)),
+			winapi::FILE_ACTION_REMOVED => changes.push(DirectoryChange::Removed(path)),
+			winapi::FILE_ACTION_MODIFIED => changes.push(DirectoryChange::Modified(path)),
+			winapi::FILE_ACTION_RENAMED_OLD_NAME => renamed_from = Some(path),
+			winapi::FILE_ACTION_RENAMED_NEW_NAME => changes.push(match renamed_from.take() {
+				Some(from) => DirectoryChange::Renamed {
+					from: from,
+					to: path
+				},
+				None => DirectoryChange::Created(path)
+			}),
+			_ => { }
+		}
+		
+		if info.NextEntryOffset == 0 {
+			break;
+		}
+		offset += info.NextEntryOffset as usize;
+	}
+	
+	if let Some(from) = renamed_from {
+		changes.push(DirectoryChange::Removed(from));
+	}
+	
+	changes
+}
+
+impl Drop for DirectoryWatcher {
+	fn drop(&mut self) {
+		if self.watching {
+			let retired = mem::replace(&mut self.read, WatchOp::new(0));
+			self.port.inner.retire_and_cancel(self.handle, retired.as_ptr(), retired);
+		}
+		
+		unsafe { let _ = kernel32::CloseHandle(self.handle); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+	
+	use winapi;
+	
+	use IocpError;
+	use super::{decode, parse, DirectoryChange, ERROR_NOTIFY_ENUM_DIR};
+	
+	use std::io::Error as IOError;
+	
+	/// Lays out FILE_NOTIFY_INFORMATION records the way ReadDirectoryChangesW does.
+	fn records(entries: &[(winapi::DWORD, &str)]) -> (Vec<winapi::DWORD>, usize) {
+		let mut buffer = Vec::new();
+		let mut last = 0;
+		
+		for &(action, name) in entries.iter() {
+			let name: Vec<u16> = name.encode_utf16().collect();
+			let words = 3 + name.len() / 2 + name.len() % 2;
+			
+			if !buffer.is_empty() {
+				buffer[last] = ((buffer.len() - last) * 4) as winapi::DWORD;
+			}
+			last = buffer.len();
+			
+			buffer.extend_from_slice(&[0, action, (name.len() * 2) as winapi::DWORD]);
+			let start = buffer.len();
+			buffer.resize(last + words, 0);
+			for (i, unit) in name.iter().enumerate() {
+				buffer[start + i / 2] |= (*unit as winapi::DWORD) << (16 * (i % 2));
+			}
+		}
+		
+		let count = buffer.len() * 4;
+		(buffer, count)
+	}
+
+	#[test]
+	fn rename_pairs_are_joined() {
+		let (buffer, count) = records(&[
+			(winapi::FILE_ACTION_RENAMED_OLD_NAME, "old.txt"),
+			(winapi::FILE_ACTION_RENAMED_NEW_NAME, "new.txt"),
+			(winapi::FILE_ACTION_MODIFIED, "new.txt")
+		]);
+		let dir = Path::new("C:\\watched");
+		
+		assert_eq!(parse(dir, &buffer, count), vec![
+			DirectoryChange::Renamed {
+				from: dir.join("old.txt"),
+				to: dir.join("new.txt")
+			},
+			DirectoryChange::Modified(dir.join("new.txt"))
+		]);
+	}
+
+	#[test]
+	fn lone_old_name_is_a_removal() {
+		let dir = Path::new("C:\\watched");
+		
+		let (buffer, count) = records(&[
+			(winapi::FILE_ACTION_RENAMED_OLD_NAME, "moved"),
+			(winapi::FILE_ACTION_ADDED, "a")
+		]);
+		assert_eq!(parse(dir, &buffer, count), vec![
+			DirectoryChange::Removed(dir.join("moved")),
+			DirectoryChange::Created(dir.join("a"))
+		]);
+		
+		let (buffer, count) = records(&[(winapi::FILE_ACTION_RENAMED_OLD_NAME, "moved")]);
+		assert_eq!(parse(dir, &buffer, count), vec![DirectoryChange::Removed(dir.join("moved"))]);
+		
+		let (buffer, count) = records(&[(winapi::FILE_ACTION_RENAMED_NEW_NAME, "arrived")]);
+		assert_eq!(parse(dir, &buffer, count), vec![DirectoryChange::Created(dir.join("arrived"))]);
+	}
+
+	#[test]
+	fn truncated_records_are_ignored() {
+		let (buffer, _) = records(&[
+			(winapi::FILE_ACTION_ADDED, "first"),
+			(winapi::FILE_ACTION_REMOVED, "second")
+		]);
+		let dir = Path::new("C:\\watched");
+		
+		assert_eq!(parse(dir, &buffer, 8), vec![]);
+		assert_eq!(parse(dir, &buffer, 24), vec![DirectoryChange::Created(dir.join("first"))]);
+	}
+
+	#[test]
+	fn lost_changes_are_an_overflow() {
+		let dir = Path::new("C:\\watched");
+		let (buffer, _) = records(&[(winapi::FILE_ACTION_ADDED, "a")]);
+		
+		assert_eq!(decode(dir, &buffer, Ok(0)).unwrap(), vec![DirectoryChange::Overflow]);
+		
+		let enum_dir = Err(IocpError::HostError(IOError::from_raw_os_error(ERROR_NOTIFY_ENUM_DIR)));
+		assert_eq!(decode(dir, &buffer, enum_dir).unwrap(), vec![DirectoryChange::Overflow]);
+		
+		let denied = Err(IocpError::HostError(IOError::from_raw_os_error(5)));
+		match decode(dir, &buffer, denied) {
+			Err(IocpError::HostError(ref error)) if error.raw_os_error() == Some(5) => (),
+			result => panic!("unexpected result {:?}", result)
+		}
+	}
+}